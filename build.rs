@@ -6,5 +6,12 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .file_descriptor_set_path(out_dir.join("metrics_service_descriptor.bin"))
         .compile(&["proto/metrics_service.proto"], &["proto"])?;
+    // the PostgreSQL tests are ignored unless there is a server to run them against
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-env-changed=POSTGRES_TEST_URL");
+    println!("cargo:rustc-check-cfg=cfg(postgres_test_url)");
+    if env::var_os("POSTGRES_TEST_URL").is_some() {
+        println!("cargo:rustc-cfg=postgres_test_url");
+    }
     Ok(())
 }
@@ -0,0 +1,33 @@
+-- names use the "C" collation so they sort and compare byte by byte, like they do in sqlite;
+-- prefix ranges and resuming reads rely on that ordering
+CREATE TABLE Metrics (
+    name TEXT COLLATE "C" NOT NULL,
+    time BIGINT NOT NULL,
+    value_type TEXT NOT NULL,
+    dvalue DOUBLE PRECISION,
+    tvalue TEXT,
+    PRIMARY KEY (name, time)
+);
+
+CREATE TABLE Series (
+    key TEXT COLLATE "C" PRIMARY KEY,
+    name TEXT COLLATE "C" NOT NULL
+);
+
+CREATE TABLE Labels (
+    series TEXT COLLATE "C" NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (series, key)
+);
+
+CREATE INDEX LabelsByValue ON Labels (key, value);
+
+-- on TimescaleDB, partition metrics into a chunk per day of nanoseconds
+DO $$
+BEGIN
+    IF EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'timescaledb') THEN
+        PERFORM create_hypertable('metrics', 'time', chunk_time_interval => 86400000000000);
+    END IF;
+END
+$$;
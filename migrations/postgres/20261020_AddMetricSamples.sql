@@ -0,0 +1,7 @@
+-- a series can keep several samples at the same time, numbered from 0 in the order they
+-- were written, so the sample joins the primary key
+ALTER TABLE Metrics ADD COLUMN sample INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE Metrics DROP CONSTRAINT metrics_pkey;
+
+ALTER TABLE Metrics ADD PRIMARY KEY (name, time, sample);
@@ -0,0 +1,17 @@
+-- batches written under a client-supplied ID, remembered for a while so that a retried
+-- batch is not written twice; committed is in nanoseconds since the unix epoch
+CREATE TABLE Batches (
+    id TEXT PRIMARY KEY,
+    committed BIGINT NOT NULL
+);
+
+CREATE INDEX BatchesByCommitted ON Batches (committed);
+
+-- the metrics of a remembered batch that could not be written, by their index in it
+CREATE TABLE BatchFailures (
+    batch TEXT NOT NULL REFERENCES Batches (id) ON DELETE CASCADE,
+    idx INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (batch, idx)
+);
@@ -0,0 +1,43 @@
+CREATE TABLE Rollups1m (
+    name TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    value_type TEXT NOT NULL,
+    dmin REAL,
+    dmax REAL,
+    dsum REAL,
+    count INTEGER NOT NULL,
+    dvalue REAL,
+    tvalue TEXT,
+    PRIMARY KEY (name, bucket)
+);
+
+CREATE TABLE Rollups1h (
+    name TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    value_type TEXT NOT NULL,
+    dmin REAL,
+    dmax REAL,
+    dsum REAL,
+    count INTEGER NOT NULL,
+    dvalue REAL,
+    tvalue TEXT,
+    PRIMARY KEY (name, bucket)
+);
+
+CREATE TABLE Rollups1d (
+    name TEXT NOT NULL,
+    bucket INTEGER NOT NULL,
+    value_type TEXT NOT NULL,
+    dmin REAL,
+    dmax REAL,
+    dsum REAL,
+    count INTEGER NOT NULL,
+    dvalue REAL,
+    tvalue TEXT,
+    PRIMARY KEY (name, bucket)
+);
+
+CREATE TABLE RollupProgress (
+    resolution INTEGER PRIMARY KEY,
+    rolled_until INTEGER NOT NULL
+);
@@ -0,0 +1,13 @@
+CREATE TABLE Series (
+    key TEXT PRIMARY KEY,
+    name TEXT NOT NULL
+);
+
+CREATE TABLE Labels (
+    series TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    PRIMARY KEY (series, key)
+);
+
+CREATE INDEX LabelsByValue ON Labels (key, value);
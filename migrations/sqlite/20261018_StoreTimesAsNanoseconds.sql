@@ -0,0 +1,27 @@
+-- times were stored as RFC3339 text, which only sorts correctly when every value has the
+-- same format; store them as nanoseconds since the unix epoch instead
+CREATE TABLE MetricsByNanos (
+    name TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    value_type TEXT NOT NULL,
+    dvalue REAL,
+    tvalue TEXT,
+    PRIMARY KEY (name, time)
+);
+
+INSERT INTO MetricsByNanos (name, time, value_type, dvalue, tvalue)
+SELECT name,
+    CAST(strftime('%s', time) AS INTEGER) * 1000000000 + CASE
+        WHEN substr(time, 20, 1) = '.' THEN CAST(substr(
+            substr(time, 21, length(time) - 21) || '000000000', 1, 9
+        ) AS INTEGER)
+        ELSE 0
+    END,
+    value_type,
+    dvalue,
+    tvalue
+FROM Metrics;
+
+DROP TABLE Metrics;
+
+ALTER TABLE MetricsByNanos RENAME TO Metrics;
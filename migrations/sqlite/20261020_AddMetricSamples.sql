@@ -0,0 +1,19 @@
+-- a series can keep several samples at the same time, numbered from 0 in the order they
+-- were written, so the sample joins the primary key
+CREATE TABLE MetricsWithSamples (
+    name TEXT NOT NULL,
+    time INTEGER NOT NULL,
+    sample INTEGER NOT NULL DEFAULT 0,
+    value_type TEXT NOT NULL,
+    dvalue REAL,
+    tvalue TEXT,
+    PRIMARY KEY (name, time, sample)
+);
+
+INSERT INTO MetricsWithSamples (name, time, value_type, dvalue, tvalue)
+SELECT name, time, value_type, dvalue, tvalue
+FROM Metrics;
+
+DROP TABLE Metrics;
+
+ALTER TABLE MetricsWithSamples RENAME TO Metrics;
@@ -0,0 +1,7 @@
+-- rollup buckets that points were written to or deleted from after they were rolled up,
+-- to be rolled up again from their source; buckets are in seconds since the unix epoch
+CREATE TABLE RollupDirty (
+    resolution INTEGER NOT NULL,
+    bucket INTEGER NOT NULL,
+    PRIMARY KEY (resolution, bucket)
+);
@@ -0,0 +1,22 @@
+-- the number of doubles in each rollup bucket, which averages are taken over; count takes
+-- in strings and NaNs as well
+ALTER TABLE Rollups1m ADD COLUMN dcount INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Rollups1h ADD COLUMN dcount INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE Rollups1d ADD COLUMN dcount INTEGER NOT NULL DEFAULT 0;
+
+UPDATE Rollups1m SET dcount = count WHERE dsum IS NOT NULL;
+UPDATE Rollups1h SET dcount = count WHERE dsum IS NOT NULL;
+UPDATE Rollups1d SET dcount = count WHERE dsum IS NOT NULL;
+
+-- that is only right for buckets of nothing but doubles, so those of series that hold
+-- anything else are rolled up again, which carries on to the coarser resolutions
+INSERT OR IGNORE INTO RollupDirty (resolution, bucket)
+SELECT DISTINCT 60, t1.bucket
+FROM Rollups1m t1
+WHERE t1.dsum IS NOT NULL
+    AND t1.name IN (
+        SELECT t2.name
+        FROM Metrics t2
+        WHERE t2.value_type <> 'double'
+            OR t2.dvalue IS NULL
+    );
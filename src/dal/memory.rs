@@ -0,0 +1,464 @@
+//! A database that keeps everything in memory, for test rigs and edge nodes. Each series
+//! is a ring buffer holding at most `max_points` points, dropping its oldest point to
+//! make room for a new one, and once `max_series` series are kept the one written to
+//! least recently is dropped to make room for a new series.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{
+        Arc,
+        PoisonError,
+        RwLock,
+    },
+};
+
+use chrono::prelude::*;
+use log::info;
+
+use super::{
+    Aggregator,
+    BatchWrite,
+    Database,
+    DatabaseError,
+    Filter,
+    Labels,
+    Metric,
+    MetricValue,
+    NanoRange,
+    PathNode,
+    ReadResult,
+    RecentBatches,
+    Result,
+    Selector,
+    TimeRange,
+    WriteMode,
+    Written,
+    ConflictPolicies,
+    ConflictPolicy,
+    aggregate_buckets,
+    batch_cutoff,
+    bound_nanos,
+    browse_nodes,
+    from_nanos,
+    series_key,
+    series_within,
+    to_nanos,
+};
+
+/// how many series are kept unless configured otherwise
+pub const DEFAULT_MAX_SERIES: usize = 10_000;
+
+/// how many points each series keeps unless configured otherwise
+pub const DEFAULT_MAX_POINTS: usize = 10_000;
+
+/// EvictionStats counts what has been dropped to stay within the limits
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct EvictionStats {
+    /// points dropped from full series, including new points older than everything kept
+    pub points: u64,
+    /// series dropped, along with their points, to make room for new ones
+    pub series: u64,
+}
+
+#[derive(Clone)]
+pub struct MemoryDatabase{
+    memory: Arc<RwLock<Memory>>,
+}
+
+struct Memory {
+    max_series: usize,
+    max_points: usize,
+    series: BTreeMap<String, Series>,
+    /// the keys of the series by when they were last written to, least recent first
+    recency: BTreeMap<u64, String>,
+    /// counts writes, to order the series by recency
+    clock: u64,
+    evicted: EvictionStats,
+    batches: RecentBatches,
+}
+
+struct Series {
+    name: String,
+    labels: Labels<'static>,
+    /// the points, in time order, with samples at the same time in the order they were
+    /// written
+    points: VecDeque<(i64, MetricValue<'static>)>,
+    /// the clock at the last write
+    written: u64,
+}
+
+impl MemoryDatabase{
+    /// creates an empty database keeping at most `max_series` series of `max_points`
+    /// points each
+    pub fn new(max_series: usize, max_points: usize) -> Self {
+        MemoryDatabase{
+            memory: Arc::new(RwLock::new(Memory{
+                max_series: max_series.max(1),
+                max_points: max_points.max(1),
+                series: BTreeMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                evicted: EvictionStats::default(),
+                batches: RecentBatches::default(),
+            })),
+        }
+    }
+
+    /// counts the points and series evicted so far
+    pub fn evictions(&self) -> EvictionStats {
+        self.memory.read().unwrap_or_else(PoisonError::into_inner).evicted
+    }
+}
+
+/// logs what the database has evicted every `period` forever, whenever it has evicted
+/// anything more; run it with tokio::spawn
+pub async fn report_evictions(db: MemoryDatabase, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    let mut reported = EvictionStats::default();
+    loop {
+        interval.tick().await;
+        let evicted = db.evictions();
+        if evicted != reported {
+            info!("Evicted {} points and {} series from memory in the last {:?}, {} points and {} series in all",
+                evicted.points - reported.points, evicted.series - reported.series, period,
+                evicted.points, evicted.series);
+            reported = evicted;
+        }
+    }
+}
+
+impl Default for MemoryDatabase {
+    fn default() -> Self {
+        MemoryDatabase::new(DEFAULT_MAX_SERIES, DEFAULT_MAX_POINTS)
+    }
+}
+
+impl Database for MemoryDatabase {
+    fn setup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_metrics_with(&self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies)
+        -> Result<Written> {
+        let mut memory = self.memory.write().map_err(poisoned)?;
+        Ok(memory.write(metrics, mode, conflicts))
+    }
+
+    fn write_batch(&self, id: &str, ttl: chrono::Duration, metrics: &[Metric], mode: WriteMode,
+        conflicts: &ConflictPolicies) -> Result<BatchWrite> {
+        let mut memory = self.memory.write().map_err(poisoned)?;
+        let now = Utc::now();
+        memory.batches.expire(batch_cutoff(&now, ttl));
+        if let Some(failures) = memory.batches.get(id) {
+            return Ok(BatchWrite{failures: failures.clone(), discarded: vec!(), replayed: true});
+        }
+        let Written{failures, discarded} = memory.write(metrics, mode, conflicts);
+        if mode == WriteMode::Partial || failures.is_empty() {
+            memory.batches.insert(id.to_string(), bound_nanos(&now), failures.clone());
+        }
+        Ok(BatchWrite{failures, discarded, replayed: false})
+    }
+
+    fn read_metrics<'a>(&'a self, selector: &Selector, range: &TimeRange, limit: usize,
+        resume: &[(String, DateTime<Utc>)]) -> Result<ReadResult<'a>> {
+        let memory = self.memory.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut page = ReadResult::default();
+        let mut read_series = |key: &str, series: &Series, after: Option<i64>| {
+            let points = series.points(&range)
+                .filter(|(time, _)| after.map(|after| *time > after).unwrap_or(true));
+            // the limit counts times, so every sample at a time lands on the same page
+            let (mut times, mut last_time) = (0, None);
+            for (time, value) in points {
+                if last_time != Some(*time) {
+                    if times == limit {
+                        if let Some(last) = last_time {
+                            page.truncated.push((key.to_string(), from_nanos(last)));
+                        }
+                        break;
+                    }
+                    times += 1;
+                    last_time = Some(*time);
+                }
+                page.metrics.push(series.metric(from_nanos(*time), value.clone()));
+            }
+        };
+        let matching = memory.matching(selector)?;
+        if resume.is_empty() {
+            for (key, series) in matching {
+                read_series(key, series, None);
+            }
+        } else {
+            // a series is only resumed if the selector still picks it
+            let matching: BTreeMap<_, _> = matching.into_iter().collect();
+            for (key, after) in resume {
+                if let Some(series) = matching.get(key) {
+                    read_series(key, series, Some(bound_nanos(after)));
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    fn read_aggregated<'a>(&'a self, selector: &Selector, range: &TimeRange,
+        width: chrono::Duration, aggregator: Aggregator) -> Result<Vec<Metric<'a>>> {
+        let width = width.num_seconds();
+        if width < 1 {
+            return Err(DatabaseError::InvalidArgument("bucket width must be at least one second".into()));
+        }
+        let memory = self.memory.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut metrics = vec!();
+        for (_, series) in memory.matching(selector)? {
+            let points = series.points(&range).map(|(time, value)| (*time, value.clone()));
+            for (when, value) in aggregate_buckets(points, width, aggregator)? {
+                metrics.push(series.metric(when, value));
+            }
+        }
+        Ok(metrics)
+    }
+
+    fn read_tail<'a>(&'a self, selector: &Selector, range: &TimeRange, count: usize) -> Result<Vec<Metric<'a>>> {
+        let memory = self.memory.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut metrics = vec!();
+        for (_, series) in memory.matching(selector)? {
+            for (time, value) in series.points(&range).rev().take(count) {
+                metrics.push(series.metric(from_nanos(*time), value.clone()));
+            }
+        }
+        Ok(metrics)
+    }
+
+    fn delete_metrics_except(&self, prefix: &str, except: &[String], before: &DateTime<Utc>) -> Result<usize> {
+        let mut memory = self.memory.write().map_err(poisoned)?;
+        let before = bound_nanos(before);
+        let keys: Vec<String> = series_within(&memory.series, prefix)
+            .filter(|(key, _)| !except.iter().any(|prefix| key.starts_with(prefix.as_str())))
+            .map(|(key, _)| key.clone())
+            .collect();
+        let mut deleted = 0;
+        for key in keys {
+            if let Some(series) = memory.series.get_mut(&key) {
+                let end = series.points.partition_point(|(time, _)| *time < before);
+                series.points.drain(..end);
+                deleted += end;
+                if series.points.is_empty() {
+                    let written = series.written;
+                    memory.series.remove(&key);
+                    memory.recency.remove(&written);
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn latest_values(&self, selector: &Selector) -> Result<Vec<Metric<'_>>> {
+        self.read_tail(selector, &TimeRange::default(), 1)
+    }
+
+    fn list_metrics(&self, selector: &Selector) -> Result<Vec<(String, Labels<'static>, DateTime<Utc>)>> {
+        let memory = self.memory.read().map_err(poisoned)?;
+        Ok(memory.matching(selector)?
+            .into_iter()
+            .filter_map(|(_, series)| series.points.back().map(|(last, _)| (series.name.clone(), series.labels.clone(), from_nanos(*last))))
+            .collect())
+    }
+
+    fn browse_metrics(&self, prefix: &str) -> Result<Vec<PathNode>> {
+        let memory = self.memory.read().map_err(poisoned)?;
+        Ok(browse_nodes(prefix, series_within(&memory.series, prefix).map(|(key, _)| key.clone())))
+    }
+}
+
+impl Memory {
+    /// writes a batch of metrics, returning the failures and the metrics left out
+    fn write(&mut self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies) -> Written {
+        let mut written = Written::default();
+        let mut accepted = vec!();
+        let mut batch = HashSet::new();
+        for (i, metric) in metrics.iter().enumerate() {
+            let key = series_key(&metric.name, &metric.labels);
+            let policy = conflicts.policy_for(&metric.name);
+            let failure = match to_nanos(&metric.when) {
+                None => Some(DatabaseError::InvalidArgument(format!("time {} cannot be stored", metric.when))),
+                Some(time) => {
+                    let exists = batch.contains(&(key.clone(), time))
+                        || self.series.get(&key).map(|series| series.position(time).is_ok()).unwrap_or(false);
+                    if exists && policy == ConflictPolicy::Reject {
+                        Some(DatabaseError::Duplicate(format!("{} already has a point at {}", key, metric.when)))
+                    } else if exists && policy == ConflictPolicy::KeepFirst {
+                        written.discarded.push(i);
+                        None
+                    } else {
+                        batch.insert((key.clone(), time));
+                        accepted.push((key, time, policy, metric));
+                        None
+                    }
+                },
+            };
+            if let Some(failure) = failure {
+                written.failures.push((i, failure));
+                if mode == WriteMode::Atomic {
+                    return written;
+                }
+            }
+        }
+        for (key, time, policy, metric) in accepted {
+            self.insert(key, time, policy, metric);
+        }
+        written
+    }
+
+    /// the series picked by a selector, in order; the prefix applies to their keys, like
+    /// it does in the other backends
+    fn matching<'m>(&'m self, selector: &'m Selector) -> Result<Vec<(&'m String, &'m Series)>> {
+        let filter = Filter::new(&Selector{prefix: String::new(), ..selector.clone()})
+            .map_err(|e| DatabaseError::InvalidArgument(format!("invalid regular expression: {}", e)))?;
+        Ok(series_within(&self.series, &selector.prefix)
+            .filter(|(_, series)| filter.matches(&series.name, &series.labels))
+            .collect())
+    }
+
+    /// adds a point, settling any already at its time by the policy and evicting whatever
+    /// it takes to stay within the limits; rejecting is up to the caller
+    fn insert(&mut self, key: String, time: i64, policy: ConflictPolicy, metric: &Metric) {
+        self.clock += 1;
+        match self.series.get_mut(&key) {
+            Some(series) => {
+                self.recency.remove(&series.written);
+                series.written = self.clock;
+            },
+            None => {
+                if self.series.len() >= self.max_series {
+                    if let Some(oldest) = self.recency.keys().next().copied() {
+                        if let Some(evicted) = self.recency.remove(&oldest) {
+                            self.series.remove(&evicted);
+                            self.evicted.series += 1;
+                        }
+                    }
+                }
+                let metric = metric.to_owned_metric();
+                self.series.insert(key.clone(), Series{
+                    name: metric.name.into_owned(),
+                    labels: metric.labels,
+                    points: VecDeque::new(),
+                    written: self.clock,
+                });
+            },
+        }
+        self.recency.insert(self.clock, key.clone());
+        if let Some(series) = self.series.get_mut(&key) {
+            let value = metric.to_owned_metric().value;
+            let start = series.points.partition_point(|(t, _)| *t < time);
+            let end = series.points.partition_point(|(t, _)| *t <= time);
+            match policy {
+                ConflictPolicy::KeepFirst if start < end => return,
+                ConflictPolicy::Overwrite => {
+                    series.points.drain(start..end);
+                },
+                _ => (),
+            }
+            // after any samples already at the time
+            let at = series.points.partition_point(|(t, _)| *t <= time);
+            if series.points.len() < self.max_points {
+                series.points.insert(at, (time, value));
+                return;
+            }
+            // the oldest point makes way, unless the new one is older still
+            self.evicted.points += 1;
+            if at > 0 {
+                series.points.pop_front();
+                series.points.insert(at - 1, (time, value));
+            }
+        }
+    }
+}
+
+impl Series {
+    fn metric(&self, when: DateTime<Utc>, value: MetricValue<'static>) -> Metric<'static> {
+        Metric{
+            name: Cow::Owned(self.name.clone()),
+            labels: self.labels.clone(),
+            when: Cow::Owned(when),
+            value,
+        }
+    }
+
+    /// where a point at a time is, or where it would go
+    fn position(&self, time: i64) -> std::result::Result<usize, usize> {
+        self.points.binary_search_by_key(&time, |(t, _)| *t)
+    }
+
+    /// the points within the range, in time order
+    fn points<'s>(&'s self, range: &'s NanoRange) -> impl DoubleEndedIterator<Item=&'s (i64, MetricValue<'static>)> {
+        self.points.iter().filter(move |(time, _)| range.contains(*time))
+    }
+}
+
+fn poisoned<T>(e: PoisonError<T>) -> DatabaseError {
+    DatabaseError::Custom(format!("lock error: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_testdb(test: fn(&dyn Database)) {
+        test(&MemoryDatabase::default())
+    }
+
+    database_suite!(with_testdb);
+
+    fn metric(name: &str, seconds: i64) -> Metric<'static> {
+        Metric{
+            name: Cow::Owned(name.to_string()),
+            labels: Labels::new(),
+            when: Cow::Owned(Utc.timestamp_opt(1_516_991_409 + seconds, 0).unwrap()),
+            value: MetricValue::Double(seconds as f64),
+        }
+    }
+
+    fn values(db: &MemoryDatabase, name: &str) -> Vec<f64> {
+        db.read_metrics(&name.into(), &TimeRange::default(), 100, &[]).unwrap()
+            .metrics
+            .into_iter()
+            .filter(|m| m.name == name)
+            .map(|m| match m.value {
+                MetricValue::Double(d) => d,
+                MetricValue::String(_) => panic!("unexpected string"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn full_series_drop_their_oldest_points() {
+        let db = MemoryDatabase::new(10, 3);
+        let metrics: Vec<_> = [4, 1, 3, 2, 5].iter().map(|s| metric("myservice.cpu_time", *s)).collect();
+        db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+        assert_eq!(values(&db, "myservice.cpu_time"), vec!(3.0, 4.0, 5.0));
+        assert_eq!(db.evictions(), EvictionStats{points: 2, series: 0});
+        // a point older than everything kept is dropped straight away
+        db.write_metric(&metric("myservice.cpu_time", 0)).unwrap();
+        assert_eq!(values(&db, "myservice.cpu_time"), vec!(3.0, 4.0, 5.0));
+        assert_eq!(db.evictions().points, 3);
+    }
+
+    #[test]
+    fn least_recently_written_series_are_dropped() {
+        let db = MemoryDatabase::new(2, 10);
+        db.write_metric(&metric("myservice.cpu_time", 1)).unwrap();
+        db.write_metric(&metric("myservice.mem_used", 1)).unwrap();
+        db.write_metric(&metric("myservice.cpu_time", 2)).unwrap();
+        db.write_metric(&metric("myservice.disk_used", 1)).unwrap();
+        assert_eq!(values(&db, "myservice.cpu_time"), vec!(1.0, 2.0));
+        assert!(values(&db, "myservice.mem_used").is_empty());
+        assert_eq!(values(&db, "myservice.disk_used"), vec!(1.0));
+        assert_eq!(db.evictions(), EvictionStats{points: 0, series: 1});
+        // deleting a series frees its place without counting as an eviction
+        db.delete_metrics("myservice.cpu_time", &Utc::now()).unwrap();
+        db.write_metric(&metric("myservice.mem_used", 1)).unwrap();
+        assert_eq!(values(&db, "myservice.disk_used"), vec!(1.0));
+        assert_eq!(db.evictions().series, 1);
+    }
+}
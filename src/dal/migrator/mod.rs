@@ -38,6 +38,7 @@ use std::{
     borrow::Cow,
 };
 
+pub mod postgres;
 pub mod sqlite;
 
 use rust_embed::RustEmbed;
@@ -50,10 +51,9 @@ pub type Result<T> = std::result::Result<T, MigrationError>;
 pub trait Applier {
     /// sets up the migration table; this should be idempotent
     fn setup(&self) -> Result<()>;
-    /// applies a schema-altering SQL statement
-    fn apply(&self, sql: &str) -> Result<()>;
-    /// mark_applied marks the migration as applied
-    fn mark_applied(&self, name: &str) -> Result<()>;
+    /// applies the schema-altering SQL statements of a migration and marks it as applied;
+    /// either both happen or neither does
+    fn apply(&self, name: &str, sql: &str) -> Result<()>;
     /// retrieves all applied migrations
     fn applied(&self) -> Result<Vec<String>>;
 }
@@ -93,9 +93,7 @@ pub fn migrate<E: RustEmbed, A: Applier>(applier: &A) -> Result<()> {
                     e,
                 ))),
             };
-            applier.apply(sql)?;
-            // If that succeeded, mark the migration as applied
-            applier.mark_applied(&files[i])?;
+            applier.apply(&files[i], sql)?;
         }
         i += 1;
     }
@@ -118,6 +116,7 @@ mod tests {
     };
 
     use super::{
+        Applier,
         migrate,
         sqlite::SqliteMigrator,
     };
@@ -155,4 +154,18 @@ mod tests {
             }).unwrap();
         assert_eq!(got_result, want_result)
     }
+
+    #[test]
+    fn test_failed_migrations_leave_no_trace() {
+        let conn = Arc::new(Mutex::new(Connection::open(":memory:").unwrap()));
+        let applier = &SqliteMigrator::new(conn.clone());
+        migrate::<TestData, _>(applier).unwrap();
+        // the migration is already recorded, so recording it again fails and takes its table with it
+        let name = applier.applied().unwrap().remove(0);
+        assert!(applier.apply(&name, "CREATE TABLE Leftovers (Id INTEGER)").is_err());
+        let leftovers: i64 = conn.lock().unwrap()
+            .query_row("SELECT COUNT(*) FROM sqlite_master WHERE name = 'Leftovers'", NO_PARAMS, |row| row.get(0))
+            .unwrap();
+        assert_eq!(leftovers, 0);
+    }
 }
\ No newline at end of file
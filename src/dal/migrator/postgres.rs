@@ -0,0 +1,66 @@
+use std::{
+    cell::RefCell,
+};
+use postgres::Client;
+
+use super::{
+    Applier,
+    Result,
+    MigrationError,
+};
+
+impl From<postgres::Error> for MigrationError {
+    fn from(e: postgres::Error) -> Self {
+        MigrationError(format!("problem interacting with database: {}", e))
+    }
+}
+
+/// PostgresMigrator applies migrations over a borrowed client; each migration runs in
+/// its own transaction, so a failed one leaves no trace behind
+pub struct PostgresMigrator<'c> {
+    client: RefCell<&'c mut Client>,
+}
+
+impl<'c> PostgresMigrator<'c> {
+    pub fn new(client: &'c mut Client) -> Self {
+        PostgresMigrator{client: RefCell::new(client)}
+    }
+}
+
+impl Applier for PostgresMigrator<'_> {
+    /// sets up the migration table; this should be idempotent
+    fn setup(&self) -> Result<()> {
+        self.client.borrow_mut().batch_execute("
+            CREATE TABLE IF NOT EXISTS SchemaMigrations (
+                name TEXT PRIMARY KEY
+            );
+        ")?;
+        Ok(())
+    }
+
+    /// applies the schema-altering SQL statements of a migration and marks it as applied,
+    /// in one transaction
+    fn apply(&self, name: &str, sql: &str) -> Result<()> {
+        let mut client = self.client.borrow_mut();
+        let mut tx = client.transaction()?;
+        tx.batch_execute(sql)?;
+        tx.execute("
+            INSERT INTO SchemaMigrations (name) VALUES ($1)
+        ", &[&name])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// retrieves all applied migrations
+    fn applied(&self) -> Result<Vec<String>> {
+        let rows = self.client.borrow_mut().query("
+            SELECT t1.name
+            FROM SchemaMigrations t1
+        ", &[])?;
+        let mut names = vec!();
+        for row in rows {
+            names.push(row.try_get(0)?);
+        }
+        Ok(names)
+    }
+}
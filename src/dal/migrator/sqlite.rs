@@ -52,17 +52,15 @@ impl Applier for SqliteMigrator {
         Ok(())
     }
 
-    /// applies a schema-altering SQL statement
-    fn apply(&self, sql: &str) -> Result<()> {
-        self.conn.lock()?.execute_batch(sql)?;
-        Ok(())
-    }
-
-    /// mark_applied marks the migration as applied
-    fn mark_applied(&self, name: &str) -> Result<()> {
-        self.conn.lock()?.execute("
+    /// applies the schema-altering SQL statements of a migration and marks it as applied
+    fn apply(&self, name: &str, sql: &str) -> Result<()> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(sql)?;
+        tx.execute("
             INSERT INTO SchemaMigrations (name) VALUES (?1)
         ", params![name])?;
+        tx.commit()?;
         Ok(())
     }
 
@@ -1,14 +1,40 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, VecDeque},
+    ops::Bound,
+    str::FromStr,
+};
 
 use chrono::prelude::*;
+use regex::Regex;
 
+#[cfg(test)]
+#[macro_use]
+mod suite;
+pub mod memory;
 pub mod migrator;
+pub mod postgres;
 pub mod sqlite;
+pub mod tsdb;
 
 #[derive(Debug, Clone)]
 pub enum DatabaseError{
     Custom(String),
     MigrationError(migrator::MigrationError),
+    /// a metric was rejected because its series already has a point at its time
+    Duplicate(String),
+    /// a write broke a constraint of the schema other than a unique key
+    ConstraintViolation(String),
+    /// something the request needed is not in the database
+    NotFound(String),
+    /// the database is locked by other work; trying again later may succeed
+    Busy(String),
+    /// the database files are damaged
+    Corrupt(String),
+    /// the request cannot be carried out as given, such as a malformed regular expression
+    InvalidArgument(String),
+    /// the database took too long to answer; trying again later may succeed
+    Timeout(String),
 }
 
 impl From<migrator::MigrationError> for DatabaseError {
@@ -25,6 +51,42 @@ impl std::fmt::Display for DatabaseError {
 
 impl std::error::Error for DatabaseError {}
 
+impl DatabaseError {
+    /// whether the same request may succeed if it is tried again later
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, DatabaseError::Busy(_) | DatabaseError::Timeout(_))
+    }
+
+    /// splits the error into the kind and message a remembered batch keeps it as
+    pub(crate) fn to_stored(&self) -> (&'static str, String) {
+        match self {
+            DatabaseError::Duplicate(message) => ("duplicate", message.clone()),
+            DatabaseError::ConstraintViolation(message) => ("constraint_violation", message.clone()),
+            DatabaseError::NotFound(message) => ("not_found", message.clone()),
+            DatabaseError::Busy(message) => ("busy", message.clone()),
+            DatabaseError::Corrupt(message) => ("corrupt", message.clone()),
+            DatabaseError::InvalidArgument(message) => ("invalid_argument", message.clone()),
+            DatabaseError::Timeout(message) => ("timeout", message.clone()),
+            DatabaseError::Custom(message) => ("custom", message.clone()),
+            e => ("custom", e.to_string()),
+        }
+    }
+
+    /// rebuilds an error kept by to_stored
+    pub(crate) fn from_stored(kind: &str, message: String) -> Self {
+        match kind {
+            "duplicate" => DatabaseError::Duplicate(message),
+            "constraint_violation" => DatabaseError::ConstraintViolation(message),
+            "not_found" => DatabaseError::NotFound(message),
+            "busy" => DatabaseError::Busy(message),
+            "corrupt" => DatabaseError::Corrupt(message),
+            "invalid_argument" => DatabaseError::InvalidArgument(message),
+            "timeout" => DatabaseError::Timeout(message),
+            _ => DatabaseError::Custom(message),
+        }
+    }
+}
+
 pub type Result<S> = std::result::Result<S, DatabaseError>;
 
 #[derive(Debug, Clone, PartialEq)]
@@ -33,20 +95,720 @@ pub enum MetricValue<'a> {
     String(Cow<'a, str>),
 }
 
+/// Labels are key/value dimensions attached to a series in addition to its name
+pub type Labels<'a> = BTreeMap<Cow<'a, str>, Cow<'a, str>>;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Metric<'a> {
     pub name: Cow<'a, str>,
+    pub labels: Labels<'a>,
     pub when: Cow<'a, DateTime<Utc>>,
     pub value: MetricValue<'a>,
 }
 
+impl Metric<'_> {
+    /// copies the metric so that it no longer borrows from anything
+    pub fn to_owned_metric(&self) -> Metric<'static> {
+        Metric{
+            name: Cow::Owned(self.name.to_string()),
+            labels: self.labels.iter().map(|(k, v)| (Cow::Owned(k.to_string()), Cow::Owned(v.to_string()))).collect(),
+            when: Cow::Owned(*self.when),
+            value: match &self.value {
+                MetricValue::Double(v) => MetricValue::Double(*v),
+                MetricValue::String(v) => MetricValue::String(Cow::Owned(v.to_string())),
+            },
+        }
+    }
+}
+
+/// builds the key that identifies a series, such as `hosts.cpu_load{host="aura"}`;
+/// series without labels are identified by their name alone, so the key of a series
+/// always starts with its name
+pub fn series_key(name: &str, labels: &Labels) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    let mut key = format!("{}{{", name);
+    for (i, (k, v)) in labels.iter().enumerate() {
+        if i > 0 {
+            key.push(',');
+        }
+        key.push_str(k);
+        key.push_str("=\"");
+        for c in v.chars() {
+            match c {
+                '\\' => key.push_str("\\\\"),
+                '"' => key.push_str("\\\""),
+                '\n' => key.push_str("\\n"),
+                c => key.push(c),
+            }
+        }
+        key.push('"');
+    }
+    key.push('}');
+    key
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    /// the value must match the whole of the regular expression
+    Regex,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LabelMatcher {
+    pub key: String,
+    pub op: MatchOp,
+    pub value: String,
+}
+
+/// NamePattern matches whole series names
+#[derive(Debug, Clone, PartialEq)]
+pub enum NamePattern {
+    /// a Graphite-style glob such as `hosts.*.cpu_load`, where `*` and `?` stay within a
+    /// dot-separated segment, `[ab]` matches one of a set of characters and `{a,b}` one of
+    /// several alternatives
+    Glob(String),
+    Regex(String),
+}
+
+impl NamePattern {
+    /// the regular expression, anchored to the whole name, that the pattern stands for
+    pub fn to_regex(&self) -> String {
+        match self {
+            NamePattern::Glob(glob) => format!("^(?:{})$", glob_to_regex(glob)),
+            NamePattern::Regex(regex) => format!("^(?:{})$", regex),
+        }
+    }
+}
+
+/// translates a glob into a regular expression; brackets and braces that are never
+/// closed are taken literally
+fn glob_to_regex(glob: &str) -> String {
+    let mut regex = String::new();
+    let mut in_braces = false;
+    let mut chars = glob.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '*' => regex.push_str("[^.]*"),
+            '?' => regex.push_str("[^.]"),
+            '[' if glob[i + 1..].contains(']') => {
+                regex.push('[');
+                if let Some((_, '!')) = chars.peek() {
+                    chars.next();
+                    regex.push('^');
+                }
+                for (_, c) in chars.by_ref() {
+                    match c {
+                        ']' => break,
+                        '-' => regex.push('-'),
+                        c => regex.push_str(&regex::escape(&c.to_string())),
+                    }
+                }
+                regex.push(']');
+            },
+            '{' if !in_braces && glob[i + 1..].contains('}') => {
+                in_braces = true;
+                regex.push_str("(?:");
+            },
+            ',' if in_braces => regex.push('|'),
+            '}' if in_braces => {
+                in_braces = false;
+                regex.push(')');
+            },
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+/// Selector picks the series a query applies to: every series whose name starts with
+/// the prefix, is one of the names if any are given, matches the pattern if there is
+/// one, and whose labels satisfy all of the matchers. Names are matched case-sensitively
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Selector {
+    pub prefix: String,
+    pub names: Vec<String>,
+    pub pattern: Option<NamePattern>,
+    pub matchers: Vec<LabelMatcher>,
+}
+
+impl From<&str> for Selector {
+    fn from(prefix: &str) -> Self {
+        Selector{
+            prefix: prefix.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Bounds picks which ends of a time range belong to it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bounds {
+    /// `[start, stop)`, so adjacent ranges tile without overlapping
+    HalfOpen,
+    /// `[start, stop]`
+    Inclusive,
+    /// `(start, stop)`
+    Exclusive,
+}
+
+impl Bounds {
+    pub fn includes_start(&self) -> bool {
+        matches!(self, Bounds::HalfOpen | Bounds::Inclusive)
+    }
+
+    pub fn includes_stop(&self) -> bool {
+        matches!(self, Bounds::Inclusive)
+    }
+}
+
+/// TimeRange limits a read to the points between start and stop, either of which
+/// may be left open; by default the range is unbounded and half-open
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimeRange {
+    pub start: Option<DateTime<Utc>>,
+    pub stop: Option<DateTime<Utc>>,
+    pub bounds: Bounds,
+}
+
+impl Default for TimeRange {
+    fn default() -> Self {
+        TimeRange::new(None, None, Bounds::HalfOpen)
+    }
+}
+
+impl TimeRange {
+    pub fn new(start: Option<DateTime<Utc>>, stop: Option<DateTime<Utc>>, bounds: Bounds) -> Self {
+        TimeRange{start, stop, bounds}
+    }
+
+    /// whether a point at `t` falls within the range
+    pub fn contains(&self, t: &DateTime<Utc>) -> bool {
+        let after_start = match &self.start {
+            Some(start) if self.bounds.includes_start() => t >= start,
+            Some(start) => t > start,
+            None => true,
+        };
+        let before_stop = match &self.stop {
+            Some(stop) if self.bounds.includes_stop() => t <= stop,
+            Some(stop) => t < stop,
+            None => true,
+        };
+        after_start && before_stop
+    }
+}
+
+/// ReadResult holds a page of metrics returned by read_metrics
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ReadResult<'a> {
+    pub metrics: Vec<Metric<'a>>,
+    /// keys of the series that had more points than the limit allowed, with the time of
+    /// the last point returned for each; pass these back to read_metrics to continue reading
+    pub truncated: Vec<(String, DateTime<Utc>)>,
+}
+
+/// Aggregator is applied to every point that falls in a bucket when downsampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregator {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+    Last,
+    /// nearest-rank percentile, from 0 to 100
+    Percentile(f64),
+}
+
+impl Aggregator {
+    /// whether the aggregator makes sense for string-valued series
+    pub fn supports_strings(&self) -> bool {
+        matches!(self, Aggregator::Count | Aggregator::Last)
+    }
+}
+
+/// the bucket widths, in seconds, that backends may keep pre-aggregated rollups for
+pub const ROLLUP_RESOLUTIONS: [i64; 3] = [60, 60 * 60, 24 * 60 * 60];
+
+/// PathNode is one of the distinct components that dotted series names continue with
+/// after a prefix
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathNode {
+    /// the component, without the prefix or the dot that follows it
+    pub component: String,
+    /// whether some series is named exactly the prefix followed by the component
+    pub leaf: bool,
+    /// whether some series names continue past the component
+    pub branch: bool,
+    /// how many series are named the prefix followed by the component, or lie below it
+    pub series: usize,
+}
+
+/// Filter applies a selector to series in memory, the same way the sqlite database does
+pub(crate) struct Filter {
+    prefix: String,
+    names: Vec<String>,
+    pattern: Option<Regex>,
+    matchers: Vec<(String, MatchOp, String, Option<Regex>)>,
+}
+
+impl Filter {
+    pub(crate) fn new(selector: &Selector) -> std::result::Result<Self, regex::Error> {
+        let mut matchers = Vec::with_capacity(selector.matchers.len());
+        for matcher in &selector.matchers {
+            let regex = match matcher.op {
+                MatchOp::Regex => Some(Regex::new(&format!("^(?:{})$", matcher.value))?),
+                _ => None,
+            };
+            matchers.push((matcher.key.clone(), matcher.op, matcher.value.clone(), regex));
+        }
+        let pattern = match &selector.pattern {
+            Some(pattern) => Some(Regex::new(&pattern.to_regex())?),
+            None => None,
+        };
+        Ok(Filter{
+            prefix: selector.prefix.clone(),
+            names: selector.names.clone(),
+            pattern,
+            matchers,
+        })
+    }
+
+    pub(crate) fn matches(&self, name: &str, labels: &Labels) -> bool {
+        if !name.starts_with(&self.prefix) || !(self.names.is_empty() || self.names.iter().any(|n| n == name)) {
+            return false;
+        }
+        if let Some(pattern) = &self.pattern {
+            if !pattern.is_match(name) {
+                return false;
+            }
+        }
+        self.matchers.iter().all(|(key, op, value, regex)| {
+            let label = labels.get(key.as_str());
+            match (op, regex) {
+                (MatchOp::Equal, _) => label.map(|l| l == value) == Some(true),
+                (MatchOp::NotEqual, _) => label.map(|l| l == value) != Some(true),
+                (MatchOp::Regex, Some(regex)) => label.map(|l| regex.is_match(l)) == Some(true),
+                (MatchOp::Regex, None) => false,
+            }
+        })
+    }
+}
+
+/// times are stored as nanoseconds since the unix epoch
+pub(crate) const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// converts a time to nanoseconds since the unix epoch, if it can be stored
+pub(crate) fn to_nanos(t: &DateTime<Utc>) -> Option<i64> {
+    t.timestamp().checked_mul(NANOS_PER_SECOND)?.checked_add(t.timestamp_subsec_nanos() as i64)
+}
+
+/// converts a time to nanoseconds for comparing against stored times, clamping times
+/// that are too far out to be stored to the earliest or latest possible time
+pub(crate) fn bound_nanos(t: &DateTime<Utc>) -> i64 {
+    to_nanos(t).unwrap_or(if t.timestamp() < 0 { i64::MIN } else { i64::MAX })
+}
+
+/// converts nanoseconds since the unix epoch back to a time
+pub(crate) fn from_nanos(nanos: i64) -> DateTime<Utc> {
+    Utc.timestamp_nanos(nanos)
+}
+
+/// converts seconds since the unix epoch, such as the start of a bucket, to a time
+pub(crate) fn from_seconds(seconds: i64) -> Result<DateTime<Utc>> {
+    Utc.timestamp_opt(seconds, 0).single()
+        .ok_or_else(|| DatabaseError::Custom(format!("{} seconds since the epoch is out of range", seconds)))
+}
+
+/// the smallest string greater than every string starting with the prefix, if there is one
+pub(crate) fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end: Vec<char> = prefix.chars().collect();
+    while let Some(last) = end.pop() {
+        // skip over the surrogate range, which chars cannot hold
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(std::char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// groups series keys starting with `prefix` by the component that follows it, as
+/// browse_metrics returns them
+pub(crate) fn browse_nodes<I: IntoIterator<Item=String>>(prefix: &str, keys: I) -> Vec<PathNode> {
+    let mut nodes: BTreeMap<String, PathNode> = BTreeMap::new();
+    for key in keys {
+        // keys of labelled series carry their labels after the name
+        let name = key.split('{').next().unwrap_or_default();
+        let rest = &name[prefix.len().min(name.len())..];
+        let (component, leaf) = match rest.find('.') {
+            Some(dot) => (&rest[..dot], false),
+            None => (rest, true),
+        };
+        let node = nodes.entry(component.to_string()).or_insert_with(|| PathNode{
+            component: component.to_string(),
+            leaf: false,
+            branch: false,
+            series: 0,
+        });
+        node.leaf |= leaf;
+        node.branch |= !leaf;
+        node.series += 1;
+    }
+    nodes.into_values().collect()
+}
+
+/// the entries of a map keyed by series key whose keys start with the prefix, in order
+pub(crate) fn series_within<'m, V>(series: &'m BTreeMap<String, V>, prefix: &'m str) -> impl Iterator<Item=(&'m String, &'m V)> {
+    let end = match prefix_end(prefix) {
+        Some(end) => Bound::Excluded(end),
+        None => Bound::Unbounded,
+    };
+    series.range::<str, _>((Bound::Included(prefix), end.as_ref().map(String::as_str)))
+}
+
+/// NanoRange is a TimeRange in the nanoseconds points are kept as
+pub(crate) struct NanoRange {
+    start: Option<i64>,
+    stop: Option<i64>,
+    bounds: Bounds,
+}
+
+impl NanoRange {
+    pub(crate) fn new(range: &TimeRange) -> Self {
+        NanoRange{
+            start: range.start.as_ref().map(bound_nanos),
+            stop: range.stop.as_ref().map(bound_nanos),
+            bounds: range.bounds,
+        }
+    }
+
+    fn after_start(&self, time: i64) -> bool {
+        match self.start {
+            Some(start) if self.bounds.includes_start() => time >= start,
+            Some(start) => time > start,
+            None => true,
+        }
+    }
+
+    fn before_stop(&self, time: i64) -> bool {
+        match self.stop {
+            Some(stop) if self.bounds.includes_stop() => time <= stop,
+            Some(stop) => time < stop,
+            None => true,
+        }
+    }
+
+    pub(crate) fn contains(&self, time: i64) -> bool {
+        self.after_start(time) && self.before_stop(time)
+    }
+
+    /// whether any time from `min` to `max` falls within the range
+    pub(crate) fn overlaps(&self, min: i64, max: i64) -> bool {
+        self.after_start(max) && self.before_stop(min)
+    }
+}
+
+/// aggregates one series' points, in time order, into buckets of `width` seconds aligned
+/// to the unix epoch, for backends that aggregate in memory; strings and NaNs are skipped
+/// unless the aggregator supports strings, as SQL aggregates skip them
+pub(crate) fn aggregate_buckets<I>(points: I, width: i64, aggregator: Aggregator) -> Result<Vec<(DateTime<Utc>, MetricValue<'static>)>>
+where I: IntoIterator<Item=(i64, MetricValue<'static>)> {
+    // the points come in time order, so each bucket's points are next to each other
+    let mut buckets: Vec<(i64, Vec<MetricValue>)> = vec!();
+    for (time, value) in points {
+        let numeric = match &value {
+            MetricValue::Double(d) => !d.is_nan(),
+            MetricValue::String(_) => false,
+        };
+        if !numeric && !aggregator.supports_strings() {
+            continue;
+        }
+        let epoch = time.div_euclid(NANOS_PER_SECOND);
+        let bucket = epoch - epoch.rem_euclid(width);
+        match buckets.last_mut() {
+            Some((last, values)) if *last == bucket => values.push(value),
+            _ => buckets.push((bucket, vec!(value))),
+        }
+    }
+    buckets.into_iter()
+        .map(|(bucket, values)| Ok((from_seconds(bucket)?, aggregate(values, aggregator))))
+        .collect()
+}
+
+/// combines the values in a bucket, which holds at least one
+fn aggregate(values: Vec<MetricValue<'static>>, aggregator: Aggregator) -> MetricValue<'static> {
+    let mut doubles: Vec<f64> = values.iter().filter_map(|value| match value {
+        MetricValue::Double(d) => Some(*d),
+        MetricValue::String(_) => None,
+    }).collect();
+    let count = values.len() as f64;
+    MetricValue::Double(match aggregator {
+        Aggregator::Avg => doubles.iter().sum::<f64>() / count,
+        Aggregator::Min => doubles.iter().copied().fold(f64::INFINITY, f64::min),
+        Aggregator::Max => doubles.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        Aggregator::Sum => doubles.iter().sum(),
+        Aggregator::Count => count,
+        Aggregator::Last => return values.into_iter().last().unwrap_or(MetricValue::Double(f64::NAN)),
+        Aggregator::Percentile(p) => {
+            // nearest rank
+            doubles.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+            let rank = (doubles.len() as f64 * p / 100.0).ceil().max(1.0) as usize;
+            doubles.get(rank.min(doubles.len()).saturating_sub(1)).copied().unwrap_or(f64::NAN)
+        },
+    })
+}
+
+/// WriteMode controls how a batch of writes behaves when some of the metrics fail
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    /// either every metric in the batch is written, or none of them are
+    Atomic,
+    /// every metric that can be written is; failures are reported per metric
+    Partial,
+}
+
+/// the time in nanoseconds before which batches committed by `now` are forgotten
+pub(crate) fn batch_cutoff(now: &DateTime<Utc>, ttl: chrono::Duration) -> i64 {
+    now.checked_sub_signed(ttl).map(|cutoff| bound_nanos(&cutoff)).unwrap_or(i64::MIN)
+}
+
+/// Written is what came of writing a batch of metrics
+#[derive(Debug, Clone, Default)]
+pub struct Written {
+    /// the index and error of every metric that could not be written
+    pub failures: Vec<(usize, DatabaseError)>,
+    /// the index of every metric left out because the conflict policy kept the point
+    /// already at its time
+    pub discarded: Vec<usize>,
+}
+
+/// BatchWrite is what came of writing a batch under a client-supplied ID
+#[derive(Debug, Clone)]
+pub struct BatchWrite {
+    /// the index and error of every metric that could not be written
+    pub failures: Vec<(usize, DatabaseError)>,
+    /// the index of every metric the conflict policy left out; none for a replayed batch
+    pub discarded: Vec<usize>,
+    /// whether a batch with the ID had already been committed, in which case nothing was
+    /// written and the failures are those from the first time
+    pub replayed: bool,
+}
+
+/// RecentBatches remembers the failures of the batches committed under an ID, for the
+/// backends that keep them in memory
+#[derive(Debug, Default)]
+pub(crate) struct RecentBatches {
+    failures: HashMap<String, Vec<(usize, DatabaseError)>>,
+    /// the IDs by when they were committed, in nanoseconds, oldest first
+    committed: VecDeque<(i64, String)>,
+}
+
+impl RecentBatches {
+    /// forgets the batches committed before the cutoff
+    pub(crate) fn expire(&mut self, cutoff: i64) {
+        while let Some((committed, _)) = self.committed.front() {
+            if *committed >= cutoff {
+                break;
+            }
+            if let Some((_, id)) = self.committed.pop_front() {
+                self.failures.remove(&id);
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, id: &str) -> Option<&Vec<(usize, DatabaseError)>> {
+        self.failures.get(id)
+    }
+
+    pub(crate) fn insert(&mut self, id: String, committed: i64, failures: Vec<(usize, DatabaseError)>) {
+        if self.failures.insert(id.clone(), failures).is_none() {
+            self.committed.push_back((committed, id));
+        }
+    }
+
+    /// every batch remembered, oldest first, with when it was committed
+    pub(crate) fn iter(&self) -> impl Iterator<Item=(&str, i64, &Vec<(usize, DatabaseError)>)> {
+        self.committed.iter().filter_map(move |(committed, id)| self.failures.get(id).map(|failures| (id.as_str(), *committed, failures)))
+    }
+}
+
+/// ConflictPolicy decides what happens to a metric written at a time its series already
+/// has a point at
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConflictPolicy {
+    /// the metric fails with DatabaseError::Duplicate
+    #[default]
+    Reject,
+    /// the metric replaces every point already at that time
+    Overwrite,
+    /// the metric is dropped, keeping the point already there
+    KeepFirst,
+    /// the metric is kept as another sample at that time, after those already there
+    MultiSample,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(ConflictPolicy::Reject),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "keep-first" => Ok(ConflictPolicy::KeepFirst),
+            "multi-sample" => Ok(ConflictPolicy::MultiSample),
+            _ => Err(format!("unknown conflict policy '{}'; expected reject, overwrite, keep-first or multi-sample", s)),
+        }
+    }
+}
+
+/// ConflictPolicies picks the conflict policy for each metric by the longest prefix of
+/// its name given one, falling back to a default. It is configured with a string such as
+/// `hosts.=overwrite,statsd.=multi-sample`, where an empty prefix sets the default
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ConflictPolicies {
+    pub default: ConflictPolicy,
+    pub prefixes: Vec<(String, ConflictPolicy)>,
+}
+
+impl ConflictPolicies {
+    /// applies one policy to every metric
+    pub fn uniform(policy: ConflictPolicy) -> Self {
+        ConflictPolicies{
+            default: policy,
+            prefixes: vec!(),
+        }
+    }
+
+    pub fn policy_for(&self, name: &str) -> ConflictPolicy {
+        self.prefixes.iter()
+            .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, policy)| *policy)
+            .unwrap_or(self.default)
+    }
+}
+
+impl FromStr for ConflictPolicies {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut policies = ConflictPolicies::default();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let prefix = parts.next().unwrap_or_default().trim();
+            let policy = parts.next()
+                .ok_or_else(|| format!("conflict policy '{}' should look like prefix=policy", entry))?
+                .trim()
+                .parse()?;
+            if prefix.is_empty() {
+                policies.default = policy;
+            } else {
+                policies.prefixes.push((prefix.to_string(), policy));
+            }
+        }
+        Ok(policies)
+    }
+}
+
 pub trait Database: Send + Sync {
     fn setup(&self) -> Result<()>;
-    fn write_metric(&self, metric: &Metric) -> Result<()>;
-    /// reads metrics with exclusive time ranges
-    fn read_metrics<'a>(&'a self, prefix: &str, start: Option<&DateTime<Utc>>, stop: Option<&DateTime<Utc>>, limit: usize)
-        -> Result<Vec<Metric<'a>>>;
+    fn write_metric(&self, metric: &Metric) -> Result<()> {
+        match self.write_metrics(std::slice::from_ref(metric), WriteMode::Atomic)?.pop() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+    /// writes a batch of metrics, rejecting those at a time their series already has a
+    /// point at
+    fn write_metrics(&self, metrics: &[Metric], mode: WriteMode) -> Result<Vec<(usize, DatabaseError)>> {
+        Ok(self.write_metrics_with(metrics, mode, &ConflictPolicies::default())?.failures)
+    }
+    /// writes a batch of metrics, settling those at a time their series already has a
+    /// point at, or that another metric in the batch is at, by the policy for their name.
+    /// Returns the index and error of every metric that could not be written, and the index
+    /// of every one the policy left out; in atomic mode the batch stops at the first failure
+    /// and nothing is committed
+    fn write_metrics_with(&self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies)
+        -> Result<Written>;
+    /// writes a batch like write_metrics_with, and remembers its ID and failures for `ttl`
+    /// once it is committed. A batch whose ID is still remembered is not written again;
+    /// the failures from the first time are returned instead, whatever metrics it holds.
+    /// An atomic batch that fails commits nothing, so its ID is not remembered
+    fn write_batch(&self, id: &str, ttl: chrono::Duration, metrics: &[Metric], mode: WriteMode,
+        conflicts: &ConflictPolicies) -> Result<BatchWrite>;
+    /// reads the metrics at up to `limit` times per series within the range, with every
+    /// sample at a time read together; if `resume` is not empty, only the listed series
+    /// keys the selector picks are read, each starting after the given time
+    fn read_metrics<'a>(&'a self, selector: &Selector, range: &TimeRange, limit: usize,
+        resume: &[(String, DateTime<Utc>)]) -> Result<ReadResult<'a>>;
     
-    /// lists all metrics matching the prefix, along with last updated timestamp
-    fn list_metrics<'a>(&'a self, prefix: &str) -> Result<Vec<(String, DateTime<Utc>)>>;
-}
\ No newline at end of file
+    /// downsamples metrics into buckets of `width` aligned to the unix epoch, returning one
+    /// metric per series and bucket stamped with the bucket start; string-valued series
+    /// are skipped unless the aggregator supports them
+    fn read_aggregated<'a>(&'a self, selector: &Selector, range: &TimeRange,
+        width: chrono::Duration, aggregator: Aggregator) -> Result<Vec<Metric<'a>>>;
+
+    /// reads the `count` most recent metrics of each series within the range, with each
+    /// series' metrics ordered newest first
+    fn read_tail<'a>(&'a self, selector: &Selector, range: &TimeRange, count: usize) -> Result<Vec<Metric<'a>>>;
+
+    /// rolls metrics recorded before `now` up into lower-resolution aggregates that
+    /// read_aggregated can use in place of raw metrics; backends that do not keep
+    /// rollups need not do anything
+    fn roll_up(&self, _now: &DateTime<Utc>) -> Result<()> {
+        Ok(())
+    }
+
+    /// deletes every metric matching the prefix recorded before the given time, along with
+    /// any rollups made from them, returning how many metrics were removed
+    fn delete_metrics(&self, prefix: &str, before: &DateTime<Utc>) -> Result<usize> {
+        self.delete_metrics_except(prefix, &[], before)
+    }
+    /// deletes like delete_metrics, but leaves alone the metrics matching any of the
+    /// `except` prefixes
+    fn delete_metrics_except(&self, prefix: &str, except: &[String], before: &DateTime<Utc>) -> Result<usize>;
+
+    /// reads the most recent metric of every series matching the selector
+    fn latest_values(&self, selector: &Selector) -> Result<Vec<Metric<'_>>>;
+
+    /// lists all series matching the selector, along with their labels and last updated timestamp
+    fn list_metrics(&self, selector: &Selector) -> Result<Vec<(String, Labels<'static>, DateTime<Utc>)>>;
+
+    /// lists the distinct components that series names continue with after the prefix, up
+    /// to the next dot, in order; the prefix normally ends with a dot, such as `hosts.`
+    fn browse_metrics(&self, prefix: &str) -> Result<Vec<PathNode>>;
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_ends() {
+        assert_eq!(prefix_end("hosts."), Some("hosts/".to_string()));
+        assert_eq!(prefix_end("a\u{10FFFF}"), Some("b".to_string()));
+        assert_eq!(prefix_end("\u{D7FF}"), Some("\u{E000}".to_string()));
+        assert_eq!(prefix_end("\u{10FFFF}"), None);
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let matches = |glob: &str, name: &str| regex::Regex::new(&NamePattern::Glob(glob.into()).to_regex())
+            .unwrap()
+            .is_match(name);
+        assert!(matches("hosts.*.cpu_load", "hosts.aura.cpu_load"));
+        assert!(!matches("hosts.*.cpu_load", "hosts.aura.eu.cpu_load"));
+        assert!(!matches("hosts.*.cpu_load", "hostsXaura.cpu_load"));
+        assert!(matches("hosts.aur?.cpu_load", "hosts.aura.cpu_load"));
+        assert!(matches("hosts.[a-c]ura.*", "hosts.aura.cpu_load"));
+        assert!(!matches("hosts.[!a-c]ura.*", "hosts.aura.cpu_load"));
+        assert!(matches("hosts.{aura,borealis}.cpu_load", "hosts.borealis.cpu_load"));
+        assert!(!matches("hosts.{aura,borealis}.cpu_load", "hosts.cirrus.cpu_load"));
+        assert!(matches("hosts.{aura", "hosts.{aura"));
+        assert!(matches("disk_io.[sd", "disk_io.[sd"));
+    }
+}
@@ -1,5 +1,7 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
+    convert::TryFrom,
     sync::{
         Arc,
         Mutex,
@@ -9,20 +11,50 @@ use std::{
 };
 
 use chrono::prelude::*;
+use regex::Regex;
 use rusqlite::{
+    CachedStatement,
     Connection,
+    ErrorCode,
+    OptionalExtension,
+    Row,
+    ffi,
     ToSql,
+    Transaction,
+    NO_PARAMS,
     params,
     named_params,
+    functions::FunctionFlags,
 };
 use rust_embed::RustEmbed;
 
 use super::{
+    Aggregator,
+    BatchWrite,
+    ConflictPolicies,
+    ConflictPolicy,
     Database,
     DatabaseError,
+    Labels,
+    MatchOp,
     Metric,
     MetricValue,
+    PathNode,
+    ReadResult,
     Result,
+    Selector,
+    TimeRange,
+    WriteMode,
+    Written,
+    NANOS_PER_SECOND,
+    batch_cutoff,
+    bound_nanos,
+    browse_nodes,
+    from_nanos,
+    from_seconds,
+    prefix_end,
+    series_key,
+    to_nanos,
     migrator::{
         migrate,
         sqlite::SqliteMigrator,
@@ -37,13 +69,24 @@ impl From<PoisonError<MutexGuard<'_, Connection>>> for DatabaseError {
 
 impl From<rusqlite::Error> for DatabaseError {
     fn from(e: rusqlite::Error) -> Self {
-        DatabaseError::Custom(format!("problem interacting with database: {}", e))
-    }
-}
-
-impl From<chrono::ParseError> for DatabaseError {
-    fn from(e: chrono::ParseError) -> Self {
-        DatabaseError::Custom(format!("problem parsing timestamp from database: {}", e))
+        let message = format!("problem interacting with database: {}", e);
+        match e {
+            rusqlite::Error::SqliteFailure(failure, _) => match failure.code {
+                ErrorCode::ConstraintViolation => match failure.extended_code {
+                    // a point is keyed by its series and time; other unique constraints are not duplicate points
+                    ffi::SQLITE_CONSTRAINT_PRIMARYKEY => DatabaseError::Duplicate(message),
+                    _ => DatabaseError::ConstraintViolation(message),
+                },
+                ErrorCode::DatabaseBusy if failure.extended_code == ffi::SQLITE_BUSY_TIMEOUT => DatabaseError::Timeout(message),
+                ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked => DatabaseError::Busy(message),
+                ErrorCode::OperationInterrupted => DatabaseError::Timeout(message),
+                ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase => DatabaseError::Corrupt(message),
+                ErrorCode::TooBig | ErrorCode::TypeMismatch => DatabaseError::InvalidArgument(message),
+                _ => DatabaseError::Custom(message),
+            },
+            rusqlite::Error::QueryReturnedNoRows => DatabaseError::NotFound(message),
+            _ => DatabaseError::Custom(message),
+        }
     }
 }
 
@@ -52,16 +95,73 @@ impl From<chrono::ParseError> for DatabaseError {
 #[folder = "migrations/sqlite"]
 struct Migrations;
 
+/// the rollup tables and their resolution in seconds, finest first
+const ROLLUP_TABLES: [(&str, i64); 3] = [
+    ("Rollups1m", 60),
+    ("Rollups1h", 60 * 60),
+    ("Rollups1d", 24 * 60 * 60),
+];
+
+/// the number of buckets rolled up per statement, so the connection is released regularly
+const ROLLUP_CHUNK_BUCKETS: i64 = 24 * 60;
+
+/// the number of rows removed per statement by delete_metrics; the connection is
+/// released between chunks so writers are not held up by a large delete
+const DELETE_CHUNK_SIZE: u32 = 1000;
+
+/// the whole seconds since the unix epoch of the time column in t1, rounded down
+const EPOCH_SECONDS: &str = "(t1.time - ((t1.time % 1000000000) + 1000000000) % 1000000000) / 1000000000";
+
+#[derive(Clone)]
 pub struct SqliteDatabase{
     conn: Arc<Mutex<Connection>>,
 }
 
 impl SqliteDatabase{
     pub fn new(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        add_regexp_function(&conn)?;
         Ok(SqliteDatabase {
-            conn: Arc::new(Mutex::new(Connection::open(path)?)),
+            conn: Arc::new(Mutex::new(conn)),
         })
     }
+
+    /// deletes the rows of `table` picked by the filter whose `condition` holds against
+    /// `:before`, a chunk at a time, returning how many went
+    fn delete_chunked(&self, table: &str, filter: &SeriesFilter, condition: &str, before: i64) -> Result<usize> {
+        let mut deleted = 0;
+        loop {
+            let mut params = filter.params();
+            params.push((":before", &before));
+            params.push((":limit", &DELETE_CHUNK_SIZE));
+            let chunk = self.conn.lock()?.execute_named(&format!("
+                DELETE FROM {table}
+                WHERE rowid IN (
+                    SELECT t1.rowid
+                    FROM {table} t1
+                    WHERE {clause}
+                        AND {condition}
+                    LIMIT :limit
+                )
+            ", table = table, clause = filter.clause, condition = condition), params.as_slice())?;
+            deleted += chunk;
+            if chunk < DELETE_CHUNK_SIZE as usize {
+                return Ok(deleted);
+            }
+        }
+    }
+}
+
+/// registers the function behind REGEXP, which sqlite leaves to the application
+fn add_regexp_function(conn: &Connection) -> Result<()> {
+    conn.create_scalar_function("regexp", 2, FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC, |ctx| {
+        let re = ctx.get_or_create_aux(0, |pattern| -> std::result::Result<_, Box<dyn std::error::Error + Send + Sync>> {
+            Ok(Regex::new(pattern.as_str()?)?)
+        })?;
+        let text = ctx.get_raw(1).as_str().map_err(|e| rusqlite::Error::UserFunctionError(e.into()))?;
+        Ok(re.is_match(text))
+    })?;
+    Ok(())
 }
 
 impl Database for SqliteDatabase {
@@ -69,109 +169,981 @@ impl Database for SqliteDatabase {
         Ok(migrate::<Migrations, _>(&SqliteMigrator::new(self.conn.clone()))?)
     }
 
-    fn write_metric(&self, metric: &Metric) -> Result<()> {
-        let (typ, dvalue, tvalue) = match &metric.value {
-            MetricValue::Double(d) => ("double", *d, Cow::Borrowed("")),
-            MetricValue::String(s) => ("string", 0.0, s.clone()),
-        };
-        self.conn.lock()?.execute("
-            INSERT INTO Metrics (name, time, value_type, dvalue, tvalue) VALUES (?1, ?2, ?3, ?4, ?5)
-        ", params![metric.name, metric.when.to_rfc3339_opts(chrono::SecondsFormat::Nanos, true), typ, dvalue, tvalue])?;
-        Ok(())
+    fn write_metrics_with(&self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies)
+        -> Result<Written> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        let written = insert_metrics(&tx, metrics, mode, conflicts)?;
+        if mode == WriteMode::Atomic && !written.failures.is_empty() {
+            tx.rollback()?;
+        } else {
+            tx.commit()?;
+        }
+        Ok(written)
     }
 
-    fn read_metrics<'a>(&'a self, prefix: &str, start: Option<&DateTime<Utc>>, stop: Option<&DateTime<Utc>>, limit: usize)
-        -> Result<Vec<Metric<'a>>> {
-        // prepare the query
-        let mut query = "
-            SELECT t1.name,
-                t1.time,
-                t1.value_type,
-                t1.dvalue,
-                t1.tvalue
-            FROM Metrics t1
-            WHERE t1.name LIKE :prefix
-        ".to_string();
-        let start_string;
-        let stop_string;
-        let prefix = &format!("{}%", prefix);
-        let mut params: Vec<(&str, &dyn ToSql)> = vec!((":prefix", &prefix));
-        if let Some(start) = start {
-            query += "
-                AND t1.time > :start
-            ";
-            start_string = start.to_rfc3339();
-            params.push((":start", &start_string));
-        };
-        if let Some(stop) = stop {
-            query += "
-                AND t1.time < :stop
-            ";
-            stop_string = stop.to_rfc3339();
-            params.push((":stop", &stop_string));
+    fn write_batch(&self, id: &str, ttl: chrono::Duration, metrics: &[Metric], mode: WriteMode,
+        conflicts: &ConflictPolicies) -> Result<BatchWrite> {
+        let mut conn = self.conn.lock()?;
+        let tx = conn.transaction()?;
+        let now = Utc::now();
+        let cutoff = batch_cutoff(&now, ttl);
+        tx.execute("
+            DELETE FROM BatchFailures WHERE batch IN (SELECT id FROM Batches WHERE committed < ?1)
+        ", params![cutoff])?;
+        tx.execute("DELETE FROM Batches WHERE committed < ?1", params![cutoff])?;
+        let replayed = tx.query_row("SELECT 1 FROM Batches WHERE id = ?1", params![id], |_| Ok(()))
+            .optional()?
+            .is_some();
+        if replayed {
+            let failures = {
+                let mut stmt = tx.prepare("SELECT idx, kind, message FROM BatchFailures WHERE batch = ?1 ORDER BY idx")?;
+                let rows = stmt.query_map(params![id], |row| {
+                    let index: i64 = row.get(0)?;
+                    let kind: String = row.get(1)?;
+                    Ok((index as usize, DatabaseError::from_stored(&kind, row.get(2)?)))
+                })?;
+                rows.collect::<rusqlite::Result<Vec<_>>>()?
+            };
+            // commits forgetting the expired batches
+            tx.commit()?;
+            return Ok(BatchWrite{failures, discarded: vec!(), replayed: true});
+        }
+        let Written{failures, discarded} = insert_metrics(&tx, metrics, mode, conflicts)?;
+        if mode == WriteMode::Atomic && !failures.is_empty() {
+            tx.rollback()?;
+            return Ok(BatchWrite{failures, discarded, replayed: false});
+        }
+        tx.execute("INSERT INTO Batches (id, committed) VALUES (?1, ?2)", params![id, bound_nanos(&now)])?;
+        for (index, e) in &failures {
+            let (kind, message) = e.to_stored();
+            tx.execute("
+                INSERT INTO BatchFailures (batch, idx, kind, message) VALUES (?1, ?2, ?3, ?4)
+            ", params![id, *index as i64, kind, message])?;
+        }
+        tx.commit()?;
+        Ok(BatchWrite{failures, discarded, replayed: false})
+    }
+
+    fn read_metrics<'a>(&'a self, selector: &Selector, range: &TimeRange, limit: usize,
+        resume: &[(String, DateTime<Utc>)]) -> Result<ReadResult<'a>> {
+        let conn = self.conn.lock()?;
+        let mut page = ReadResult::default();
+        let filter = SeriesFilter::new(selector);
+        if resume.is_empty() {
+            read_series(&conn, &filter.clause, &filter.params(), range, limit, &mut page)?;
+        }
+        // a series is only resumed if the selector still picks it
+        let resumed = format!("{} AND t1.name = :name AND t1.time > :after", filter.clause);
+        for (name, after) in resume {
+            let after = &bound_nanos(after);
+            let mut params = filter.params();
+            params.extend_from_slice(&[(":name", name as &dyn ToSql), (":after", after)]);
+            read_series(&conn, &resumed, &params, range, limit, &mut page)?;
+        }
+        resolve_series(&conn, &mut page.metrics)?;
+        Ok(page)
+    }
+
+    fn read_aggregated<'a>(&'a self, selector: &Selector, range: &TimeRange,
+        width: chrono::Duration, aggregator: Aggregator) -> Result<Vec<Metric<'a>>> {
+        let width = width.num_seconds();
+        if width < 1 {
+            return Err(DatabaseError::InvalidArgument("bucket width must be at least one second".into()));
+        }
+        let conn = self.conn.lock()?;
+        let filter = SeriesFilter::new(selector);
+        let mut metrics = vec!();
+        // buckets that have been rolled up are read from the rollups, and the rest from raw metrics
+        let mut from = None;
+        if let Some((table, cutoff)) = pick_rollup(&conn, range, width, aggregator)? {
+            aggregate_rollup(&conn, table, &filter, range, cutoff, width, aggregator, &mut metrics)?;
+            from = Some(cutoff);
+            metrics.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        }
+        aggregate_raw(&conn, &filter, range, from, width, aggregator, &mut metrics)?;
+        if from.is_some() {
+            metrics.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        }
+        resolve_series(&conn, &mut metrics)?;
+        Ok(metrics)
+    }
+
+    fn read_tail<'a>(&'a self, selector: &Selector, range: &TimeRange, count: usize) -> Result<Vec<Metric<'a>>> {
+        let filter = SeriesFilter::new(selector);
+        let (start_clause, stop_clause) = range_clauses(range);
+        let before_end = match prefix_end(&selector.prefix) {
+            Some(_) => "AND m.name < :prefix_end",
+            None => "",
         };
+        // hop from one series under the prefix to the next along the primary key rather than
+        // scanning every row, then take the last few points of each matching series from the
+        // same index
+        let mut query = format!("
+            WITH RECURSIVE names(name) AS (
+                SELECT MIN(m.name) FROM Metrics m WHERE m.name >= :prefix {before_end}
+                UNION ALL
+                SELECT (SELECT MIN(m.name) FROM Metrics m WHERE m.name > names.name {before_end})
+                FROM names
+                WHERE names.name IS NOT NULL
+            ),
+            matching(name) AS (
+                SELECT t1.name
+                FROM names t1
+                WHERE t1.name IS NOT NULL
+                    AND {clause}
+            )
+            SELECT m.name,
+                m.time,
+                m.value_type,
+                m.dvalue,
+                m.tvalue
+            FROM matching s
+            JOIN Metrics m ON m.rowid IN (
+                SELECT t1.rowid
+                FROM Metrics t1
+                WHERE t1.name = s.name
+        ", clause = filter.clause, before_end = before_end);
+        let mut params = filter.params();
+        let start_nanos;
+        let stop_nanos;
+        if let Some(start) = &range.start {
+            query += start_clause;
+            start_nanos = bound_nanos(start);
+            params.push((":start", &start_nanos));
+        }
+        if let Some(stop) = &range.stop {
+            query += stop_clause;
+            stop_nanos = bound_nanos(stop);
+            params.push((":stop", &stop_nanos));
+        }
         query += "
-            ORDER BY t1.name, t1.time
-            LIMIT :limit
+                ORDER BY t1.time DESC, t1.sample DESC
+                LIMIT :count
+            )
+            ORDER BY m.name, m.time DESC, m.sample DESC
         ";
-        let limit = limit as u32;
-        params.push((":limit", &limit));
+        let count = count as u32;
+        params.push((":count", &count));
         let conn = self.conn.lock()?;
         let mut stmt = conn.prepare(&query)?;
         let mut rows = stmt.query_named(params.as_slice())?;
         let mut metrics = vec!();
         while let Some(row) = rows.next()? {
-            let date_time:String = row.get(1)?;
-            let date_time: DateTime<Utc> = DateTime::parse_from_rfc3339(&date_time)?.with_timezone(&Utc);
-            let typ: String = row.get(2)?;
-            let value = if typ == "double"{
-                MetricValue::Double(row.get(3)?)
-            } else {
-                MetricValue::String(Cow::Owned(row.get(4)?))
-            };
+            let value = read_value(row, 2)?;
             metrics.push(Metric{
                 name: Cow::Owned(row.get(0)?),
-                when: Cow::Owned(date_time),
+                labels: Labels::new(),
+                when: Cow::Owned(from_nanos(row.get(1)?)),
                 value,
             });
         }
+        resolve_series(&conn, &mut metrics)?;
         Ok(metrics)
     }
 
-    fn list_metrics<'a>(&'a self, prefix: &str) -> Result<Vec<(String, DateTime<Utc>)>> {
+    fn roll_up(&self, now: &DateTime<Utc>) -> Result<()> {
+        // each rollup is built from the one before it, so it can only go as far as that one has
+        let mut source_until = now.timestamp();
+        for (i, (table, resolution)) in ROLLUP_TABLES.iter().enumerate() {
+            let source = if i == 0 { None } else { Some(ROLLUP_TABLES[i - 1].0) };
+            let coarser = ROLLUP_TABLES.get(i + 1).map(|(_, resolution)| *resolution);
+            let until = floor_to(source_until, *resolution);
+            source_until = until;
+            // buckets written to since they were rolled up are rolled up again first, which
+            // leaves the bucket of the next resolution that takes them in to be rolled up again
+            let (rolled_until, dirty) = {
+                let conn = self.conn.lock()?;
+                let mut stmt = conn.prepare("
+                    SELECT t1.bucket
+                    FROM RollupDirty t1
+                    WHERE t1.resolution = ?1
+                    ORDER BY t1.bucket
+                ")?;
+                let dirty = stmt.query_map(params![resolution], |row| row.get(0))?
+                    .collect::<rusqlite::Result<Vec<i64>>>()?;
+                (rolled_until(&conn, *resolution)?, dirty)
+            };
+            for bucket in dirty {
+                let mut conn = self.conn.lock()?;
+                let tx = conn.transaction()?;
+                // a bucket that has not been rolled up yet will be in its turn
+                if rolled_until.is_some_and(|rolled_until| bucket < rolled_until) {
+                    tx.execute(&format!("DELETE FROM {} WHERE bucket = ?1", table), params![bucket])?;
+                    roll_up_buckets(&tx, table, *resolution, source, bucket, bucket + resolution)?;
+                    if let Some(coarser) = coarser {
+                        tx.execute("
+                            INSERT OR IGNORE INTO RollupDirty (resolution, bucket) VALUES (?1, ?2)
+                        ", params![coarser, floor_to(bucket, coarser)])?;
+                    }
+                }
+                tx.execute("
+                    DELETE FROM RollupDirty WHERE resolution = ?1 AND bucket = ?2
+                ", params![resolution, bucket])?;
+                tx.commit()?;
+            }
+            let mut from = {
+                let conn = self.conn.lock()?;
+                let earliest: Option<i64> = match (rolled_until, source) {
+                    (Some(rolled_until), _) => Some(rolled_until),
+                    (None, None) => conn.query_row("
+                        SELECT MIN(t1.time)
+                        FROM Metrics t1
+                    ", NO_PARAMS, |row| row.get::<_, Option<i64>>(0))?
+                        .map(|earliest| earliest.div_euclid(NANOS_PER_SECOND)),
+                    (None, Some(source)) => conn.query_row(&format!("
+                        SELECT MIN(t1.bucket)
+                        FROM {} t1
+                    ", source), NO_PARAMS, |row| row.get(0))?,
+                };
+                match earliest {
+                    Some(earliest) => floor_to(earliest, *resolution),
+                    None => continue,
+                }
+            };
+            while from < until {
+                let to = until.min(from + resolution * ROLLUP_CHUNK_BUCKETS);
+                let mut conn = self.conn.lock()?;
+                let tx = conn.transaction()?;
+                roll_up_buckets(&tx, table, *resolution, source, from, to)?;
+                tx.execute("
+                    INSERT OR REPLACE INTO RollupProgress (resolution, rolled_until) VALUES (?1, ?2)
+                ", params![resolution, to])?;
+                tx.commit()?;
+                from = to;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete_metrics_except(&self, prefix: &str, except: &[String], before: &DateTime<Utc>) -> Result<usize> {
+        let filter = SeriesFilter::new(&prefix.into()).except(except);
+        let before = bound_nanos(before);
+        let deleted = self.delete_chunked("Metrics", &filter, "t1.time < :before", before)?;
+        // rollups go with the metrics they were made from: the buckets that end by the time
+        // are deleted, and one it falls partway through is rolled up again from what is left
+        let epoch = before.div_euclid(NANOS_PER_SECOND);
+        let mut rolled_up = 0;
+        for (table, resolution) in ROLLUP_TABLES.iter() {
+            rolled_up += self.delete_chunked(table, &filter, "t1.bucket <= :before", epoch.saturating_sub(*resolution))?;
+        }
+        if deleted + rolled_up > 0 {
+            let conn = self.conn.lock()?;
+            for (_, resolution) in ROLLUP_TABLES.iter() {
+                let bucket = floor_to(epoch, *resolution);
+                if bucket.saturating_mul(NANOS_PER_SECOND) < before {
+                    conn.execute("
+                        INSERT OR IGNORE INTO RollupDirty (resolution, bucket) VALUES (?1, ?2)
+                    ", params![resolution, bucket])?;
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    fn latest_values(&self, selector: &Selector) -> Result<Vec<Metric<'_>>> {
+        self.read_tail(selector, &TimeRange::default(), 1)
+    }
 
+    fn list_metrics(&self, selector: &Selector) -> Result<Vec<(String, Labels<'static>, DateTime<Utc>)>> {
+        let filter = SeriesFilter::new(selector);
         // prepare the query
-        let query = "
+        let query = format!("
             SELECT t1.name,
                 MAX(t1.time)
             FROM Metrics t1
-            WHERE t1.name LIKE :prefix
+            WHERE {}
             GROUP BY t1.name
-        ".to_string();
+        ", filter.clause);
 
         let conn = self.conn.lock()?;
         let mut stmt = conn.prepare(&query)?;
-        let prefix = &format!("{}%", prefix);
-        let mut rows = stmt.query_named(named_params!(
-            ":prefix": prefix,
-        ))?;
+        let mut rows = stmt.query_named(filter.params().as_slice())?;
+        let mut cache = HashMap::new();
         let mut metrics = vec!();
         while let Some(row) = rows.next()? {
-            let date_time:String = row.get(1)?;
-            let date_time: DateTime<Utc> = DateTime::parse_from_rfc3339(&date_time)?.with_timezone(&Utc);
+            let date_time = from_nanos(row.get(1)?);
+            let (name, labels) = lookup_series(&conn, &mut cache, row.get(0)?)?;
             metrics.push((
-                row.get::<usize, String>(0)?,
+                name,
+                labels,
                 date_time,
             ))
         }
         Ok(metrics)
     }
+
+    fn browse_metrics(&self, prefix: &str) -> Result<Vec<PathNode>> {
+        let end = prefix_end(prefix);
+        let before_end = if end.is_some() { "AND m.name < :prefix_end" } else { "" };
+        // hop from one series key to the next rather than reading every point
+        let query = format!("
+            WITH RECURSIVE keys(key) AS (
+                SELECT MIN(m.name) FROM Metrics m WHERE m.name >= :prefix {before_end}
+                UNION ALL
+                SELECT (SELECT MIN(m.name) FROM Metrics m WHERE m.name > keys.key {before_end})
+                FROM keys
+                WHERE keys.key IS NOT NULL
+            )
+            SELECT keys.key
+            FROM keys
+            WHERE keys.key IS NOT NULL
+        ", before_end = before_end);
+        let mut params: Vec<(&str, &dyn ToSql)> = vec!((":prefix", &prefix));
+        if let Some(end) = &end {
+            params.push((":prefix_end", end));
+        }
+        let conn = self.conn.lock()?;
+        let mut stmt = conn.prepare(&query)?;
+        let mut rows = stmt.query_named(params.as_slice())?;
+        let mut keys = vec!();
+        while let Some(row) = rows.next()? {
+            keys.push(row.get(0)?);
+        }
+        Ok(browse_nodes(prefix, keys))
+    }
+}
+
+/// inserts a batch of metrics within a transaction, returning the failures and the metrics
+/// left out; an atomic batch stops at the first failure, leaving it to the caller to roll the
+/// transaction back
+fn insert_metrics(tx: &Transaction, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies)
+    -> Result<Written> {
+    let mut written = Written::default();
+    let mut statements = InsertStatements{
+        metric: tx.prepare_cached("
+            INSERT INTO Metrics (name, time, value_type, dvalue, tvalue) VALUES (?1, ?2, ?3, ?4, ?5)
+        ")?,
+        first: tx.prepare_cached("
+            INSERT OR IGNORE INTO Metrics (name, time, value_type, dvalue, tvalue) VALUES (?1, ?2, ?3, ?4, ?5)
+        ")?,
+        sample: tx.prepare_cached("
+            INSERT INTO Metrics (name, time, sample, value_type, dvalue, tvalue)
+            SELECT ?1, ?2, COALESCE(MAX(sample) + 1, 0), ?3, ?4, ?5
+            FROM Metrics
+            WHERE name = ?1 AND time = ?2
+        ")?,
+        clear: tx.prepare_cached("
+            DELETE FROM Metrics WHERE name = ?1 AND time = ?2
+        ")?,
+        series: tx.prepare_cached("
+            INSERT OR IGNORE INTO Series (key, name) VALUES (?1, ?2)
+        ")?,
+        label: tx.prepare_cached("
+            INSERT OR IGNORE INTO Labels (series, key, value) VALUES (?1, ?2, ?3)
+        ")?,
+        dirty: tx.prepare_cached("
+            INSERT OR IGNORE INTO RollupDirty (resolution, bucket) VALUES (?1, ?2)
+        ")?,
+        rolled_until: rolled_until(tx, ROLLUP_TABLES[0].1)?,
+    };
+    for (i, metric) in metrics.iter().enumerate() {
+        match statements.insert(metric, conflicts.policy_for(&metric.name)) {
+            Ok(true) => (),
+            Ok(false) => written.discarded.push(i),
+            Err(e) => {
+                written.failures.push((i, e));
+                if mode == WriteMode::Atomic {
+                    break;
+                }
+            },
+        }
+    }
+    Ok(written)
+}
+
+/// the statements insert_metrics uses to record each metric and the series it belongs to
+struct InsertStatements<'conn> {
+    /// inserts the first sample at a time, failing if there already is one
+    metric: CachedStatement<'conn>,
+    /// inserts the first sample at a time, unless there already is one
+    first: CachedStatement<'conn>,
+    /// inserts a sample after any already at a time
+    sample: CachedStatement<'conn>,
+    /// deletes every sample at a time
+    clear: CachedStatement<'conn>,
+    series: CachedStatement<'conn>,
+    label: CachedStatement<'conn>,
+    /// marks a bucket of the finest rollup to be rolled up again
+    dirty: CachedStatement<'conn>,
+    /// how far the finest rollup has been built; points before it land in rolled up buckets
+    rolled_until: Option<i64>,
+}
+
+impl InsertStatements<'_> {
+    /// inserts a metric, returning whether it was stored rather than left out by the policy
+    fn insert(&mut self, metric: &Metric, policy: ConflictPolicy) -> Result<bool> {
+        let key = series_key(&metric.name, &metric.labels);
+        // only labelled series need to be remembered; the rest are known by name alone
+        if !metric.labels.is_empty() && self.series.execute(params![key, metric.name])? > 0 {
+            for (k, v) in &metric.labels {
+                self.label.execute(params![key, k, v])?;
+            }
+        }
+        let (typ, dvalue, tvalue) = match &metric.value {
+            // SQLite stores NaN as NULL; read_value turns it back into NaN
+            MetricValue::Double(d) => ("double", Some(*d).filter(|d| !d.is_nan()), Cow::Borrowed("")),
+            MetricValue::String(s) => ("string", Some(0.0), s.clone()),
+        };
+        let when = to_nanos(&metric.when).ok_or_else(|| DatabaseError::InvalidArgument(
+            format!("time {} cannot be stored", metric.when)))?;
+        let params = params![key, when, typ, dvalue, tvalue];
+        let inserted = match policy {
+            ConflictPolicy::Reject => match self.metric.execute(params) {
+                // only the primary key means the series has a point at the time; any other
+                // constraint is classified along with every other error
+                Err(rusqlite::Error::SqliteFailure(e, _)) if e.extended_code == ffi::SQLITE_CONSTRAINT_PRIMARYKEY =>
+                    return Err(DatabaseError::Duplicate(format!("{} already has a point at {}", key, metric.when))),
+                result => result?,
+            },
+            ConflictPolicy::Overwrite => {
+                self.clear.execute(params![key, when])?;
+                self.metric.execute(params)?
+            },
+            ConflictPolicy::KeepFirst => self.first.execute(params)?,
+            ConflictPolicy::MultiSample => self.sample.execute(params)?,
+        };
+        // keeping the first point inserts nothing when there already is one
+        if inserted == 0 {
+            return Ok(false);
+        }
+        let epoch = when.div_euclid(NANOS_PER_SECOND);
+        if self.rolled_until.is_some_and(|rolled_until| epoch < rolled_until) {
+            let resolution = ROLLUP_TABLES[0].1;
+            self.dirty.execute(params![resolution, floor_to(epoch, resolution)])?;
+        }
+        Ok(true)
+    }
+}
+
+/// SeriesFilter is the SQL condition, and its parameters, matching the series picked by
+/// a selector in a table aliased as t1
+struct SeriesFilter {
+    clause: String,
+    params: Vec<(String, String)>,
+}
+
+impl SeriesFilter {
+    fn new(selector: &Selector) -> Self {
+        // a range rather than LIKE, so the prefix is compared case-sensitively, its
+        // characters are never wildcards, and the primary key can be used
+        let mut clause = "t1.name >= :prefix".to_string();
+        let mut params = vec!((":prefix".to_string(), selector.prefix.clone()));
+        if let Some(end) = prefix_end(&selector.prefix) {
+            clause += " AND t1.name < :prefix_end";
+            params.push((":prefix_end".to_string(), end));
+        }
+        if !selector.names.is_empty() {
+            // unlabelled series are keyed by their name, and labelled ones are listed in Series
+            let names: Vec<_> = (0..selector.names.len()).map(|i| format!(":name{}", i)).collect();
+            clause += &format!("
+                AND (
+                    t1.name IN ({names})
+                    OR t1.name IN (SELECT s.key FROM Series s WHERE s.name IN ({names}))
+                )
+            ", names = names.join(", "));
+            params.extend(names.into_iter().zip(selector.names.iter().cloned()));
+        }
+        if let Some(pattern) = &selector.pattern {
+            // keys with labels carry more than the name, so those are matched through Series
+            clause += "
+                AND (
+                    (instr(t1.name, '{') = 0 AND t1.name REGEXP :name_pattern)
+                    OR t1.name IN (SELECT s.key FROM Series s WHERE s.name REGEXP :name_pattern)
+                )
+            ";
+            params.push((":name_pattern".to_string(), pattern.to_regex()));
+        }
+        for (i, matcher) in selector.matchers.iter().enumerate() {
+            let (negate, op, value) = match matcher.op {
+                MatchOp::Equal => ("", "=", matcher.value.clone()),
+                MatchOp::NotEqual => ("NOT ", "=", matcher.value.clone()),
+                MatchOp::Regex => ("", "REGEXP", format!("^(?:{})$", matcher.value)),
+            };
+            clause += &format!("
+                AND t1.name {}IN (
+                    SELECT l.series
+                    FROM Labels l
+                    WHERE l.key = :label_key{}
+                        AND l.value {} :label_value{}
+                )
+            ", negate, i, op, i);
+            params.push((format!(":label_key{}", i), matcher.key.clone()));
+            params.push((format!(":label_value{}", i), value));
+        }
+        SeriesFilter{clause, params}
+    }
+
+    /// leaves out the series under any of the prefixes
+    fn except(mut self, prefixes: &[String]) -> Self {
+        for (i, prefix) in prefixes.iter().enumerate() {
+            self.clause += &format!(" AND NOT (t1.name >= :except{}", i);
+            self.params.push((format!(":except{}", i), prefix.clone()));
+            if let Some(end) = prefix_end(prefix) {
+                self.clause += &format!(" AND t1.name < :except_end{}", i);
+                self.params.push((format!(":except_end{}", i), end));
+            }
+            self.clause += ")";
+        }
+        self
+    }
+
+    fn params(&self) -> Vec<(&str, &dyn ToSql)> {
+        self.params.iter()
+            .map(|(name, value)| (name.as_str(), value as &dyn ToSql))
+            .collect()
+    }
+}
+
+/// finds the name and labels of the series with the given key
+fn lookup_series(conn: &Connection, cache: &mut HashMap<String, (String, Labels<'static>)>, key: String)
+    -> Result<(String, Labels<'static>)> {
+    if let Some(series) = cache.get(&key) {
+        return Ok(series.clone());
+    }
+    let name: Option<String> = conn.query_row("
+        SELECT t1.name
+        FROM Series t1
+        WHERE t1.key = ?1
+    ", params![key], |row| row.get(0)).optional()?;
+    let series = match name {
+        Some(name) => {
+            let mut stmt = conn.prepare_cached("
+                SELECT t1.key,
+                    t1.value
+                FROM Labels t1
+                WHERE t1.series = ?1
+            ")?;
+            let mut rows = stmt.query(params![key])?;
+            let mut labels = Labels::new();
+            while let Some(row) = rows.next()? {
+                labels.insert(Cow::Owned(row.get(0)?), Cow::Owned(row.get(1)?));
+            }
+            (name, labels)
+        },
+        None => (key.clone(), Labels::new()),
+    };
+    cache.insert(key, series.clone());
+    Ok(series)
+}
+
+/// replaces the series keys read from the database with the name and labels of each series
+fn resolve_series(conn: &Connection, metrics: &mut [Metric]) -> Result<()> {
+    let mut cache = HashMap::new();
+    for metric in metrics {
+        let (name, labels) = lookup_series(conn, &mut cache, metric.name.to_string())?;
+        metric.name = Cow::Owned(name);
+        metric.labels = labels;
+    }
+    Ok(())
+}
+
+/// reads up to `limit` points from each series matching `filter` into `page`, noting
+/// the series that had more points than that
+fn read_series(conn: &Connection, filter: &str, filter_params: &[(&str, &dyn ToSql)],
+    range: &TimeRange, limit: usize, page: &mut ReadResult) -> Result<()> {
+    // prepare the query
+    let mut query = format!("
+        SELECT t1.name,
+            t1.time,
+            t1.value_type,
+            t1.dvalue,
+            t1.tvalue,
+            t1.sample,
+            DENSE_RANK() OVER (PARTITION BY t1.name ORDER BY t1.time) AS row_num
+        FROM Metrics t1
+        WHERE {}
+    ", filter);
+    let mut params = filter_params.to_vec();
+    let (start_clause, stop_clause) = range_clauses(range);
+    let start_nanos;
+    let stop_nanos;
+    if let Some(start) = &range.start {
+        query += start_clause;
+        start_nanos = bound_nanos(start);
+        params.push((":start", &start_nanos));
+    };
+    if let Some(stop) = &range.stop {
+        query += stop_clause;
+        stop_nanos = bound_nanos(stop);
+        params.push((":stop", &stop_nanos));
+    };
+    // grab one extra time per series so we know when a series was cut short; every sample
+    // at a time shares its rank, so they all land on the same page
+    let query = format!("
+        SELECT * FROM ({})
+        WHERE row_num <= :limit
+        ORDER BY name, time, sample
+    ", query);
+    let limit = i64::try_from(limit).unwrap_or(i64::MAX).saturating_add(1);
+    params.push((":limit", &limit));
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query_named(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let row_num: i64 = row.get(6)?;
+        if row_num == limit {
+            if let Some(last) = page.metrics.last() {
+                let noted = page.truncated.last().map(|(truncated, _)| *truncated == name).unwrap_or(false);
+                if last.name == name && !noted {
+                    page.truncated.push((name, *last.when));
+                }
+            }
+            continue;
+        }
+        let date_time = from_nanos(row.get(1)?);
+        let value = read_value(row, 2)?;
+        page.metrics.push(Metric{
+            name: Cow::Owned(name),
+            labels: Labels::new(),
+            when: Cow::Owned(date_time),
+            value,
+        });
+    }
+    Ok(())
+}
+
+/// reads the value held in the value_type, dvalue and tvalue columns starting at `first`
+fn read_value(row: &Row, first: usize) -> Result<MetricValue<'static>> {
+    let typ: String = row.get(first)?;
+    Ok(if typ == "double" {
+        MetricValue::Double(row.get::<_, Option<f64>>(first + 1)?.unwrap_or(f64::NAN))
+    } else {
+        MetricValue::String(Cow::Owned(row.get(first + 2)?))
+    })
+}
+
+/// rounds an epoch down to the start of the bucket of `width` seconds containing it; the
+/// modulo dance keeps times before 1970 in the bucket that starts before them
+fn floor_to(epoch: i64, width: i64) -> i64 {
+    epoch - ((epoch % width) + width) % width
+}
+
+/// the conditions comparing the time column of t1 against the `:start` and `:stop` of a range
+fn range_clauses(range: &TimeRange) -> (&'static str, &'static str) {
+    let start = if range.bounds.includes_start() {
+        " AND t1.time >= :start "
+    } else {
+        " AND t1.time > :start "
+    };
+    let stop = if range.bounds.includes_stop() {
+        " AND t1.time <= :stop "
+    } else {
+        " AND t1.time < :stop "
+    };
+    (start, stop)
+}
+
+/// rolls the points from `from` up to `to`, in seconds since the unix epoch, up into `table`,
+/// from raw metrics or else from the `source` rollup
+fn roll_up_buckets(tx: &Transaction, table: &str, resolution: i64, source: Option<&str>, from: i64, to: i64)
+    -> Result<()> {
+    match source {
+        None => {
+            let from = from.saturating_mul(NANOS_PER_SECOND);
+            let to = to.saturating_mul(NANOS_PER_SECOND);
+            tx.execute_named(&rollup_query(table, &format!("
+                SELECT t1.name,
+                    {} AS epoch,
+                    t1.time AS ordering,
+                    t1.sample,
+                    t1.value_type,
+                    CASE WHEN t1.value_type = 'double' THEN t1.dvalue END AS dmin,
+                    CASE WHEN t1.value_type = 'double' THEN t1.dvalue END AS dmax,
+                    CASE WHEN t1.value_type = 'double' THEN t1.dvalue END AS dsum,
+                    1 AS count,
+                    CASE WHEN t1.value_type = 'double' AND t1.dvalue IS NOT NULL THEN 1 ELSE 0 END AS dcount,
+                    t1.dvalue,
+                    t1.tvalue
+                FROM Metrics t1
+                WHERE t1.time >= :from
+                    AND t1.time < :to
+            ", EPOCH_SECONDS)), named_params!(":resolution": resolution, ":from": from, ":to": to))?;
+        },
+        Some(source) => {
+            tx.execute_named(&rollup_query(table, &format!("
+                SELECT t1.name,
+                    t1.bucket AS epoch,
+                    t1.bucket AS ordering,
+                    0 AS sample,
+                    t1.value_type,
+                    t1.dmin,
+                    t1.dmax,
+                    t1.dsum,
+                    t1.count,
+                    t1.dcount,
+                    t1.dvalue,
+                    t1.tvalue
+                FROM {} t1
+                WHERE t1.bucket >= :from
+                    AND t1.bucket < :to
+            ", source)), named_params!(":resolution": resolution, ":from": from, ":to": to))?;
+        },
+    }
+    Ok(())
+}
+
+/// the time, in seconds since the unix epoch, up to which the rollup of a resolution has been built
+fn rolled_until(conn: &Connection, resolution: i64) -> Result<Option<i64>> {
+    Ok(conn.query_row("
+        SELECT t1.rolled_until
+        FROM RollupProgress t1
+        WHERE t1.resolution = ?1
+    ", params![resolution], |row| row.get(0)).optional()?)
+}
+
+/// builds the statement that rolls `source` up into `table`; the source has to provide
+/// the columns of a rollup table, plus an `epoch` to bucket by and an `ordering` and
+/// `sample` that decide which value is last
+fn rollup_query(table: &str, source: &str) -> String {
+    format!("
+        INSERT OR REPLACE INTO {} (name, bucket, value_type, dmin, dmax, dsum, count, dcount, dvalue, tvalue)
+        SELECT name,
+            rollup_bucket,
+            last_type,
+            MIN(dmin),
+            MAX(dmax),
+            SUM(dsum),
+            SUM(count),
+            SUM(dcount),
+            last_dvalue,
+            last_tvalue
+        FROM (
+            SELECT *,
+                LAST_VALUE(value_type) OVER rollup_window AS last_type,
+                LAST_VALUE(dvalue) OVER rollup_window AS last_dvalue,
+                LAST_VALUE(tvalue) OVER rollup_window AS last_tvalue
+            FROM (
+                SELECT *, epoch - ((epoch % :resolution) + :resolution) % :resolution AS rollup_bucket
+                FROM ({})
+            )
+            WINDOW rollup_window AS (
+                PARTITION BY name, rollup_bucket
+                ORDER BY ordering, sample
+                ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
+            )
+        )
+        GROUP BY name, rollup_bucket
+    ", table, source)
+}
+
+/// finds the coarsest rollup that can answer an aggregation over the range, along with
+/// the time up to which it can be used; the range has to fall on the rollup's bucket
+/// boundaries, and since buckets hold points from their start up to but not including
+/// their end, the range has to include its start and exclude its stop
+fn pick_rollup(conn: &Connection, range: &TimeRange, width: i64,
+    aggregator: Aggregator) -> Result<Option<(&'static str, i64)>> {
+    if let Aggregator::Percentile(_) = aggregator {
+        return Ok(None);
+    }
+    if (range.start.is_some() && !range.bounds.includes_start()) || (range.stop.is_some() && range.bounds.includes_stop()) {
+        return Ok(None);
+    }
+    let (start, stop) = (range.start.as_ref(), range.stop.as_ref());
+    for (table, resolution) in ROLLUP_TABLES.iter().rev() {
+        let aligned = |t: Option<&DateTime<Utc>>| match t {
+            Some(t) => t.timestamp_subsec_nanos() == 0 && floor_to(t.timestamp(), *resolution) == t.timestamp(),
+            None => true,
+        };
+        if width % resolution != 0 || !aligned(start) || !aligned(stop) {
+            continue;
+        }
+        // only whole buckets of the requested width come from the rollup
+        if let Some(rolled_until) = rolled_until(conn, *resolution)? {
+            let cutoff = floor_to(rolled_until, width);
+            match start {
+                Some(start) if start.timestamp() >= cutoff => (),
+                _ => return Ok(Some((table, cutoff))),
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// aggregates the rollup `table` into buckets of `width`, for buckets before `cutoff`
+#[allow(clippy::too_many_arguments)]
+fn aggregate_rollup(conn: &Connection, table: &str, filter: &SeriesFilter, range: &TimeRange,
+    cutoff: i64, width: i64, aggregator: Aggregator, metrics: &mut Vec<Metric>) -> Result<()> {
+    let mut buckets = format!("
+        SELECT *, bucket - ((bucket % :width) + :width) % :width AS width_bucket
+        FROM {} t1
+        WHERE {}
+            AND t1.bucket < :cutoff
+    ", table, filter.clause);
+    let start_epoch;
+    let stop_epoch;
+    let mut params = filter.params();
+    params.push((":width", &width));
+    params.push((":cutoff", &cutoff));
+    if let Some(start) = &range.start {
+        buckets += "
+            AND t1.bucket >= :start
+        ";
+        start_epoch = start.timestamp();
+        params.push((":start", &start_epoch));
+    }
+    if let Some(stop) = &range.stop {
+        buckets += "
+            AND t1.bucket < :stop
+        ";
+        stop_epoch = stop.timestamp();
+        params.push((":stop", &stop_epoch));
+    }
+    // a bucket's value_type is that of its last point, which says nothing of its doubles
+    if !aggregator.supports_strings() {
+        buckets += "
+            AND t1.dcount > 0
+        ";
+    }
+    let columns = match aggregator {
+        Aggregator::Avg => "'double', SUM(dsum) / SUM(dcount), ''",
+        Aggregator::Min => "'double', MIN(dmin), ''",
+        Aggregator::Max => "'double', MAX(dmax), ''",
+        Aggregator::Sum => "'double', SUM(dsum), ''",
+        Aggregator::Count => "'double', CAST(SUM(count) AS REAL), ''",
+        _ => "last_type, last_dvalue, last_tvalue",
+    };
+    let query = format!("
+        SELECT name, width_bucket, {}
+        FROM (
+            SELECT *,
+                LAST_VALUE(value_type) OVER width_window AS last_type,
+                LAST_VALUE(dvalue) OVER width_window AS last_dvalue,
+                LAST_VALUE(tvalue) OVER width_window AS last_tvalue
+            FROM ({})
+            WINDOW width_window AS (
+                PARTITION BY name, width_bucket
+                ORDER BY bucket
+                ROWS BETWEEN UNBOUNDED PRECEDING AND UNBOUNDED FOLLOWING
+            )
+        )
+        GROUP BY name, width_bucket
+        ORDER BY name, width_bucket
+    ", columns, buckets);
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query_named(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let value = read_value(row, 2)?;
+        metrics.push(Metric{
+            name: Cow::Owned(row.get(0)?),
+            labels: Labels::new(),
+            when: Cow::Owned(from_seconds(row.get(1)?)?),
+            value,
+        });
+    }
+    Ok(())
+}
+
+/// aggregates raw metrics into buckets of `width`, from `from` onwards if given
+#[allow(clippy::too_many_arguments)]
+fn aggregate_raw(conn: &Connection, filter: &SeriesFilter, range: &TimeRange,
+    from: Option<i64>, width: i64, aggregator: Aggregator, metrics: &mut Vec<Metric>) -> Result<()> {
+    // the innermost query finds every point in range
+    let mut points = format!("
+        SELECT t1.name,
+            t1.time,
+            t1.value_type,
+            t1.dvalue,
+            t1.tvalue,
+            t1.sample,
+            {} AS epoch
+        FROM Metrics t1
+        WHERE {}
+    ", EPOCH_SECONDS, filter.clause);
+    let start_nanos;
+    let stop_nanos;
+    let from_time;
+    let mut params = filter.params();
+    params.push((":width", &width));
+    let (start_clause, stop_clause) = range_clauses(range);
+    if let Some(start) = &range.start {
+        points += start_clause;
+        start_nanos = bound_nanos(start);
+        params.push((":start", &start_nanos));
+    };
+    if let Some(stop) = &range.stop {
+        points += stop_clause;
+        stop_nanos = bound_nanos(stop);
+        params.push((":stop", &stop_nanos));
+    };
+    if let Some(from) = from {
+        points += "
+            AND t1.time >= :from
+        ";
+        from_time = from.saturating_mul(NANOS_PER_SECOND);
+        params.push((":from", &from_time));
+    }
+    // NaNs are stored as NULL, which aggregates would skip but percentiles would not
+    if !aggregator.supports_strings() {
+        points += "
+            AND t1.value_type = 'double'
+            AND t1.dvalue IS NOT NULL
+        ";
+    }
+    // then assigns each of them to a bucket
+    let points = format!("
+        SELECT *, epoch - ((epoch % :width) + :width) % :width AS bucket
+        FROM ({})
+    ", points);
+    let percentile;
+    let query = match aggregator {
+        Aggregator::Last => format!("
+            SELECT name, bucket, value_type, dvalue, tvalue FROM (
+                SELECT *, ROW_NUMBER() OVER (PARTITION BY name, bucket ORDER BY time DESC, sample DESC) AS row_num
+                FROM ({})
+            )
+            WHERE row_num = 1
+            ORDER BY name, bucket
+        ", points),
+        Aggregator::Percentile(p) => {
+            percentile = p;
+            params.push((":percentile", &percentile));
+            format!("
+                SELECT name, bucket, value_type, dvalue, tvalue FROM (
+                    SELECT *,
+                        ROW_NUMBER() OVER (PARTITION BY name, bucket ORDER BY dvalue) AS row_num,
+                        COUNT(*) OVER (PARTITION BY name, bucket) * :percentile / 100.0 AS rank
+                    FROM ({})
+                )
+                WHERE row_num = MAX(1, CAST(rank AS INTEGER) + (rank > CAST(rank AS INTEGER)))
+                ORDER BY name, bucket
+            ", points)
+        },
+        _ => {
+            let function = match aggregator {
+                Aggregator::Avg => "AVG(dvalue)",
+                Aggregator::Min => "MIN(dvalue)",
+                Aggregator::Max => "MAX(dvalue)",
+                Aggregator::Sum => "SUM(dvalue)",
+                _ => "CAST(COUNT(*) AS REAL)",
+            };
+            format!("
+                SELECT name, bucket, 'double', {}, ''
+                FROM ({})
+                GROUP BY name, bucket
+                ORDER BY name, bucket
+            ", function, points)
+        },
+    };
+    let mut stmt = conn.prepare(&query)?;
+    let mut rows = stmt.query_named(params.as_slice())?;
+    while let Some(row) = rows.next()? {
+        let value = read_value(row, 2)?;
+        metrics.push(Metric{
+            name: Cow::Owned(row.get(0)?),
+            labels: Labels::new(),
+            when: Cow::Owned(from_seconds(row.get(1)?)?),
+            value,
+        });
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use chrono::prelude::*;
     use super::*;
+    use crate::dal::Bounds;
 
     fn testdb() -> SqliteDatabase {
         let db = SqliteDatabase::new(":memory:").unwrap();
@@ -179,6 +1151,12 @@ mod tests {
         db
     }
 
+    fn with_testdb(test: fn(&dyn Database)) {
+        test(&testdb())
+    }
+
+    database_suite!(with_testdb);
+
     #[test]
     fn create_database() {
         let db = SqliteDatabase::new(":memory:").unwrap();
@@ -186,69 +1164,228 @@ mod tests {
     }
 
     #[test]
-    fn insert_value() {
-        let db = testdb();
-        let date_time = Utc.ymd(2018, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        db.write_metric(&Metric{
-            name: Cow::Borrowed("myservice.cpu_time"),
-            when: Cow::Owned(date_time),
-            value: MetricValue::Double(23.0),
-        }).unwrap();
+    fn classifies_sqlite_errors() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE Points (id INTEGER PRIMARY KEY, value REAL CHECK (value >= 0), tag TEXT UNIQUE);
+            INSERT INTO Points VALUES (1, 1.0, 'a');").unwrap();
+        let error = |sql| DatabaseError::from(conn.execute(sql, NO_PARAMS).unwrap_err());
+        assert!(matches!(error("INSERT INTO Points VALUES (1, 2.0, 'b')"), DatabaseError::Duplicate(_)));
+        assert!(matches!(error("INSERT INTO Points VALUES (2, -1.0, 'b')"), DatabaseError::ConstraintViolation(_)));
+        assert!(matches!(error("INSERT INTO Points VALUES (2, 2.0, 'a')"), DatabaseError::ConstraintViolation(_)));
+        let missing = conn.query_row("SELECT value FROM Points WHERE id = 2", NO_PARAMS, |row| row.get::<_, f64>(0));
+        assert!(matches!(DatabaseError::from(missing.unwrap_err()), DatabaseError::NotFound(_)));
+
+        let path = std::env::temp_dir().join(format!("oc-metrics-busy-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let holder = Connection::open(&path).unwrap();
+        holder.execute_batch("CREATE TABLE Points (id INTEGER PRIMARY KEY); BEGIN EXCLUSIVE;").unwrap();
+        let waiter = Connection::open(&path).unwrap();
+        waiter.busy_timeout(std::time::Duration::from_secs(0)).unwrap();
+        let busy = DatabaseError::from(waiter.execute("INSERT INTO Points VALUES (1)", NO_PARAMS).unwrap_err());
+        assert!(matches!(busy, DatabaseError::Busy(_)));
+        assert!(busy.is_retryable());
+        drop(holder);
+        let _ = std::fs::remove_file(&path);
     }
 
     #[test]
-    fn load_values() {
+    fn only_primary_key_conflicts_are_duplicates() {
         let db = testdb();
-        let date_time = Utc.ymd(2018, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        let metric = Metric{
-            name: Cow::Borrowed("myservice.cpu_time"),
+        db.conn.lock().unwrap().execute_batch("CREATE TRIGGER NoNegatives BEFORE INSERT ON Metrics
+            WHEN NEW.dvalue < 0 BEGIN SELECT RAISE(ABORT, 'negative value'); END;").unwrap();
+        let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap();
+        let metric = |value| Metric{
+            name: "myservice.cpu_time".into(),
+            labels: Labels::new(),
             when: Cow::Owned(date_time),
-            value: MetricValue::Double(23.0),
+            value: MetricValue::Double(value),
         };
-        db.write_metric(&metric).unwrap();
-        let got_metrics = db.read_metrics("myservice.", None, None, 100).unwrap();
-        assert_eq!(
-            got_metrics,
-            vec!(metric),
-        )
+        let failures = db.write_metrics(&[metric(1.0), metric(2.0), metric(-1.0)], WriteMode::Partial).unwrap();
+        assert!(matches!(failures[0], (1, DatabaseError::Duplicate(_))));
+        assert!(matches!(failures[1], (2, DatabaseError::ConstraintViolation(_))));
     }
 
     #[test]
-    fn load_values_with_timerange() {
+    fn migrate_text_times() {
+        let db = SqliteDatabase::new(":memory:").unwrap();
+        {
+            // set the database up as it was before times were stored as nanoseconds
+            let conn = db.conn.lock().unwrap();
+            conn.execute_batch("CREATE TABLE SchemaMigrations (name TEXT PRIMARY KEY)").unwrap();
+            let mut names: Vec<_> = Migrations::iter().filter(|name| name.as_ref() < "20261018").collect();
+            names.sort();
+            for name in names {
+                let sql = Migrations::get(&name).unwrap();
+                conn.execute_batch(std::str::from_utf8(&sql).unwrap()).unwrap();
+                conn.execute("INSERT INTO SchemaMigrations (name) VALUES (?1)", params![name.as_ref()]).unwrap();
+            }
+            conn.execute_batch("
+                INSERT INTO Metrics (name, time, value_type, dvalue, tvalue) VALUES
+                    ('myservice.cpu_time', '2018-01-26T18:30:09.453829000Z', 'double', 23.0, ''),
+                    ('myservice.cpu_time', '1969-12-31T23:59:59.500000000Z', 'double', 22.0, ''),
+                    ('myservice.state', '2018-01-26T18:30:09Z', 'string', 0.0, 'ok')
+            ").unwrap();
+        }
+        db.setup().unwrap();
+        let got_metrics = db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics;
+        let metric = |name, when, value| Metric{
+            name: Cow::Borrowed(name),
+            labels: Labels::new(),
+            when: Cow::Owned(when),
+            value,
+        };
+        assert_eq!(got_metrics, vec!(
+            metric("myservice.cpu_time", Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap() + chrono::Duration::milliseconds(500), MetricValue::Double(22.0)),
+            metric("myservice.cpu_time", Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829), MetricValue::Double(23.0)),
+            metric("myservice.state", Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap(), MetricValue::String(Cow::Borrowed("ok"))),
+        ));
+    }
+
+    #[test]
+    fn load_rolled_up_values() {
         let db = testdb();
-        let before = Utc.ymd(2018, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        let valid = Utc.ymd(2019, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        let after =  Utc.ymd(2020, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        let mut want_metrics = vec!();
-        for date_time in vec!(before.checked_sub_signed(chrono::Duration::days(1)).unwrap(), valid, after) {
-            let metric = Metric{
+        let day = Utc.with_ymd_and_hms(2018, 1, 26, 0, 0, 0).unwrap();
+        let mut metrics = vec!();
+        for minute in 0..(3 * 24 * 60) {
+            metrics.push(Metric{
                 name: Cow::Borrowed("myservice.cpu_time"),
-                when: Cow::Owned(date_time),
-                value: MetricValue::Double(23.0),
-            };
-            db.write_metric(&metric).unwrap();
-            want_metrics.push(metric);
+                labels: Labels::new(),
+                when: Cow::Owned(day + chrono::Duration::minutes(minute) + chrono::Duration::seconds(30)),
+                value: MetricValue::Double((minute % 7) as f64),
+            });
+        }
+        db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+        let aggregate = |aggregator| db.read_aggregated(&"myservice.".into(), &TimeRange::new(Some(day), None, Bounds::HalfOpen), chrono::Duration::days(1), aggregator)
+            .unwrap();
+        let want: Vec<_> = [Aggregator::Avg, Aggregator::Min, Aggregator::Max, Aggregator::Sum, Aggregator::Count, Aggregator::Last]
+            .iter()
+            .map(|aggregator| aggregate(*aggregator))
+            .collect();
+
+        // roll up the first two days and drop their raw metrics behind the rollups' back;
+        // the third still comes from raw metrics
+        let drop_raw = |before: DateTime<Utc>| db.conn.lock().unwrap()
+            .execute("DELETE FROM Metrics WHERE time < ?1", params![bound_nanos(&before)])
+            .unwrap();
+        db.roll_up(&(day + chrono::Duration::days(2) + chrono::Duration::hours(1))).unwrap();
+        drop_raw(day + chrono::Duration::days(2));
+        let got: Vec<_> = [Aggregator::Avg, Aggregator::Min, Aggregator::Max, Aggregator::Sum, Aggregator::Count, Aggregator::Last]
+            .iter()
+            .map(|aggregator| aggregate(*aggregator))
+            .collect();
+        assert_eq!(got, want);
+        assert_eq!(got[0].len(), 3);
+
+        // rolling up again picks up where the last run left off
+        db.roll_up(&(day + chrono::Duration::days(4))).unwrap();
+        drop_raw(day + chrono::Duration::days(4));
+        assert_eq!(aggregate(Aggregator::Sum), want[3]);
+
+        // deleting metrics takes the rollups made from them along
+        db.delete_metrics("myservice.", &(day + chrono::Duration::days(1))).unwrap();
+        assert_eq!(aggregate(Aggregator::Sum), want[3][1..]);
+        for (table, _) in ROLLUP_TABLES.iter() {
+            let oldest: i64 = db.conn.lock().unwrap()
+                .query_row(&format!("SELECT MIN(bucket) FROM {}", table), NO_PARAMS, |row| row.get(0))
+                .unwrap();
+            assert_eq!(oldest, (day + chrono::Duration::days(1)).timestamp());
         }
-        let got_metrics = db.read_metrics("myservice.", Some(&before), Some(&after), 100).unwrap();
-        assert_eq!(
-            got_metrics,
-            vec!(want_metrics[1].clone()),
-        )
     }
 
     #[test]
-    fn list_values() {
+    fn roll_up_again_after_late_writes_and_deletes() {
         let db = testdb();
-        let date_time = Utc.ymd(2018, 1, 26).and_hms_micro(18, 30, 9, 453_829);
-        db.write_metric(&Metric{
+        let day = Utc.with_ymd_and_hms(2018, 1, 26, 0, 0, 0).unwrap();
+        let at = |minute, second| day + chrono::Duration::minutes(minute) + chrono::Duration::seconds(second);
+        let metric = |when, value| Metric{
             name: Cow::Borrowed("myservice.cpu_time"),
-            when: Cow::Owned(date_time.clone()),
-            value: MetricValue::Double(23.0),
-        }).unwrap();
+            labels: Labels::new(),
+            when: Cow::Owned(when),
+            value: MetricValue::Double(value),
+        };
+        let mut metrics = vec!();
+        for minute in 0..120 {
+            metrics.push(metric(at(minute, 10), 1.0));
+            metrics.push(metric(at(minute, 50), 1.0));
+        }
+        db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+        db.roll_up(&at(120, 0)).unwrap();
+
+        // a new point, an overwritten one and another sample, all in buckets already rolled up
+        db.write_metrics(&[metric(at(10, 30), 5.0)], WriteMode::Atomic).unwrap();
+        let conflicts: ConflictPolicies = "myservice.=overwrite".parse().unwrap();
+        db.write_metrics_with(&[metric(at(20, 10), 4.0)], WriteMode::Atomic, &conflicts).unwrap();
+        let conflicts: ConflictPolicies = "myservice.=multi-sample".parse().unwrap();
+        db.write_metrics_with(&[metric(at(21, 10), 3.0)], WriteMode::Atomic, &conflicts).unwrap();
+        db.roll_up(&at(120, 0)).unwrap();
+        let sums = |width| db.read_aggregated(&"myservice.".into(), &TimeRange::new(Some(day), Some(at(120, 0)), Bounds::HalfOpen),
+            width, Aggregator::Sum)
+            .unwrap()
+            .into_iter()
+            .map(|m| (m.when.into_owned(), m.value))
+            .collect::<HashMap<_, _>>();
+        let minutes = sums(chrono::Duration::minutes(1));
+        assert_eq!(minutes[&at(10, 0)], MetricValue::Double(7.0));
+        assert_eq!(minutes[&at(20, 0)], MetricValue::Double(5.0));
+        assert_eq!(minutes[&at(21, 0)], MetricValue::Double(5.0));
+        assert_eq!(minutes[&at(22, 0)], MetricValue::Double(2.0));
+        let hours = sums(chrono::Duration::hours(1));
+        assert_eq!(hours[&at(0, 0)], MetricValue::Double(131.0));
+        assert_eq!(hours[&at(60, 0)], MetricValue::Double(120.0));
+
+        // a delete that ends partway through a bucket takes the points it deleted out of it
+        db.delete_metrics("myservice.", &at(30, 30)).unwrap();
+        db.roll_up(&at(120, 0)).unwrap();
+        let minutes = sums(chrono::Duration::minutes(1));
+        assert!(!minutes.contains_key(&at(29, 0)));
+        assert_eq!(minutes[&at(30, 0)], MetricValue::Double(1.0));
+        assert_eq!(minutes[&at(31, 0)], MetricValue::Double(2.0));
+        assert_eq!(sums(chrono::Duration::hours(1))[&at(0, 0)], MetricValue::Double(59.0));
+    }
 
-        let matches = db.list_metrics("myservice.cpu_time").unwrap();
-        assert_eq!(matches, vec!(
-            ("myservice.cpu_time".to_string(), date_time),
+    #[test]
+    fn roll_up_mixed_values() {
+        let db = testdb();
+        let day = Utc.with_ymd_and_hms(2018, 1, 26, 0, 0, 0).unwrap();
+        let at = |minute, second| day + chrono::Duration::minutes(minute) + chrono::Duration::seconds(second);
+        let metric = |name, when, value| Metric{
+            name: Cow::Borrowed(name),
+            labels: Labels::new(),
+            when: Cow::Owned(when),
+            value,
+        };
+        // strings and NaNs are counted but take no part in averages, and buckets that end
+        // in a string still hold their doubles
+        let mut metrics = vec!();
+        for minute in 0..120 {
+            metrics.push(metric("myservice.load", at(minute, 10), MetricValue::Double(minute as f64)));
+            metrics.push(metric("myservice.load", at(minute, 20), MetricValue::String(Cow::Borrowed("overloaded"))));
+            metrics.push(metric("myservice.load", at(minute, 30), MetricValue::Double(f64::NAN)));
+            metrics.push(metric("myservice.load", at(minute, 50), MetricValue::Double(1.0)));
+            metrics.push(metric("myservice.state", at(minute, 10), MetricValue::Double(2.0)));
+            metrics.push(metric("myservice.state", at(minute, 50), MetricValue::String(Cow::Borrowed("x"))));
+        }
+        db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+        let aggregate = |width, aggregator| db.read_aggregated(&"myservice.".into(),
+            &TimeRange::new(Some(day), Some(at(120, 0)), Bounds::HalfOpen), width, aggregator)
+            .unwrap();
+        let widths = [chrono::Duration::minutes(1), chrono::Duration::hours(1)];
+        let aggregators = [Aggregator::Avg, Aggregator::Min, Aggregator::Max, Aggregator::Sum, Aggregator::Count];
+        let all = || widths.iter()
+            .flat_map(|width| aggregators.iter().map(move |aggregator| aggregate(*width, *aggregator)))
+            .collect::<Vec<_>>();
+        let want = all();
+        assert_eq!(want[0][3].value, MetricValue::Double(2.0));
+        // the hourly maximums
+        assert_eq!(want[7].iter().map(|m| (&*m.name, &m.value)).collect::<Vec<_>>(), vec!(
+            ("myservice.load", &MetricValue::Double(59.0)),
+            ("myservice.load", &MetricValue::Double(119.0)),
+            ("myservice.state", &MetricValue::Double(2.0)),
+            ("myservice.state", &MetricValue::Double(2.0)),
         ));
+
+        db.roll_up(&at(180, 0)).unwrap();
+        db.conn.lock().unwrap().execute("DELETE FROM Metrics", NO_PARAMS).unwrap();
+        assert_eq!(all(), want);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,682 @@
+//! The tests every Database implementation has to pass. Each backend's test module
+//! runs them against a fresh database with `database_suite!`, given a function that
+//! hands one to each test.
+use std::borrow::Cow;
+
+use chrono::prelude::*;
+
+use super::{
+    Aggregator,
+    Bounds,
+    ConflictPolicies,
+    Database,
+    DatabaseError,
+    LabelMatcher,
+    Labels,
+    MatchOp,
+    Metric,
+    MetricValue,
+    NamePattern,
+    PathNode,
+    Selector,
+    TimeRange,
+    WriteMode,
+};
+
+/// generates a test for each function in this module, running it with
+/// `$with_testdb(test)`, which is expected to call `test` with an empty database; attributes
+/// given before `$with_testdb`, such as `#[ignore]`, are put on every test
+macro_rules! database_suite {
+    (@test [$(#[$attr:meta])*] $with_testdb:path, $test:ident) => {
+        #[test]
+        $(#[$attr])*
+        fn $test() {
+            $with_testdb(crate::dal::suite::$test);
+        }
+    };
+    ($(#[$attr:meta])* $with_testdb:path) => {
+        database_suite!([$(#[$attr])*] $with_testdb;
+            insert_value,
+            write_batch_atomic,
+            write_batch_partial,
+            conflict_policies,
+            batch_ids,
+            load_values,
+            load_values_with_timerange,
+            load_values_with_subsecond_timerange,
+            load_values_before_epoch,
+            load_non_finite_values,
+            load_values_per_series_limit,
+            load_tail_values,
+            load_aggregated_values,
+            load_aggregated_non_finite_values,
+            delete_values,
+            latest_values,
+            prefixes_are_literal_and_case_sensitive,
+            browse_values,
+            list_values,
+            load_values_with_labels
+        );
+    };
+    ($attrs:tt $with_testdb:path; $($test:ident),*) => {
+        $(
+            database_suite!(@test $attrs $with_testdb, $test);
+        )*
+    };
+}
+
+pub(crate) fn insert_value(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    db.write_metric(&Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(23.0),
+    }).unwrap();
+}
+
+pub(crate) fn write_batch_atomic(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let metric = Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(23.0),
+    };
+    let other = Metric{
+        name: Cow::Borrowed("myservice.mem_used"),
+        ..metric.clone()
+    };
+    // the duplicate primary key fails the whole batch
+    let failures = db.write_metrics(&[other, metric.clone(), metric], WriteMode::Atomic).unwrap();
+    assert_eq!(failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec!(2));
+    assert_eq!(db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics, vec!());
+}
+
+pub(crate) fn write_batch_partial(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let metric = Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(23.0),
+    };
+    let other = Metric{
+        name: Cow::Borrowed("myservice.mem_used"),
+        ..metric.clone()
+    };
+    let failures = db.write_metrics(&[metric.clone(), metric.clone(), other.clone()], WriteMode::Partial).unwrap();
+    assert_eq!(failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec!(1));
+    assert_eq!(db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics, vec!(metric, other));
+}
+
+pub(crate) fn conflict_policies(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let metric = |name, value| Metric{
+        name: Cow::Borrowed(name),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(value),
+    };
+    let read = |prefix: &str, limit| db.read_metrics(&prefix.into(), &TimeRange::default(), limit, &[]).unwrap();
+    let conflicts: ConflictPolicies = "myservice.cpu=keep-first,myservice.mem=overwrite,myservice.disk=multi-sample"
+        .parse()
+        .unwrap();
+    let first = [metric("myservice.cpu_time", 1.0), metric("myservice.mem_used", 1.0), metric("myservice.disk_used", 1.0),
+        metric("myservice.net_sent", 1.0)];
+    assert!(db.write_metrics_with(&first, WriteMode::Atomic, &conflicts).unwrap().failures.is_empty());
+    let second = [metric("myservice.cpu_time", 2.0), metric("myservice.mem_used", 2.0), metric("myservice.disk_used", 2.0),
+        metric("myservice.net_sent", 2.0), metric("myservice.mem_used", 3.0), metric("myservice.disk_used", 3.0)];
+    let written = db.write_metrics_with(&second, WriteMode::Partial, &conflicts).unwrap();
+    // only the prefix without a policy of its own rejects the duplicate
+    assert_eq!(written.failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec!(3));
+    assert!(matches!(written.failures[0].1, DatabaseError::Duplicate(_)));
+    // and the point kept over another is told apart from those written
+    assert_eq!(written.discarded, vec!(0));
+    assert_eq!(read("myservice.cpu", 100).metrics, vec!(metric("myservice.cpu_time", 1.0)));
+    let later = Metric{
+        when: Cow::Owned(date_time + chrono::Duration::seconds(1)),
+        ..metric("myservice.cpu_time", 4.0)
+    };
+    let third = [later.clone(), Metric{value: MetricValue::Double(5.0), ..later.clone()}];
+    assert_eq!(db.write_metrics_with(&third, WriteMode::Partial, &conflicts).unwrap().discarded, vec!(1));
+    assert_eq!(read("myservice.cpu", 100).metrics, vec!(metric("myservice.cpu_time", 1.0), later));
+    // within a batch too, the last write wins
+    assert_eq!(read("myservice.mem", 100).metrics, vec!(metric("myservice.mem_used", 3.0)));
+    assert_eq!(read("myservice.net", 100).metrics, vec!(metric("myservice.net_sent", 1.0)));
+    // samples come back in the order they were written, and a page never splits them up
+    let samples = vec!(metric("myservice.disk_used", 1.0), metric("myservice.disk_used", 2.0), metric("myservice.disk_used", 3.0));
+    let page = read("myservice.disk", 1);
+    assert_eq!(page.metrics, samples);
+    assert_eq!(page.truncated, vec!());
+    assert_eq!(db.latest_values(&"myservice.disk".into()).unwrap(), vec!(samples[2].clone()));
+    assert_eq!(db.delete_metrics("myservice.disk", &(date_time + chrono::Duration::seconds(1))).unwrap(), 3);
+}
+
+pub(crate) fn batch_ids(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let metric = |name, seconds| Metric{
+        name: Cow::Borrowed(name),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time + chrono::Duration::seconds(seconds)),
+        value: MetricValue::Double(23.0),
+    };
+    let read = || db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics;
+    let conflicts = ConflictPolicies::default();
+    let hour = chrono::Duration::hours(1);
+    let indexes = |failures: &[(usize, DatabaseError)]| failures.iter().map(|(i, _)| *i).collect::<Vec<_>>();
+
+    let batch = [metric("myservice.cpu_time", 0), metric("myservice.cpu_time", 0)];
+    let written = db.write_batch("agent-1", hour, &batch, WriteMode::Partial, &conflicts).unwrap();
+    assert!(!written.replayed);
+    assert_eq!(indexes(&written.failures), vec!(1));
+    // a retry gets the first result back, and writes nothing
+    let retried = db.write_batch("agent-1", hour, &[metric("myservice.cpu_time", 1)], WriteMode::Partial, &conflicts).unwrap();
+    assert!(retried.replayed);
+    assert_eq!(indexes(&retried.failures), vec!(1));
+    assert!(matches!(retried.failures[0].1, DatabaseError::Duplicate(_)));
+    assert_eq!(read(), vec!(metric("myservice.cpu_time", 0)));
+
+    // an atomic batch that fails is not remembered
+    let batch = [metric("myservice.mem_used", 0), metric("myservice.cpu_time", 0)];
+    let written = db.write_batch("agent-2", hour, &batch, WriteMode::Atomic, &conflicts).unwrap();
+    assert_eq!(indexes(&written.failures), vec!(1));
+    let retried = db.write_batch("agent-2", hour, &batch[..1], WriteMode::Atomic, &conflicts).unwrap();
+    assert!(!retried.replayed);
+    assert!(retried.failures.is_empty());
+
+    // nor is a batch once its time is up
+    let ttl = chrono::Duration::milliseconds(1);
+    db.write_batch("agent-3", ttl, &[metric("myservice.disk_used", 0)], WriteMode::Atomic, &conflicts).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    let written = db.write_batch("agent-3", ttl, &[metric("myservice.disk_used", 1)], WriteMode::Atomic, &conflicts).unwrap();
+    assert!(!written.replayed);
+    assert_eq!(read().len(), 4);
+}
+
+pub(crate) fn load_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let metric = Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(23.0),
+    };
+    db.write_metric(&metric).unwrap();
+    let got_metrics = db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics;
+    assert_eq!(
+        got_metrics,
+        vec!(metric),
+    )
+}
+
+pub(crate) fn load_values_with_timerange(db: &dyn Database) {
+    let before = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let valid = Utc.with_ymd_and_hms(2019, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let after =  Utc.with_ymd_and_hms(2020, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut want_metrics = vec!();
+    for date_time in [before.checked_sub_signed(chrono::Duration::days(1)).unwrap(), valid, after] {
+        let metric = Metric{
+            name: Cow::Borrowed("myservice.cpu_time"),
+            labels: Labels::new(),
+            when: Cow::Owned(date_time),
+            value: MetricValue::Double(23.0),
+        };
+        db.write_metric(&metric).unwrap();
+        want_metrics.push(metric);
+    }
+    let got_metrics = db.read_metrics(&"myservice.".into(), &TimeRange::new(Some(before), Some(after), Bounds::HalfOpen), 100, &[]).unwrap().metrics;
+    assert_eq!(
+        got_metrics,
+        vec!(want_metrics[1].clone()),
+    )
+}
+
+pub(crate) fn load_values_with_subsecond_timerange(db: &dyn Database) {
+    let second = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap();
+    let mut metrics = vec!();
+    for offset in &[0, 1, 999_999_999, 1_000_000_000] {
+        let metric = Metric{
+            name: Cow::Borrowed("myservice.cpu_time"),
+            labels: Labels::new(),
+            when: Cow::Owned(second + chrono::Duration::nanoseconds(*offset)),
+            value: MetricValue::Double(23.0),
+        };
+        db.write_metric(&metric).unwrap();
+        metrics.push(metric);
+    }
+    let stop = second + chrono::Duration::seconds(1);
+    let read = |start, stop, bounds| db.read_metrics(&"myservice.".into(), &TimeRange::new(start, stop, bounds), 100, &[])
+        .unwrap()
+        .metrics;
+    assert_eq!(read(Some(second), Some(stop), Bounds::HalfOpen), metrics[..3].to_vec());
+    assert_eq!(read(Some(second), Some(stop), Bounds::Inclusive), metrics);
+    assert_eq!(read(Some(second), Some(stop), Bounds::Exclusive), metrics[1..3].to_vec());
+    assert_eq!(read(Some(second), Some(second), Bounds::Inclusive), metrics[..1].to_vec());
+    assert_eq!(read(Some(second), Some(second), Bounds::HalfOpen), vec!());
+    let start = second + chrono::Duration::nanoseconds(1);
+    assert_eq!(read(Some(start), None, Bounds::HalfOpen), metrics[1..].to_vec());
+    assert_eq!(read(Some(start), None, Bounds::Exclusive), metrics[2..].to_vec());
+    assert_eq!(read(None, Some(start), Bounds::Inclusive), metrics[..2].to_vec());
+}
+
+pub(crate) fn load_values_before_epoch(db: &dyn Database) {
+    let mut metrics = vec!();
+    for when in &[Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap() + chrono::Duration::milliseconds(500), Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap() + chrono::Duration::milliseconds(500)] {
+        let metric = Metric{
+            name: Cow::Borrowed("myservice.cpu_time"),
+            labels: Labels::new(),
+            when: Cow::Owned(*when),
+            value: MetricValue::Double(23.0),
+        };
+        db.write_metric(&metric).unwrap();
+        metrics.push(metric);
+    }
+    let start = Utc.with_ymd_and_hms(1969, 12, 31, 23, 59, 59).unwrap();
+    let stop = Utc.with_ymd_and_hms(1970, 1, 1, 0, 0, 0).unwrap();
+    let got_metrics = db.read_metrics(&"myservice.".into(), &TimeRange::new(Some(start), Some(stop), Bounds::HalfOpen), 100, &[]).unwrap().metrics;
+    assert_eq!(got_metrics, metrics[..1].to_vec());
+    let got_metrics = db.read_aggregated(&"myservice.".into(), &TimeRange::new(Some(start), None, Bounds::HalfOpen), chrono::Duration::seconds(1), Aggregator::Count).unwrap();
+    assert_eq!(got_metrics.iter().map(|m| *m.when).collect::<Vec<_>>(), vec!(start, stop));
+}
+
+pub(crate) fn load_non_finite_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let values = [f64::NAN, f64::INFINITY, f64::NEG_INFINITY];
+    let metrics: Vec<_> = values.iter().enumerate().map(|(i, value)| Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time + chrono::Duration::seconds(i as i64)),
+        value: MetricValue::Double(*value),
+    }).collect();
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    // NaN never equals itself, so compare the bits
+    let bits = |metrics: &[Metric]| -> Vec<_> {
+        metrics.iter().map(|m| match m.value {
+            MetricValue::Double(v) => (*m.when, v.to_bits()),
+            MetricValue::String(_) => panic!("{:?} should be a double", m),
+        }).collect()
+    };
+    let page = db.read_metrics(&"myservice.".into(), &TimeRange::default(), 100, &[]).unwrap();
+    assert_eq!(bits(&page.metrics), bits(&metrics));
+    assert_eq!(bits(&db.read_tail(&"myservice.".into(), &TimeRange::default(), 3).unwrap()), bits(&metrics).into_iter().rev().collect::<Vec<_>>());
+
+    // a NaN point is still the latest value of its series
+    let later = Metric{
+        when: Cow::Owned(date_time + chrono::Duration::seconds(3)),
+        value: MetricValue::Double(f64::NAN),
+        ..metrics[0].clone()
+    };
+    db.write_metric(&later).unwrap();
+    assert_eq!(bits(&db.latest_values(&"myservice.".into()).unwrap()), bits(&[later]));
+}
+
+pub(crate) fn load_values_per_series_limit(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for name in &["myservice.cpu_time", "myservice.mem_used"] {
+        for i in 0..3 {
+            metrics.push(Metric{
+                name: Cow::Borrowed(name),
+                labels: Labels::new(),
+                when: Cow::Owned(date_time + chrono::Duration::seconds(i)),
+                value: MetricValue::Double(i as f64),
+            });
+        }
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+
+    // every series gets its own two points, and says it had more
+    let page = db.read_metrics(&"myservice.".into(), &TimeRange::default(), 2, &[]).unwrap();
+    assert_eq!(page.metrics, vec!(
+        metrics[0].clone(), metrics[1].clone(),
+        metrics[3].clone(), metrics[4].clone(),
+    ));
+    assert_eq!(page.truncated, vec!(
+        ("myservice.cpu_time".to_string(), *metrics[1].when),
+        ("myservice.mem_used".to_string(), *metrics[4].when),
+    ));
+
+    // resuming picks up only where each series left off
+    let truncated = page.truncated;
+    let page = db.read_metrics(&"myservice.".into(), &TimeRange::default(), 2, &truncated).unwrap();
+    assert_eq!(page.metrics, vec!(metrics[2].clone(), metrics[5].clone()));
+    assert_eq!(page.truncated, vec!());
+
+    // but only in series the selector picks, whatever the cursors name
+    let page = db.read_metrics(&"myservice.cpu".into(), &TimeRange::default(), 2, &truncated).unwrap();
+    assert_eq!(page.metrics, vec!(metrics[2].clone()));
+    let labelled = Selector{
+        matchers: vec!(LabelMatcher{key: "host".into(), op: MatchOp::Equal, value: "aura".into()}),
+        ..Selector::from("myservice.")
+    };
+    assert_eq!(db.read_metrics(&labelled, &TimeRange::default(), 2, &truncated).unwrap().metrics, vec!());
+
+    // the largest limits leave nothing out
+    for limit in [u32::MAX as usize, usize::MAX] {
+        let page = db.read_metrics(&"myservice.".into(), &TimeRange::default(), limit, &[]).unwrap();
+        assert_eq!(page.metrics, metrics);
+        assert_eq!(page.truncated, vec!());
+    }
+}
+
+pub(crate) fn load_tail_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for name in &["myservice.cpu_time", "myservice.mem_used", "other.cpu_time"] {
+        for i in 0..5 {
+            metrics.push(Metric{
+                name: Cow::Borrowed(*name),
+                labels: Labels::new(),
+                when: Cow::Owned(date_time + chrono::Duration::seconds(i)),
+                value: MetricValue::Double(i as f64),
+            });
+        }
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let got_metrics = db.read_tail(&"myservice.".into(), &TimeRange::default(), 2).unwrap();
+    assert_eq!(got_metrics, vec!(
+        metrics[4].clone(),
+        metrics[3].clone(),
+        metrics[9].clone(),
+        metrics[8].clone(),
+    ));
+    let stop = date_time + chrono::Duration::seconds(3);
+    let got_metrics = db.read_tail(&"other.".into(), &TimeRange::new(None, Some(stop), Bounds::HalfOpen), 10).unwrap();
+    assert_eq!(got_metrics, vec!(
+        metrics[12].clone(),
+        metrics[11].clone(),
+        metrics[10].clone(),
+    ));
+    // series outside the prefix are never visited, whichever side of it they sort on
+    assert_eq!(db.read_tail(&"n.".into(), &TimeRange::default(), 1).unwrap(), vec!());
+    assert_eq!(db.read_tail(&"".into(), &TimeRange::default(), 1).unwrap().len(), 3);
+}
+
+pub(crate) fn load_aggregated_values(db: &dyn Database) {
+    let bucket = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap();
+    let mut metrics = vec!();
+    for (offset, value) in &[(0, 1.0), (10, 4.0), (20, 2.0), (30, 3.0), (70, 5.0)] {
+        metrics.push(Metric{
+            name: Cow::Borrowed("myservice.cpu_time"),
+            labels: Labels::new(),
+            when: Cow::Owned(bucket + chrono::Duration::seconds(*offset)),
+            value: MetricValue::Double(*value),
+        });
+    }
+    metrics.push(Metric{
+        name: Cow::Borrowed("myservice.state"),
+        labels: Labels::new(),
+        when: Cow::Owned(bucket),
+        value: MetricValue::String(Cow::Borrowed("ok")),
+    });
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let next_bucket = bucket + chrono::Duration::minutes(1);
+    let aggregate = |aggregator| db.read_aggregated(&"myservice.".into(), &TimeRange::default(), chrono::Duration::minutes(1), aggregator)
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.name.into_owned(), m.when.into_owned(), m.value))
+        .collect::<Vec<_>>();
+    let double = |when, value| ("myservice.cpu_time".to_string(), when, MetricValue::Double(value));
+
+    assert_eq!(aggregate(Aggregator::Avg), vec!(double(bucket, 2.5), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Min), vec!(double(bucket, 1.0), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Max), vec!(double(bucket, 4.0), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Sum), vec!(double(bucket, 10.0), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Percentile(50.0)), vec!(double(bucket, 2.0), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Percentile(100.0)), vec!(double(bucket, 4.0), double(next_bucket, 5.0)));
+    assert_eq!(aggregate(Aggregator::Count), vec!(
+        double(bucket, 4.0),
+        double(next_bucket, 1.0),
+        ("myservice.state".to_string(), bucket, MetricValue::Double(1.0)),
+    ));
+    assert_eq!(aggregate(Aggregator::Last), vec!(
+        double(bucket, 3.0),
+        double(next_bucket, 5.0),
+        ("myservice.state".to_string(), bucket, MetricValue::String(Cow::Borrowed("ok"))),
+    ));
+}
+
+pub(crate) fn load_aggregated_non_finite_values(db: &dyn Database) {
+    let bucket = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap();
+    let next_bucket = bucket + chrono::Duration::minutes(1);
+    let mut metrics = vec!();
+    for (offset, value) in &[(0, 2.0), (10, f64::NAN), (20, 4.0), (70, f64::NAN)] {
+        metrics.push(Metric{
+            name: Cow::Borrowed("myservice.cpu_time"),
+            labels: Labels::new(),
+            when: Cow::Owned(bucket + chrono::Duration::seconds(*offset)),
+            value: MetricValue::Double(*value),
+        });
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let aggregate = |aggregator| db.read_aggregated(&"myservice.".into(), &TimeRange::default(), chrono::Duration::minutes(1), aggregator)
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.when.into_owned(), m.value))
+        .collect::<Vec<_>>();
+
+    // NaN takes no part in aggregating values, so a bucket of nothing else has none
+    assert_eq!(aggregate(Aggregator::Avg), vec!((bucket, MetricValue::Double(3.0))));
+    assert_eq!(aggregate(Aggregator::Min), vec!((bucket, MetricValue::Double(2.0))));
+    assert_eq!(aggregate(Aggregator::Max), vec!((bucket, MetricValue::Double(4.0))));
+    assert_eq!(aggregate(Aggregator::Sum), vec!((bucket, MetricValue::Double(6.0))));
+    assert_eq!(aggregate(Aggregator::Percentile(50.0)), vec!((bucket, MetricValue::Double(2.0))));
+    assert_eq!(aggregate(Aggregator::Percentile(100.0)), vec!((bucket, MetricValue::Double(4.0))));
+    // but it is still a point
+    assert_eq!(aggregate(Aggregator::Count), vec!(
+        (bucket, MetricValue::Double(3.0)),
+        (next_bucket, MetricValue::Double(1.0)),
+    ));
+    let last = aggregate(Aggregator::Last);
+    assert_eq!(last[0], (bucket, MetricValue::Double(4.0)));
+    assert!(matches!(last[1], (when, MetricValue::Double(v)) if when == next_bucket && v.is_nan()));
+    assert_eq!(last.len(), 2);
+}
+
+pub(crate) fn delete_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for name in &["debug.trace", "hosts.cpu_time"] {
+        for i in 0..1010 {
+            metrics.push(Metric{
+                name: Cow::Borrowed(name),
+                labels: Labels::new(),
+                when: Cow::Owned(date_time + chrono::Duration::seconds(i)),
+                value: MetricValue::Double(i as f64),
+            });
+        }
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+
+    // everything but the last point of the debug series goes, across several chunks
+    let last = date_time + chrono::Duration::seconds(1009);
+    assert_eq!(db.delete_metrics("debug.", &last).unwrap(), 1009);
+    assert_eq!(db.list_metrics(&"".into()).unwrap(), vec!(
+        ("debug.trace".to_string(), Labels::new(), last),
+        ("hosts.cpu_time".to_string(), Labels::new(), last),
+    ));
+    assert_eq!(db.read_metrics(&"debug.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics.len(), 1);
+
+    // a delete can leave the prefixes under its own alone
+    let after = last + chrono::Duration::seconds(1);
+    assert_eq!(db.delete_metrics_except("", &["debug.".to_string()], &after).unwrap(), 1010);
+    assert_eq!(db.list_metrics(&"".into()).unwrap(), vec!(("debug.trace".to_string(), Labels::new(), last)));
+}
+
+pub(crate) fn latest_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for (i, name) in ["myservice.cpu_time", "myservice.cpu_time", "myservice.state", "other.cpu_time"].iter().enumerate() {
+        metrics.push(Metric{
+            name: Cow::Borrowed(name),
+            labels: Labels::new(),
+            when: Cow::Owned(date_time + chrono::Duration::seconds(i as i64)),
+            value: MetricValue::Double(i as f64),
+        });
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    assert_eq!(db.latest_values(&"myservice.".into()).unwrap(), vec!(metrics[1].clone(), metrics[2].clone()));
+    let names = Selector{
+        names: vec!("myservice.state".into(), "other.cpu_time".into(), "myservice".into()),
+        ..Default::default()
+    };
+    assert_eq!(db.latest_values(&names).unwrap(), vec!(metrics[2].clone(), metrics[3].clone()));
+}
+
+pub(crate) fn prefixes_are_literal_and_case_sensitive(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for name in &["disk_io.reads", "diskXio.reads", "Hosts.cpu_load", "hosts.cpu_load", "100%.full", "100x.full"] {
+        metrics.push(Metric{
+            name: Cow::Borrowed(*name),
+            labels: Labels::new(),
+            when: Cow::Owned(date_time),
+            value: MetricValue::Double(1.0),
+        });
+    }
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let names = |prefix: &str| db.list_metrics(&prefix.into()).unwrap().into_iter().map(|(name, _, _)| name).collect::<Vec<_>>();
+    assert_eq!(names("disk_io."), vec!("disk_io.reads"));
+    assert_eq!(names("hosts."), vec!("hosts.cpu_load"));
+    assert_eq!(names("100%"), vec!("100%.full"));
+    assert_eq!(names("").len(), metrics.len());
+    assert_eq!(db.delete_metrics("disk_io.", &(date_time + chrono::Duration::seconds(1))).unwrap(), 1);
+}
+
+pub(crate) fn browse_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for name in &["hosts.aura", "hosts.aura.cpu_load", "hosts.aura.mem_used", "hosts.aura-2.cpu_load",
+                  "hosts.borealis.cpu_load", "hosts_old.aura", "debug.trace"] {
+        for i in 0..3 {
+            metrics.push(Metric{
+                name: Cow::Borrowed(*name),
+                labels: Labels::new(),
+                when: Cow::Owned(date_time + chrono::Duration::seconds(i)),
+                value: MetricValue::Double(1.0),
+            });
+        }
+    }
+    let mut labels = Labels::new();
+    labels.insert(Cow::Borrowed("region"), Cow::Borrowed("eu"));
+    metrics.push(Metric{
+        name: Cow::Borrowed("hosts.aura.cpu_load"),
+        labels,
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(1.0),
+    });
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let node = |component: &str, leaf, branch, series| PathNode{
+        component: component.to_string(),
+        leaf,
+        branch,
+        series,
+    };
+    assert_eq!(db.browse_metrics("hosts.").unwrap(), vec!(
+        node("aura", true, true, 4),
+        node("aura-2", false, true, 1),
+        node("borealis", false, true, 1),
+    ));
+    assert_eq!(db.browse_metrics("hosts.aura.").unwrap(), vec!(
+        node("cpu_load", true, false, 2),
+        node("mem_used", true, false, 1),
+    ));
+    assert_eq!(db.browse_metrics("").unwrap(), vec!(
+        node("debug", false, true, 1),
+        node("hosts", false, true, 6),
+        node("hosts_old", false, true, 1),
+    ));
+    assert_eq!(db.browse_metrics("nothing.").unwrap(), vec!());
+}
+
+pub(crate) fn list_values(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    db.write_metric(&Metric{
+        name: Cow::Borrowed("myservice.cpu_time"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(23.0),
+    }).unwrap();
+
+    let matches = db.list_metrics(&"myservice.cpu_time".into()).unwrap();
+    assert_eq!(matches, vec!(
+        ("myservice.cpu_time".to_string(), Labels::new(), date_time),
+    ));
+}
+
+pub(crate) fn load_values_with_labels(db: &dyn Database) {
+    let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::microseconds(453_829);
+    let mut metrics = vec!();
+    for (host, region) in &[("aura", "eu"), ("borealis", "us"), ("cirrus", "eu")] {
+        let mut labels = Labels::new();
+        labels.insert(Cow::Borrowed("host"), Cow::Borrowed(*host));
+        labels.insert(Cow::Borrowed("region"), Cow::Borrowed(*region));
+        metrics.push(Metric{
+            name: Cow::Borrowed("hosts.cpu_load"),
+            labels,
+            when: Cow::Owned(date_time),
+            value: MetricValue::Double(1.0),
+        });
+    }
+    metrics.push(Metric{
+        name: Cow::Borrowed("hosts.cpu_load"),
+        labels: Labels::new(),
+        when: Cow::Owned(date_time),
+        value: MetricValue::Double(1.0),
+    });
+    db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+    let select = |key: &str, op, value: &str| Selector{
+        prefix: "hosts.".into(),
+        names: vec!(),
+        pattern: None,
+        matchers: vec!(LabelMatcher{key: key.into(), op, value: value.into()}),
+    };
+
+    // prefix queries see every series
+    assert_eq!(db.read_metrics(&"hosts.".into(), &TimeRange::default(), 100, &[]).unwrap().metrics.len(), 4);
+    assert_eq!(
+        db.read_metrics(&select("region", MatchOp::Equal, "eu"), &TimeRange::default(), 100, &[]).unwrap().metrics,
+        vec!(metrics[0].clone(), metrics[2].clone()),
+    );
+    assert_eq!(
+        db.read_metrics(&select("region", MatchOp::NotEqual, "eu"), &TimeRange::default(), 100, &[]).unwrap().metrics,
+        vec!(metrics[3].clone(), metrics[1].clone()),
+    );
+    assert_eq!(
+        db.read_metrics(&select("host", MatchOp::Regex, "a.*|c.*"), &TimeRange::default(), 100, &[]).unwrap().metrics,
+        vec!(metrics[0].clone(), metrics[2].clone()),
+    );
+    assert_eq!(
+        db.read_metrics(&select("host", MatchOp::Regex, "ura"), &TimeRange::default(), 100, &[]).unwrap().metrics,
+        vec!(),
+    );
+    assert_eq!(
+        db.read_aggregated(&select("region", MatchOp::Equal, "us"), &TimeRange::default(), chrono::Duration::days(1), Aggregator::Count)
+            .unwrap()
+            .into_iter()
+            .map(|m| m.labels)
+            .collect::<Vec<_>>(),
+        vec!(metrics[1].labels.clone()),
+    );
+    assert_eq!(db.list_metrics(&select("region", MatchOp::Equal, "eu")).unwrap(), vec!(
+        ("hosts.cpu_load".to_string(), metrics[0].labels.clone(), date_time),
+        ("hosts.cpu_load".to_string(), metrics[2].labels.clone(), date_time),
+    ));
+
+    // so do name patterns
+    let pattern = |pattern| Selector{
+        pattern: Some(pattern),
+        ..Default::default()
+    };
+    assert_eq!(db.list_metrics(&pattern(NamePattern::Glob("hosts.*_load".into()))).unwrap().len(), 4);
+    assert_eq!(db.list_metrics(&pattern(NamePattern::Regex("hosts\\.cpu.*".into()))).unwrap().len(), 4);
+    assert_eq!(db.list_metrics(&pattern(NamePattern::Regex("hosts\\.cpu_load\\{.*".into()))).unwrap().len(), 0);
+
+    // names pick out every series with that name, whatever its labels
+    let names = Selector{
+        names: vec!("hosts.cpu_load".into()),
+        ..Default::default()
+    };
+    assert_eq!(db.latest_values(&names).unwrap().len(), 4);
+}
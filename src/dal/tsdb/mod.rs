@@ -0,0 +1,1200 @@
+//! An append-only storage engine for write-heavy workloads. Each series keeps its
+//! points in chunks of up to CHUNK_POINTS, compressed with Gorilla-style encoding, plus
+//! a head of recent points that have not been chunked yet. Writes go to a write-ahead
+//! log before they reach the head, and a full head is cut into a chunk. Compaction
+//! merges small and overlapping chunks, writes them all out to the chunks file, and
+//! starts a fresh log.
+//!
+//! Chunks are kept in memory, compressed; the files are there so nothing is lost on a
+//! restart. The chunks file begins with the generation of the log that goes with it,
+//! and that log (`wal.<generation>`) and any later ones are replayed on top of it on
+//! opening. Compaction starts the next log before writing the chunks out, so writes
+//! carry on while it does, and a crash before the new chunks file is in place replays
+//! the old log and then the new one. A batch written under an ID is logged along with
+//! it, and compaction starts the new log with the IDs still remembered. Deletes are
+//! logged too, and only leave the files once compaction writes out the points that are
+//! left.
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc,
+        Mutex,
+        PoisonError,
+        RwLock,
+    },
+};
+
+use chrono::prelude::*;
+use log::warn;
+
+use super::{
+    Aggregator,
+    BatchWrite,
+    Database,
+    DatabaseError,
+    Filter,
+    Labels,
+    Metric,
+    MetricValue,
+    NanoRange,
+    PathNode,
+    ReadResult,
+    RecentBatches,
+    Result,
+    Selector,
+    TimeRange,
+    WriteMode,
+    Written,
+    ConflictPolicies,
+    ConflictPolicy,
+    aggregate_buckets,
+    batch_cutoff,
+    bound_nanos,
+    browse_nodes,
+    from_nanos,
+    series_key,
+    series_within,
+    to_nanos,
+};
+
+mod gorilla;
+mod wal;
+
+use gorilla::ChunkEncoder;
+
+impl From<io::Error> for DatabaseError {
+    fn from(e: io::Error) -> Self {
+        DatabaseError::Custom(format!("problem interacting with database files: {}", e))
+    }
+}
+
+/// how many points a chunk holds; a head is cut into a chunk once it has this many
+const CHUNK_POINTS: usize = 120;
+
+const CHUNKS_FILE: &str = "chunks";
+const CHUNKS_TMP_FILE: &str = "chunks.tmp";
+const WAL_PREFIX: &str = "wal.";
+
+/// starts the chunks file, ahead of its generation
+const CHUNKS_MAGIC: &[u8] = b"oc-metrics tsdb chunks 1";
+
+/// the kinds of record in the write-ahead log: a batch of points, a batch of points
+/// written under a batch ID, which is remembered along with the batch's failures, or the
+/// deletion of the points under a prefix from before a time
+const WRITE_RECORD: u8 = 1;
+const BATCH_RECORD: u8 = 2;
+const DELETE_RECORD: u8 = 3;
+
+#[derive(Clone)]
+pub struct TsdbDatabase{
+    store: Arc<RwLock<Store>>,
+    /// held while compacting, so chunks files are swapped in in the order of their generations
+    compacting: Arc<Mutex<()>>,
+}
+
+struct Store {
+    dir: PathBuf,
+    generation: u64,
+    wal: File,
+    /// how much of the log holds whole records
+    wal_len: u64,
+    /// how much of the log compaction carried over from the last one
+    wal_start: u64,
+    series: BTreeMap<String, Series>,
+    batches: RecentBatches,
+}
+
+struct Series {
+    name: String,
+    labels: Labels<'static>,
+    chunks: Vec<Chunk>,
+    /// the samples at each time, in the order they were written
+    head: BTreeMap<i64, Vec<MetricValue<'static>>>,
+}
+
+/// the chunks of a series, copied out of the store to be written without holding it
+struct SeriesChunks {
+    name: String,
+    labels: Labels<'static>,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Clone)]
+struct Chunk {
+    min_time: i64,
+    max_time: i64,
+    count: usize,
+    data: Vec<u8>,
+}
+
+impl TsdbDatabase{
+    /// opens the database kept in a directory, creating it if need be, and replays its
+    /// write-ahead log
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        remove_if_exists(&dir.join(CHUNKS_TMP_FILE))?;
+        let chunks_path = dir.join(CHUNKS_FILE);
+        if !chunks_path.exists() {
+            write_chunks_file(&dir, 0, &[])?;
+        }
+
+        let (records, _) = wal::read(&chunks_path)?;
+        let mut records = records.into_iter();
+        let generation = records.next()
+            .and_then(|header| decode_header(&header))
+            .ok_or_else(|| DatabaseError::Custom(format!("{} is not a chunks file", chunks_path.display())))?;
+        let mut series = BTreeMap::new();
+        for record in records {
+            let (name, labels, chunk) = decode_chunk(&record).ok_or_else(corrupt)?;
+            series.entry(series_key(&name, &labels))
+                .or_insert_with(|| Series::new(name, labels))
+                .chunks
+                .push(chunk);
+        }
+
+        // logs from earlier generations have already been compacted away, and later ones
+        // were started by a compaction that never got its chunks file in place
+        let mut generations = remove_logs_before(&dir, generation)?;
+        generations.sort_unstable();
+        let last = generations.last().copied().unwrap_or(generation);
+        let (mut records, mut wal_len) = (vec!(), 0);
+        for generation in generations {
+            let (logged, len) = wal::read(&dir.join(format!("{}{}", WAL_PREFIX, generation)))?;
+            records.extend(logged);
+            wal_len = len;
+        }
+        // only the last log is appended to, so only its torn tail needs cutting off
+        let wal = OpenOptions::new().create(true).append(true).open(dir.join(format!("{}{}", WAL_PREFIX, last)))?;
+        wal.set_len(wal_len)?;
+        let mut store = Store{
+            dir,
+            generation: last,
+            wal,
+            wal_len,
+            wal_start: 0,
+            series,
+            batches: RecentBatches::default(),
+        };
+        for record in records {
+            if record.first() == Some(&DELETE_RECORD) {
+                let (prefix, except, before) = decode_delete(&record).ok_or_else(corrupt)?;
+                store.delete(&prefix, &except, before)?;
+                continue;
+            }
+            let (batch, points) = decode_write(&record).ok_or_else(corrupt)?;
+            if let Some((id, committed, failures)) = batch {
+                store.batches.insert(id, committed, failures);
+            }
+            for (name, labels, time, policy, value) in points {
+                store.apply(series_key(&name, &labels), &name, &labels, time, policy, value)?;
+            }
+        }
+
+        Ok(TsdbDatabase{
+            store: Arc::new(RwLock::new(store)),
+            compacting: Arc::default(),
+        })
+    }
+
+    /// merges small and overlapping chunks, and writes every point out to a new
+    /// chunks file so the write-ahead log can start over; does nothing if nothing has
+    /// been written since the last time
+    pub fn compact(&self) -> Result<()> {
+        let _compacting = self.compacting.lock().map_err(poisoned)?;
+        let (dir, generation, snapshot) = {
+            let mut store = self.store.write().map_err(poisoned)?;
+            if store.wal_len == store.wal_start {
+                return Ok(());
+            }
+            let snapshot = store.compact()?;
+            (store.dir.clone(), store.generation, snapshot)
+        };
+        // writes carry on into the new log while the chunks are written out
+        write_chunks_file(&dir, generation, &snapshot)?;
+        remove_logs_before(&dir, generation)?;
+        Ok(())
+    }
+}
+
+/// compacts the database every `period` forever; run it with tokio::spawn
+pub async fn run_compaction(db: TsdbDatabase, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        // compacting takes the database lock, so keep it off the async workers
+        match tokio::task::spawn_blocking(move || db.compact()).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Unable to compact metrics: {}", e),
+            Err(e) => warn!("Compaction task failed: {}", e),
+        }
+    }
+}
+
+impl Database for TsdbDatabase {
+    fn setup(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_metrics_with(&self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies)
+        -> Result<Written> {
+        let mut store = self.store.write().map_err(poisoned)?;
+        store.write(metrics, mode, conflicts, None)
+    }
+
+    fn write_batch(&self, id: &str, ttl: chrono::Duration, metrics: &[Metric], mode: WriteMode,
+        conflicts: &ConflictPolicies) -> Result<BatchWrite> {
+        let mut store = self.store.write().map_err(poisoned)?;
+        let now = Utc::now();
+        store.batches.expire(batch_cutoff(&now, ttl));
+        if let Some(failures) = store.batches.get(id) {
+            return Ok(BatchWrite{failures: failures.clone(), discarded: vec!(), replayed: true});
+        }
+        let Written{failures, discarded} = store.write(metrics, mode, conflicts, Some((id, bound_nanos(&now))))?;
+        Ok(BatchWrite{failures, discarded, replayed: false})
+    }
+
+    fn read_metrics<'a>(&'a self, selector: &Selector, range: &TimeRange, limit: usize,
+        resume: &[(String, DateTime<Utc>)]) -> Result<ReadResult<'a>> {
+        let store = self.store.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut page = ReadResult::default();
+        let mut read_series = |key: &str, series: &Series, after: Option<i64>| -> Result<()> {
+            let points = series.points(&range)?
+                .into_iter()
+                .filter(|(time, _)| after.map(|after| *time > after).unwrap_or(true));
+            // the limit counts times, so every sample at a time lands on the same page
+            let (mut times, mut last_time) = (0, None);
+            for (time, value) in points {
+                if last_time != Some(time) {
+                    if times == limit {
+                        if let Some(last) = last_time {
+                            page.truncated.push((key.to_string(), from_nanos(last)));
+                        }
+                        break;
+                    }
+                    times += 1;
+                    last_time = Some(time);
+                }
+                page.metrics.push(series.metric(from_nanos(time), value));
+            }
+            Ok(())
+        };
+        let matching = store.matching(selector)?;
+        if resume.is_empty() {
+            for (key, series) in matching {
+                read_series(key, series, None)?;
+            }
+        } else {
+            // a series is only resumed if the selector still picks it
+            let matching: BTreeMap<_, _> = matching.into_iter().collect();
+            for (key, after) in resume {
+                if let Some(series) = matching.get(key) {
+                    read_series(key, series, Some(bound_nanos(after)))?;
+                }
+            }
+        }
+        Ok(page)
+    }
+
+    fn read_aggregated<'a>(&'a self, selector: &Selector, range: &TimeRange,
+        width: chrono::Duration, aggregator: Aggregator) -> Result<Vec<Metric<'a>>> {
+        let width = width.num_seconds();
+        if width < 1 {
+            return Err(DatabaseError::InvalidArgument("bucket width must be at least one second".into()));
+        }
+        let store = self.store.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut metrics = vec!();
+        for (_, series) in store.matching(selector)? {
+            for (when, value) in aggregate_buckets(series.points(&range)?, width, aggregator)? {
+                metrics.push(series.metric(when, value));
+            }
+        }
+        Ok(metrics)
+    }
+
+    fn read_tail<'a>(&'a self, selector: &Selector, range: &TimeRange, count: usize) -> Result<Vec<Metric<'a>>> {
+        let store = self.store.read().map_err(poisoned)?;
+        let range = NanoRange::new(range);
+        let mut metrics = vec!();
+        for (_, series) in store.matching(selector)? {
+            for (time, value) in series.points(&range)?.into_iter().rev().take(count) {
+                metrics.push(series.metric(from_nanos(time), value));
+            }
+        }
+        Ok(metrics)
+    }
+
+    fn delete_metrics_except(&self, prefix: &str, except: &[String], before: &DateTime<Utc>) -> Result<usize> {
+        let mut store = self.store.write().map_err(poisoned)?;
+        let before = bound_nanos(before);
+        let any = store.deleting(prefix, except).any(|(_, series)| series.first_time().is_some_and(|first| first < before));
+        if !any {
+            return Ok(0);
+        }
+        // the chunks file keeps the deleted points until the next compaction, which the
+        // logged delete is replayed on top of until then
+        store.log_delete(prefix, except, before)?;
+        store.delete(prefix, except, before)
+    }
+
+    fn latest_values(&self, selector: &Selector) -> Result<Vec<Metric<'_>>> {
+        self.read_tail(selector, &TimeRange::default(), 1)
+    }
+
+    fn list_metrics(&self, selector: &Selector) -> Result<Vec<(String, Labels<'static>, DateTime<Utc>)>> {
+        let store = self.store.read().map_err(poisoned)?;
+        Ok(store.matching(selector)?
+            .into_iter()
+            .filter_map(|(_, series)| series.last_time().map(|last| (series.name.clone(), series.labels.clone(), from_nanos(last))))
+            .collect())
+    }
+
+    fn browse_metrics(&self, prefix: &str) -> Result<Vec<PathNode>> {
+        let store = self.store.read().map_err(poisoned)?;
+        Ok(browse_nodes(prefix, store.within(prefix).map(|(key, _)| key.clone())))
+    }
+}
+
+impl Store {
+    /// writes a batch of metrics, returning the failures and the metrics left out; a batch
+    /// with an ID, committed at the given time, is remembered unless it is atomic and fails
+    fn write(&mut self, metrics: &[Metric], mode: WriteMode, conflicts: &ConflictPolicies, batch: Option<(&str, i64)>)
+        -> Result<Written> {
+        let mut failures = vec!();
+        let mut discarded = vec!();
+        let mut accepted = vec!();
+        let mut written = HashSet::new();
+        for (i, metric) in metrics.iter().enumerate() {
+            let key = series_key(&metric.name, &metric.labels);
+            let policy = conflicts.policy_for(&metric.name);
+            let failure = match to_nanos(&metric.when) {
+                None => Some(DatabaseError::InvalidArgument(format!("time {} cannot be stored", metric.when))),
+                Some(time) => {
+                    // only rejecting and keeping the first need to know whether there is a
+                    // point there already
+                    let settled = matches!(policy, ConflictPolicy::Reject | ConflictPolicy::KeepFirst);
+                    let exists = settled && (written.contains(&(key.clone(), time))
+                        || self.series.get(&key).map(|series| series.contains(time)).transpose()?.unwrap_or(false));
+                    if exists && policy == ConflictPolicy::Reject {
+                        Some(DatabaseError::Duplicate(format!("{} already has a point at {}", key, metric.when)))
+                    } else if exists {
+                        discarded.push(i);
+                        None
+                    } else {
+                        written.insert((key.clone(), time));
+                        accepted.push((key, time, policy, metric));
+                        None
+                    }
+                },
+            };
+            if let Some(failure) = failure {
+                failures.push((i, failure));
+                if mode == WriteMode::Atomic {
+                    return Ok(Written{failures, discarded});
+                }
+            }
+        }
+        if accepted.is_empty() && batch.is_none() {
+            return Ok(Written{failures, discarded});
+        }
+        self.log_write(batch, &failures, &accepted)?;
+        if let Some((id, committed)) = batch {
+            self.batches.insert(id.to_string(), committed, failures.clone());
+        }
+        for (key, time, policy, metric) in accepted {
+            self.apply(key, &metric.name, &metric.labels, time, policy, metric.to_owned_metric().value)?;
+        }
+        Ok(Written{failures, discarded})
+    }
+
+    /// the series whose keys start with the prefix, in order
+    fn within<'s>(&'s self, prefix: &'s str) -> impl Iterator<Item=(&'s String, &'s Series)> {
+        series_within(&self.series, prefix)
+    }
+
+    /// the series picked by a selector, in order; the prefix applies to their keys, like
+    /// it does in the other backends
+    fn matching<'s>(&'s self, selector: &'s Selector) -> Result<Vec<(&'s String, &'s Series)>> {
+        let filter = Filter::new(&Selector{prefix: String::new(), ..selector.clone()})
+            .map_err(|e| DatabaseError::InvalidArgument(format!("invalid regular expression: {}", e)))?;
+        Ok(self.within(&selector.prefix)
+            .filter(|(_, series)| filter.matches(&series.name, &series.labels))
+            .collect())
+    }
+
+    /// appends a batch of points to the write-ahead log, along with its ID and failures if
+    /// it has an ID, and waits for it to reach the disk
+    fn log_write(&mut self, batch: Option<(&str, i64)>, failures: &[(usize, DatabaseError)],
+        accepted: &[(String, i64, ConflictPolicy, &Metric)]) -> Result<()> {
+        let mut record = wal::Encoder::default();
+        match batch {
+            Some((id, committed)) => {
+                record.u8(BATCH_RECORD);
+                encode_batch(&mut record, id, committed, failures);
+            },
+            None => record.u8(WRITE_RECORD),
+        }
+        record.u32(accepted.len() as u32);
+        for (_, time, policy, metric) in accepted {
+            encode_series(&mut record, &metric.name, &metric.labels);
+            record.i64(*time);
+            record.u8(encode_policy(*policy));
+            encode_value(&mut record, &metric.value);
+        }
+        self.append(&record.bytes)
+    }
+
+    /// appends a record to the write-ahead log, and waits for it to reach the disk
+    fn append(&mut self, payload: &[u8]) -> Result<()> {
+        let framed = wal::frame(payload);
+        let written = self.wal.write_all(&framed).and_then(|()| self.wal.sync_data());
+        if let Err(e) = written {
+            // a partial record would hide every record appended after it
+            let _ = self.wal.set_len(self.wal_len);
+            return Err(e.into());
+        }
+        self.wal_len += framed.len() as u64;
+        Ok(())
+    }
+
+    /// appends the deletion of points to the write-ahead log, and waits for it to reach the disk
+    fn log_delete(&mut self, prefix: &str, except: &[String], before: i64) -> Result<()> {
+        let mut record = wal::Encoder::default();
+        record.u8(DELETE_RECORD);
+        record.str(prefix);
+        record.i64(before);
+        record.u32(except.len() as u32);
+        for prefix in except {
+            record.str(prefix);
+        }
+        self.append(&record.bytes)
+    }
+
+    /// the series under a prefix but none of the `except` ones
+    fn deleting<'s>(&'s self, prefix: &'s str, except: &'s [String]) -> impl Iterator<Item=(&'s String, &'s Series)> {
+        self.within(prefix).filter(move |(key, _)| !except.iter().any(|prefix| key.starts_with(prefix.as_str())))
+    }
+
+    /// removes the points under a prefix but none of the `except` ones from before a time,
+    /// returning how many went
+    fn delete(&mut self, prefix: &str, except: &[String], before: i64) -> Result<usize> {
+        let keys: Vec<String> = self.deleting(prefix, except).map(|(key, _)| key.clone()).collect();
+        let mut deleted = 0;
+        for key in keys {
+            if let Some(series) = self.series.get_mut(&key) {
+                deleted += series.delete_before(before)?;
+                if series.is_empty() {
+                    self.series.remove(&key);
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// adds a logged point to its series, cutting the head into chunks once it is full
+    fn apply(&mut self, key: String, name: &str, labels: &Labels, time: i64, policy: ConflictPolicy,
+        value: MetricValue<'static>) -> Result<()> {
+        let series = self.series.entry(key).or_insert_with(|| Series::new(
+            name.to_string(),
+            labels.iter().map(|(k, v)| (Cow::Owned(k.to_string()), Cow::Owned(v.to_string()))).collect(),
+        ));
+        series.put(time, policy, value)?;
+        while series.head.len() >= CHUNK_POINTS {
+            // the oldest points are cut, so a long replay can make several chunks
+            let rest = match series.head.keys().nth(CHUNK_POINTS) {
+                Some(time) => {
+                    let time = *time;
+                    series.head.split_off(&time)
+                },
+                None => BTreeMap::new(),
+            };
+            let head = std::mem::replace(&mut series.head, rest);
+            series.chunks.push(Chunk::encode(samples(&head)));
+        }
+        Ok(())
+    }
+
+    /// merges the chunks of every series and starts the log of the next generation, returning
+    /// the chunks to write out for it
+    fn compact(&mut self) -> Result<Vec<SeriesChunks>> {
+        for series in self.series.values_mut() {
+            series.compact()?;
+        }
+        let generation = self.generation + 1;
+        // the remembered batches start off the new log, so it stands on its own once the
+        // chunks file for its generation is in place
+        let mut carried = vec!();
+        for (id, committed, failures) in self.batches.iter() {
+            let mut record = wal::Encoder::default();
+            record.u8(BATCH_RECORD);
+            encode_batch(&mut record, id, committed, failures);
+            record.u32(0);
+            carried.extend(wal::frame(&record.bytes));
+        }
+        let mut wal = OpenOptions::new().create(true).append(true).open(self.dir.join(format!("{}{}", WAL_PREFIX, generation)))?;
+        wal.set_len(0)?;
+        wal.write_all(&carried)?;
+        wal.sync_data()?;
+        self.wal = wal;
+        self.wal_len = carried.len() as u64;
+        self.wal_start = self.wal_len;
+        self.generation = generation;
+        Ok(self.series.values()
+            .map(|series| SeriesChunks{
+                name: series.name.clone(),
+                labels: series.labels.clone(),
+                chunks: series.chunks.clone(),
+            })
+            .collect())
+    }
+}
+
+impl Series {
+    fn new(name: String, labels: Labels<'static>) -> Self {
+        Series{
+            name,
+            labels,
+            chunks: vec!(),
+            head: BTreeMap::new(),
+        }
+    }
+
+    fn metric(&self, when: DateTime<Utc>, value: MetricValue<'static>) -> Metric<'static> {
+        Metric{
+            name: Cow::Owned(self.name.clone()),
+            labels: self.labels.clone(),
+            when: Cow::Owned(when),
+            value,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.is_empty() && self.chunks.is_empty()
+    }
+
+    fn first_time(&self) -> Option<i64> {
+        let head = self.head.keys().next().copied();
+        self.chunks.iter().map(|chunk| chunk.min_time).chain(head).min()
+    }
+
+    fn last_time(&self) -> Option<i64> {
+        let head = self.head.keys().next_back().copied();
+        self.chunks.iter().map(|chunk| chunk.max_time).chain(head).max()
+    }
+
+    /// adds a sample at a time, settling any already there by the policy; rejecting is
+    /// up to the caller
+    fn put(&mut self, time: i64, policy: ConflictPolicy, value: MetricValue<'static>) -> Result<()> {
+        match policy {
+            ConflictPolicy::KeepFirst if self.contains(time)? => return Ok(()),
+            ConflictPolicy::Overwrite => {
+                self.head.remove(&time);
+                for chunk in self.chunks.iter_mut().filter(|chunk| chunk.min_time <= time && time <= chunk.max_time) {
+                    let points = chunk.points()?;
+                    if points.iter().any(|(t, _)| *t == time) {
+                        *chunk = Chunk::encode(points.iter().filter(|(t, _)| *t != time).map(|(t, value)| (*t, value)));
+                    }
+                }
+                self.chunks.retain(|chunk| chunk.count > 0);
+            },
+            _ => (),
+        }
+        self.head.entry(time).or_default().push(value);
+        Ok(())
+    }
+
+    fn contains(&self, time: i64) -> Result<bool> {
+        if self.head.contains_key(&time) {
+            return Ok(true);
+        }
+        for chunk in &self.chunks {
+            if chunk.min_time <= time && time <= chunk.max_time && chunk.points()?.iter().any(|(t, _)| *t == time) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// the points within the range, in time order
+    fn points(&self, range: &NanoRange) -> Result<Vec<(i64, MetricValue<'static>)>> {
+        let mut points = vec!();
+        let mut sorted = true;
+        for chunk in &self.chunks {
+            if range.overlaps(chunk.min_time, chunk.max_time) {
+                sorted &= points.last().map(|(last, _)| *last < chunk.min_time).unwrap_or(true);
+                points.extend(chunk.points()?.into_iter().filter(|(time, _)| range.contains(*time)));
+            }
+        }
+        sorted &= match (points.last(), self.head.keys().next()) {
+            (Some((last, _)), Some(first)) => last < first,
+            _ => true,
+        };
+        points.extend(samples(&self.head)
+            .filter(|(time, _)| range.contains(*time))
+            .map(|(time, value)| (time, value.clone())));
+        // chunks only overlap when points arrived out of order; the sort is stable, so
+        // samples at the same time stay in the order they were written
+        if !sorted {
+            points.sort_by_key(|(time, _)| *time);
+        }
+        Ok(points)
+    }
+
+    /// removes every point from before the given time, returning how many went
+    fn delete_before(&mut self, before: i64) -> Result<usize> {
+        let kept = self.head.split_off(&before);
+        let mut deleted = self.head.values().map(Vec::len).sum();
+        self.head = kept;
+        let mut chunks = std::mem::take(&mut self.chunks).into_iter();
+        while let Some(chunk) = chunks.next() {
+            if chunk.min_time >= before {
+                self.chunks.push(chunk);
+            } else if chunk.max_time < before {
+                deleted += chunk.count;
+            } else {
+                let points = match chunk.points() {
+                    Ok(points) => points,
+                    Err(e) => {
+                        self.chunks.push(chunk);
+                        self.chunks.extend(chunks);
+                        return Err(e);
+                    },
+                };
+                let kept: Vec<_> = points.into_iter().filter(|(time, _)| *time >= before).collect();
+                deleted += chunk.count - kept.len();
+                if !kept.is_empty() {
+                    self.chunks.push(Chunk::encode(kept.iter().map(|(time, value)| (*time, value))));
+                }
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// re-chunks the head along with any chunks that are not full or that overlap the
+    /// ones after them; full chunks that end before every later point are left alone
+    fn compact(&mut self) -> Result<()> {
+        self.chunks.sort_by_key(|chunk| chunk.min_time);
+        let mut later = vec!(self.head.keys().next().copied().unwrap_or(i64::MAX); self.chunks.len() + 1);
+        for i in (0..self.chunks.len()).rev() {
+            later[i] = later[i + 1].min(self.chunks[i].min_time);
+        }
+        let keep = self.chunks.iter()
+            .enumerate()
+            .take_while(|(i, chunk)| chunk.count >= CHUNK_POINTS && chunk.max_time < later[i + 1])
+            .count();
+        // a lone partial chunk at the end has nothing to merge with
+        if self.head.is_empty() && keep + 1 >= self.chunks.len() {
+            return Ok(());
+        }
+        let mut points = vec!();
+        for chunk in &self.chunks[keep..] {
+            points.extend(chunk.points()?);
+        }
+        let head = std::mem::take(&mut self.head);
+        points.extend(head.into_iter().flat_map(|(time, values)| values.into_iter().map(move |value| (time, value))));
+        points.sort_by_key(|(time, _)| *time);
+        self.chunks.truncate(keep);
+        for chunk in points.chunks(CHUNK_POINTS) {
+            self.chunks.push(Chunk::encode(chunk.iter().map(|(time, value)| (*time, value))));
+        }
+        Ok(())
+    }
+}
+
+impl Chunk {
+    fn encode<'a, 'v: 'a, I: Iterator<Item=(i64, &'a MetricValue<'v>)>>(points: I) -> Self {
+        let mut encoder = ChunkEncoder::new();
+        let (mut min_time, mut max_time) = (i64::MAX, i64::MIN);
+        for (time, value) in points {
+            encoder.push(time, value);
+            min_time = min_time.min(time);
+            max_time = max_time.max(time);
+        }
+        Chunk{
+            min_time,
+            max_time,
+            count: encoder.len(),
+            data: encoder.finish(),
+        }
+    }
+
+    fn points(&self) -> Result<Vec<(i64, MetricValue<'static>)>> {
+        gorilla::decode(&self.data, self.count).ok_or_else(corrupt)
+    }
+}
+
+/// the samples in a head, in order
+fn samples<'h>(head: &'h BTreeMap<i64, Vec<MetricValue<'static>>>) -> impl Iterator<Item=(i64, &'h MetricValue<'static>)> {
+    head.iter().flat_map(|(time, values)| values.iter().map(move |value| (*time, value)))
+}
+
+fn poisoned<T>(e: PoisonError<T>) -> DatabaseError {
+    DatabaseError::Custom(format!("lock error: {}", e))
+}
+
+fn corrupt() -> DatabaseError {
+    DatabaseError::Corrupt("the database files are corrupt".into())
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// removes the logs of generations before `generation`, and returns the generations of
+/// the rest
+fn remove_logs_before(dir: &Path, generation: u64) -> Result<Vec<u64>> {
+    let mut kept = vec!();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let logged = match name.strip_prefix(WAL_PREFIX).and_then(|n| n.parse::<u64>().ok()) {
+            Some(logged) => logged,
+            None => continue,
+        };
+        if logged < generation {
+            remove_if_exists(&entry.path())?;
+        } else {
+            kept.push(logged);
+        }
+    }
+    Ok(kept)
+}
+
+/// writes every chunk to a new chunks file for the generation, and swaps it in for the
+/// old one
+fn write_chunks_file(dir: &Path, generation: u64, series: &[SeriesChunks]) -> Result<()> {
+    let tmp = dir.join(CHUNKS_TMP_FILE);
+    let file = File::create(&tmp)?;
+    let mut writer = BufWriter::new(&file);
+    let mut header = wal::Encoder::default();
+    header.bytes(CHUNKS_MAGIC);
+    header.u64(generation);
+    for record in std::iter::once(header.bytes).chain(series.iter().flat_map(|series| series.chunks.iter().map(move |chunk| {
+        let mut record = wal::Encoder::default();
+        encode_series(&mut record, &series.name, &series.labels);
+        encode_chunk(&mut record, chunk);
+        record.bytes
+    }))) {
+        writer.write_all(&wal::frame(&record))?;
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(CHUNKS_FILE))?;
+    // make the rename itself durable
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+fn encode_series(record: &mut wal::Encoder, name: &str, labels: &Labels) {
+    record.str(name);
+    record.u32(labels.len() as u32);
+    for (k, v) in labels {
+        record.str(k);
+        record.str(v);
+    }
+}
+
+fn decode_series(record: &mut wal::Decoder) -> Option<(String, Labels<'static>)> {
+    let name = record.str()?.to_string();
+    let mut labels = Labels::new();
+    for _ in 0..record.u32()? {
+        let k = record.str()?.to_string();
+        let v = record.str()?.to_string();
+        labels.insert(Cow::Owned(k), Cow::Owned(v));
+    }
+    Some((name, labels))
+}
+
+fn encode_value(record: &mut wal::Encoder, value: &MetricValue) {
+    match value {
+        MetricValue::Double(d) => {
+            record.u8(0);
+            record.f64(*d);
+        },
+        MetricValue::String(s) => {
+            record.u8(1);
+            record.str(s);
+        },
+    }
+}
+
+fn decode_value(record: &mut wal::Decoder) -> Option<MetricValue<'static>> {
+    match record.u8()? {
+        0 => Some(MetricValue::Double(record.f64()?)),
+        1 => Some(MetricValue::String(Cow::Owned(record.str()?.to_string()))),
+        _ => None,
+    }
+}
+
+fn encode_chunk(record: &mut wal::Encoder, chunk: &Chunk) {
+    record.i64(chunk.min_time);
+    record.i64(chunk.max_time);
+    record.u32(chunk.count as u32);
+    record.bytes(&chunk.data);
+}
+
+fn encode_batch(record: &mut wal::Encoder, id: &str, committed: i64, failures: &[(usize, DatabaseError)]) {
+    record.str(id);
+    record.i64(committed);
+    record.u32(failures.len() as u32);
+    for (index, e) in failures {
+        let (kind, message) = e.to_stored();
+        record.u32(*index as u32);
+        record.str(kind);
+        record.str(&message);
+    }
+}
+
+fn decode_batch(record: &mut wal::Decoder) -> Option<LoggedBatch> {
+    let id = record.str()?.to_string();
+    let committed = record.i64()?;
+    let mut failures = vec!();
+    for _ in 0..record.u32()? {
+        let index = record.u32()? as usize;
+        let kind = record.str()?;
+        failures.push((index, DatabaseError::from_stored(kind, record.str()?.to_string())));
+    }
+    Some((id, committed, failures))
+}
+
+fn encode_policy(policy: ConflictPolicy) -> u8 {
+    match policy {
+        ConflictPolicy::Reject => 0,
+        ConflictPolicy::Overwrite => 1,
+        ConflictPolicy::KeepFirst => 2,
+        ConflictPolicy::MultiSample => 3,
+    }
+}
+
+fn decode_policy(policy: u8) -> Option<ConflictPolicy> {
+    match policy {
+        0 => Some(ConflictPolicy::Reject),
+        1 => Some(ConflictPolicy::Overwrite),
+        2 => Some(ConflictPolicy::KeepFirst),
+        3 => Some(ConflictPolicy::MultiSample),
+        _ => None,
+    }
+}
+
+fn decode_chunk(record: &[u8]) -> Option<(String, Labels<'static>, Chunk)> {
+    let mut record = wal::Decoder::new(record);
+    let (name, labels) = decode_series(&mut record)?;
+    let chunk = Chunk{
+        min_time: record.i64()?,
+        max_time: record.i64()?,
+        count: record.u32()? as usize,
+        data: record.bytes()?.to_vec(),
+    };
+    Some((name, labels, chunk)).filter(|_| record.is_empty())
+}
+
+fn decode_header(record: &[u8]) -> Option<u64> {
+    let mut record = wal::Decoder::new(record);
+    if record.bytes()? != CHUNKS_MAGIC {
+        return None;
+    }
+    record.u64()
+}
+
+/// a point as it was logged: its name, labels, time, conflict policy and value
+type LoggedPoint = (String, Labels<'static>, i64, ConflictPolicy, MetricValue<'static>);
+
+/// a batch as it was logged: its ID, when it was committed and its failures
+type LoggedBatch = (String, i64, Vec<(usize, DatabaseError)>);
+
+fn decode_delete(record: &[u8]) -> Option<(String, Vec<String>, i64)> {
+    let mut record = wal::Decoder::new(record);
+    if record.u8()? != DELETE_RECORD {
+        return None;
+    }
+    let prefix = record.str()?.to_string();
+    let before = record.i64()?;
+    let mut except = vec!();
+    for _ in 0..record.u32()? {
+        except.push(record.str()?.to_string());
+    }
+    Some((prefix, except, before)).filter(|_| record.is_empty())
+}
+
+fn decode_write(record: &[u8]) -> Option<(Option<LoggedBatch>, Vec<LoggedPoint>)> {
+    let mut record = wal::Decoder::new(record);
+    let batch = match record.u8()? {
+        WRITE_RECORD => None,
+        BATCH_RECORD => Some(decode_batch(&mut record)?),
+        _ => return None,
+    };
+    let count = record.u32()?;
+    let mut points = vec!();
+    for _ in 0..count {
+        let (name, labels) = decode_series(&mut record)?;
+        let time = record.i64()?;
+        let policy = decode_policy(record.u8()?)?;
+        points.push((name, labels, time, policy, decode_value(&mut record)?));
+    }
+    Some((batch, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+    /// TempDir is a fresh directory, removed along with its contents when dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let id = NEXT_DIR.fetch_add(1, Ordering::SeqCst);
+            let dir = std::env::temp_dir().join(format!("oc-metrics-tsdb-{}-{}", std::process::id(), id));
+            let _ = fs::remove_dir_all(&dir);
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn with_testdb(test: fn(&dyn Database)) {
+        let dir = TempDir::new();
+        test(&TsdbDatabase::open(&dir.0).unwrap())
+    }
+
+    database_suite!(with_testdb);
+
+    fn points(count: i64) -> Vec<Metric<'static>> {
+        let date_time = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap();
+        let mut metrics = vec!();
+        for name in &["myservice.cpu_time", "myservice.state"] {
+            for i in 0..count {
+                let value = match *name {
+                    "myservice.state" => MetricValue::String(Cow::Owned(format!("state {}", i % 3))),
+                    _ => MetricValue::Double(i as f64 / 4.0),
+                };
+                metrics.push(Metric{
+                    name: Cow::Borrowed(*name),
+                    labels: Labels::new(),
+                    when: Cow::Owned(date_time + chrono::Duration::milliseconds(i * 1500)),
+                    value,
+                });
+            }
+        }
+        metrics
+    }
+
+    fn read_all(db: &TsdbDatabase) -> Vec<Metric<'_>> {
+        db.read_metrics(&"".into(), &TimeRange::default(), 10_000, &[]).unwrap().metrics
+    }
+
+    fn read_sorted(db: &TsdbDatabase) -> Vec<Metric<'_>> {
+        let mut got = read_all(db);
+        got.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        got
+    }
+
+    fn file_len(dir: &TempDir, name: &str) -> u64 {
+        fs::metadata(dir.0.join(name)).unwrap().len()
+    }
+
+    #[test]
+    fn reopen_replays_the_log() {
+        let dir = TempDir::new();
+        // enough points to cut a few chunks, and leave some in the head
+        let metrics = points(CHUNK_POINTS as i64 * 3 + 7);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+            assert_eq!(db.store.read().unwrap().series["myservice.cpu_time"].chunks.len(), 3);
+        }
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_all(&db), metrics);
+        {
+            let store = db.store.read().unwrap();
+            assert_eq!(store.series["myservice.cpu_time"].chunks.len(), 3);
+            assert_eq!(store.series["myservice.cpu_time"].head.len(), 7);
+        }
+        // points that were chunked are still known after replaying
+        let failures = db.write_metrics(&metrics[..1], WriteMode::Partial).unwrap();
+        assert_eq!(failures.len(), 1);
+    }
+
+    #[test]
+    fn compaction_starts_a_new_log() {
+        let dir = TempDir::new();
+        let metrics = points(CHUNK_POINTS as i64 + 50);
+        let (early, late) = metrics.split_at(metrics.len() / 2);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            // the late points end up in a partial chunk that the early ones overlap
+            db.write_metrics(late, WriteMode::Atomic).unwrap();
+            db.compact().unwrap();
+            db.write_metrics(early, WriteMode::Atomic).unwrap();
+            db.compact().unwrap();
+            assert_eq!(file_len(&dir, "wal.2"), 0);
+            assert!(!dir.0.join("wal.0").exists());
+            assert!(!dir.0.join("wal.1").exists());
+            let store = db.store.read().unwrap();
+            let series = &store.series["myservice.cpu_time"];
+            assert_eq!(series.chunks.iter().map(|chunk| chunk.count).collect::<Vec<_>>(), vec!(CHUNK_POINTS, 50));
+            assert!(series.chunks[0].max_time < series.chunks[1].min_time);
+            assert!(series.head.is_empty());
+        }
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_all(&db), metrics);
+        // with nothing new written, there is nothing to compact
+        db.compact().unwrap();
+        assert!(dir.0.join("wal.2").exists());
+    }
+
+    #[test]
+    fn writes_during_compaction_survive_a_crash() {
+        let dir = TempDir::new();
+        let mut metrics = points(CHUNK_POINTS as i64 + 50);
+        let (early, late) = metrics.split_at(metrics.len() / 2);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            db.write_metrics(early, WriteMode::Atomic).unwrap();
+            // the next log is started, but the chunks file for it never gets written
+            db.store.write().unwrap().compact().unwrap();
+            db.write_metrics(late, WriteMode::Atomic).unwrap();
+            assert!(dir.0.join("wal.0").exists());
+            assert!(file_len(&dir, "wal.1") > 0);
+        }
+        metrics.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_sorted(&db), metrics);
+        db.compact().unwrap();
+        assert!(!dir.0.join("wal.0").exists());
+        assert!(!dir.0.join("wal.1").exists());
+        drop(db);
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_sorted(&db), metrics);
+    }
+
+    #[test]
+    fn deletes_survive_reopening() {
+        let dir = TempDir::new();
+        let metrics = points(CHUNK_POINTS as i64 * 2);
+        let cutoff = *metrics[CHUNK_POINTS + 10].when;
+        let chunks_len = {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+            db.compact().unwrap();
+            let chunks_len = file_len(&dir, "chunks");
+            assert_eq!(db.delete_metrics("myservice.cpu", &cutoff).unwrap(), CHUNK_POINTS + 10);
+            assert_eq!(db.delete_metrics("myservice.cpu", &cutoff).unwrap(), 0);
+            // a point written again after the delete is replayed after it too
+            db.write_metrics(&metrics[..1], WriteMode::Atomic).unwrap();
+            chunks_len
+        };
+        let mut want: Vec<_> = metrics.iter()
+            .filter(|metric| metric.name == "myservice.state" || *metric.when >= cutoff)
+            .cloned()
+            .chain(std::iter::once(metrics[0].clone()))
+            .collect();
+        want.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        // deleting only logs the delete, leaving the rewrite to compaction
+        assert_eq!(file_len(&dir, "chunks"), chunks_len);
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_sorted(&db), want);
+        db.compact().unwrap();
+        assert!(file_len(&dir, "chunks") < chunks_len);
+        drop(db);
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_sorted(&db), want);
+    }
+
+    #[test]
+    fn overwrites_reach_into_chunks() {
+        let dir = TempDir::new();
+        let metrics = points(CHUNK_POINTS as i64 + 10);
+        let overwrite = Metric{value: MetricValue::Double(-1.0), ..metrics[3].clone()};
+        let conflicts = ConflictPolicies::uniform(ConflictPolicy::Overwrite);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            db.write_metrics(&metrics, WriteMode::Atomic).unwrap();
+            db.write_metrics_with(std::slice::from_ref(&overwrite), WriteMode::Atomic, &conflicts).unwrap();
+            let store = db.store.read().unwrap();
+            let series = &store.series["myservice.cpu_time"];
+            assert_eq!(series.chunks[0].count, CHUNK_POINTS - 1);
+            assert_eq!(series.head[&to_nanos(&overwrite.when).unwrap()], vec!(overwrite.value.clone()));
+        }
+        let mut want = metrics.clone();
+        want[3] = overwrite;
+        want.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        // replaying the log settles the conflict the same way again
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_all(&db), want);
+        db.compact().unwrap();
+        drop(db);
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        assert_eq!(read_all(&db), want);
+    }
+
+    #[test]
+    fn batch_ids_survive_reopening() {
+        let dir = TempDir::new();
+        let metrics = points(2);
+        let (conflicts, hour) = (ConflictPolicies::default(), chrono::Duration::hours(1));
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            let batch = [metrics[0].clone(), metrics[0].clone()];
+            db.write_batch("agent-1", hour, &batch, WriteMode::Partial, &conflicts).unwrap();
+            db.write_batch("agent-2", hour, &metrics[1..2], WriteMode::Partial, &conflicts).unwrap();
+            // agent-2 is carried over into the new log
+            db.compact().unwrap();
+            assert!(file_len(&dir, "wal.1") > 0);
+            db.compact().unwrap();
+            assert!(dir.0.join("wal.1").exists());
+        }
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        let retried = db.write_batch("agent-1", hour, &metrics[2..], WriteMode::Partial, &conflicts).unwrap();
+        assert!(retried.replayed);
+        assert_eq!(retried.failures.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec!(1));
+        assert!(db.write_batch("agent-2", hour, &metrics[2..], WriteMode::Partial, &conflicts).unwrap().replayed);
+        assert_eq!(read_all(&db), metrics[..2].to_vec());
+    }
+
+    #[test]
+    fn torn_writes_are_dropped() {
+        let dir = TempDir::new();
+        let metrics = points(10);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            db.write_metrics(&metrics[..5], WriteMode::Atomic).unwrap();
+        }
+        // a crash partway through appending the next record
+        let whole = file_len(&dir, "wal.0");
+        let mut wal = OpenOptions::new().append(true).open(dir.0.join("wal.0")).unwrap();
+        wal.write_all(&wal::frame(b"a record that never finished")[..12]).unwrap();
+        drop(wal);
+        {
+            let db = TsdbDatabase::open(&dir.0).unwrap();
+            assert_eq!(file_len(&dir, "wal.0"), whole);
+            assert_eq!(read_all(&db), metrics[..5].to_vec());
+            db.write_metrics(&metrics[5..], WriteMode::Atomic).unwrap();
+        }
+        let db = TsdbDatabase::open(&dir.0).unwrap();
+        let mut got = read_all(&db);
+        got.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        let mut want = metrics;
+        want.sort_by(|a, b| (&a.name, &a.when).cmp(&(&b.name, &b.when)));
+        assert_eq!(got, want);
+    }
+}
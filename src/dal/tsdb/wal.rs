@@ -0,0 +1,135 @@
+//! Both files the tsdb keeps, the write-ahead log and the chunks file, are logs of
+//! framed records: the payload's length and checksum, then the payload. A crash can
+//! leave the last record half written; reading stops before it, and reports how much
+//! of the file holds whole records so the rest can be cut off.
+use std::{
+    convert::TryInto,
+    io::{self, Read},
+    path::Path,
+};
+
+/// the length and checksum in front of every payload
+const FRAME_HEADER: usize = 8;
+
+/// frames a payload so it can be appended to a log
+pub(super) fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(FRAME_HEADER + payload.len());
+    framed.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    framed.extend_from_slice(&checksum(payload).to_le_bytes());
+    framed.extend_from_slice(payload);
+    framed
+}
+
+/// reads every whole record in a log, along with the length of the file they take up
+pub(super) fn read(path: &Path) -> io::Result<(Vec<Vec<u8>>, u64)> {
+    let mut bytes = vec!();
+    std::fs::File::open(path)?.read_to_end(&mut bytes)?;
+    let mut records = vec!();
+    let mut offset = 0;
+    while let Some(header) = bytes.get(offset..offset + FRAME_HEADER) {
+        let length = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let sum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        let payload = match bytes.get(offset + FRAME_HEADER..offset + FRAME_HEADER + length) {
+            Some(payload) if checksum(payload) == sum => payload,
+            _ => break,
+        };
+        records.push(payload.to_vec());
+        offset += FRAME_HEADER + length;
+    }
+    Ok((records, offset as u64))
+}
+
+/// FNV-1a, which is plenty to notice a torn write
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Encoder builds up a payload out of little-endian numbers and length-prefixed strings
+#[derive(Default)]
+pub(super) struct Encoder {
+    pub(super) bytes: Vec<u8>,
+}
+
+impl Encoder {
+    pub(super) fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub(super) fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub(super) fn f64(&mut self, value: f64) {
+        self.bytes.extend_from_slice(&value.to_bits().to_le_bytes());
+    }
+
+    pub(super) fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value);
+    }
+
+    pub(super) fn str(&mut self, value: &str) {
+        self.bytes(value.as_bytes());
+    }
+}
+
+/// Decoder reads back what an Encoder wrote, returning None once the payload runs out
+pub(super) struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub(super) fn new(bytes: &'a [u8]) -> Self {
+        Decoder{bytes}
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.bytes.split_at(n);
+        self.bytes = rest;
+        Some(taken)
+    }
+
+    pub(super) fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub(super) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    pub(super) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub(super) fn i64(&mut self) -> Option<i64> {
+        Some(i64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    pub(super) fn f64(&mut self) -> Option<f64> {
+        Some(f64::from_bits(self.u64()?))
+    }
+
+    pub(super) fn bytes(&mut self) -> Option<&'a [u8]> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub(super) fn str(&mut self) -> Option<&'a str> {
+        std::str::from_utf8(self.bytes()?).ok()
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
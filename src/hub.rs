@@ -0,0 +1,171 @@
+//! An in-process hub that hands newly recorded metrics to live subscribers, such as
+//! WatchMetrics streams. Publishing never waits on subscribers: one that falls too far
+//! behind misses metrics, and is told how many it missed.
+use std::sync::Arc;
+
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+use crate::dal::{
+    Filter,
+    Metric,
+    Selector,
+};
+
+/// how many metrics a subscriber may fall behind by before it misses any
+const HUB_CAPACITY: usize = 4096;
+
+#[derive(Debug, Clone)]
+pub struct Hub {
+    sender: broadcast::Sender<Arc<Metric<'static>>>,
+}
+
+impl Default for Hub {
+    fn default() -> Self {
+        Hub::new(HUB_CAPACITY)
+    }
+}
+
+impl Hub {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Hub{
+            sender,
+        }
+    }
+
+    /// hands recorded metrics to every subscriber
+    pub fn publish<'a, 'b: 'a, I: IntoIterator<Item=&'a Metric<'b>>>(&self, metrics: I) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        for metric in metrics {
+            // sending only fails once every subscriber has gone
+            if self.sender.send(Arc::new(metric.to_owned_metric())).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// subscribes to metrics recorded from now on that match the selector
+    pub fn subscribe(&self, selector: &Selector) -> Result<Subscription, regex::Error> {
+        Ok(Subscription{
+            receiver: self.sender.subscribe(),
+            filter: Filter::new(selector)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Received {
+    Metric(Arc<Metric<'static>>),
+    /// the subscriber fell behind, and this many metrics were dropped
+    Dropped(u64),
+}
+
+pub struct Subscription {
+    receiver: broadcast::Receiver<Arc<Metric<'static>>>,
+    filter: Filter,
+}
+
+impl Subscription {
+    /// waits for the next matching metric, or None once the hub has gone
+    pub async fn recv(&mut self) -> Option<Received> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(metric) if self.filter.matches(&metric.name, &metric.labels) => return Some(Received::Metric(metric)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(dropped)) => return Some(Received::Dropped(dropped)),
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// takes the next matching metric if one is already waiting
+    pub fn try_recv(&mut self) -> Option<Received> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(metric) if self.filter.matches(&metric.name, &metric.labels) => return Some(Received::Metric(metric)),
+                Ok(_) => continue,
+                Err(TryRecvError::Lagged(dropped)) => return Some(Received::Dropped(dropped)),
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use chrono::prelude::*;
+
+    use super::*;
+    use crate::dal::{
+        LabelMatcher,
+        Labels,
+        MatchOp,
+        MetricValue,
+    };
+
+    fn metric(name: &str, region: Option<&str>) -> Metric<'static> {
+        let mut labels = Labels::new();
+        if let Some(region) = region {
+            labels.insert(Cow::Borrowed("region"), Cow::Owned(region.to_string()));
+        }
+        Metric{
+            name: Cow::Owned(name.to_string()),
+            labels,
+            when: Cow::Owned(Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap()),
+            value: MetricValue::Double(1.0),
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_to_matching_subscribers() {
+        let hub = Hub::default();
+        let mut hosts = hub.subscribe(&"hosts.".into()).unwrap();
+        let mut eu = hub.subscribe(&Selector{
+            prefix: "hosts.".into(),
+            names: vec!(),
+            pattern: None,
+            matchers: vec!(LabelMatcher{ key: "region".into(), op: MatchOp::Regex, value: "eu-.*".into() }),
+        }).unwrap();
+        let mut not_eu = hub.subscribe(&Selector{
+            prefix: "".into(),
+            names: vec!(),
+            pattern: None,
+            matchers: vec!(LabelMatcher{ key: "region".into(), op: MatchOp::NotEqual, value: "eu-west".into() }),
+        }).unwrap();
+        let metrics = vec!(metric("hosts.cpu", Some("eu-west")), metric("hosts.cpu", None), metric("debug.cpu", Some("us")));
+        hub.publish(&metrics);
+
+        let names = |subscription: &mut Subscription| {
+            let mut received = vec!();
+            while let Some(Received::Metric(metric)) = subscription.try_recv() {
+                received.push(metric.as_ref().clone());
+            }
+            received
+        };
+        assert_eq!(names(&mut hosts), metrics[..2].to_vec());
+        assert_eq!(names(&mut eu), metrics[..1].to_vec());
+        assert_eq!(names(&mut not_eu), metrics[1..].to_vec());
+
+        hub.publish(&metrics[..1]);
+        assert_eq!(hosts.recv().await, Some(Received::Metric(Arc::new(metrics[0].clone()))));
+    }
+
+    #[test]
+    fn slow_subscribers_are_told_what_they_missed() {
+        let hub = Hub::new(2);
+        let mut subscription = hub.subscribe(&"".into()).unwrap();
+        let metrics: Vec<_> = (0..5).map(|i| metric(&format!("hosts.{}", i), None)).collect();
+        hub.publish(&metrics);
+        assert_eq!(subscription.try_recv(), Some(Received::Dropped(3)));
+        assert_eq!(subscription.try_recv(), Some(Received::Metric(Arc::new(metrics[3].clone()))));
+        assert_eq!(subscription.try_recv(), Some(Received::Metric(Arc::new(metrics[4].clone()))));
+        assert_eq!(subscription.try_recv(), None);
+    }
+}
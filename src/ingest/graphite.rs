@@ -0,0 +1,110 @@
+//! Graphite's plaintext protocol: one `name value [timestamp]` line per metric,
+//! where the timestamp is in seconds since the unix epoch. A missing timestamp,
+//! or -1, means now.
+use std::borrow::Cow;
+
+use chrono::prelude::*;
+use tokio::sync::mpsc;
+
+use crate::dal::{
+    Labels,
+    Metric,
+    MetricValue,
+};
+use super::{
+    LineHandler,
+    Malformed,
+};
+
+pub struct Graphite {
+    metrics: mpsc::Sender<Metric<'static>>,
+    malformed: Malformed,
+}
+
+impl Graphite {
+    pub fn new(metrics: mpsc::Sender<Metric<'static>>) -> Self {
+        Graphite{
+            metrics,
+            malformed: Malformed::new("graphite"),
+        }
+    }
+}
+
+/// parses a single line, using `now` when it has no timestamp
+pub fn parse(line: &str, now: DateTime<Utc>) -> Result<Metric<'static>, String> {
+    let mut fields = line.split_whitespace();
+    let name = fields.next().ok_or("missing name")?;
+    let value = match fields.next().ok_or("missing value")?.parse::<f64>() {
+        Ok(v) if v.is_finite() => v,
+        _ => return Err("value is not a number".into()),
+    };
+    let when = match fields.next() {
+        None | Some("-1") => now,
+        Some(timestamp) => {
+            let timestamp: f64 = timestamp.parse().map_err(|_| "timestamp is not a number")?;
+            if !timestamp.is_finite() {
+                return Err("timestamp is not a number".into());
+            }
+            // the cast saturates, so anything too far out is caught by the range check
+            Utc.timestamp_millis_opt((timestamp * 1e3).round() as i64)
+                .single()
+                .ok_or("timestamp is out of range")?
+        },
+    };
+    if fields.next().is_some() {
+        return Err("unexpected fields after the timestamp".into());
+    }
+    Ok(Metric{
+        name: Cow::Owned(name.to_string()),
+        labels: Labels::new(),
+        when: Cow::Owned(when),
+        value: MetricValue::Double(value),
+    })
+}
+
+#[tonic::async_trait]
+impl LineHandler for Graphite {
+    async fn handle(&self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        match parse(line, Utc::now()) {
+            // the writer only goes away when we are shutting down
+            Ok(metric) => { let _ = self.metrics.send(metric).await; },
+            Err(e) => self.malformed.record(line, &e),
+        }
+    }
+
+    fn malformed(&self) -> &Malformed {
+        &self.malformed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        let now = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap();
+        let metric = |when| Metric{
+            name: Cow::Borrowed("hosts.aura.cpu_load"),
+            labels: Labels::new(),
+            when: Cow::Owned(when),
+            value: MetricValue::Double(0.5),
+        };
+        assert_eq!(parse("hosts.aura.cpu_load 0.5 1516991400", now), Ok(metric(Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap())));
+        assert_eq!(parse("hosts.aura.cpu_load 0.5 1516991400.25", now),
+            Ok(metric(Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap() + chrono::Duration::milliseconds(250))));
+        assert_eq!(parse("hosts.aura.cpu_load\t0.5", now), Ok(metric(now)));
+        assert_eq!(parse("hosts.aura.cpu_load 0.5 -1", now), Ok(metric(now)));
+        assert!(parse("hosts.aura.cpu_load", now).is_err());
+        assert!(parse("hosts.aura.cpu_load high", now).is_err());
+        assert!(parse("hosts.aura.cpu_load nan", now).is_err());
+        assert!(parse("hosts.aura.cpu_load -inf", now).is_err());
+        assert!(parse("hosts.aura.cpu_load 0.5 1e300", now).is_err());
+        assert!(parse("hosts.aura.cpu_load 0.5 -9223372036854775", now).is_err());
+        assert!(parse("hosts.aura.cpu_load 0.5 yesterday", now).is_err());
+        assert!(parse("hosts.aura.cpu_load 0.5 1516991400 extra", now).is_err());
+    }
+}
@@ -0,0 +1,218 @@
+//! Listeners for the plaintext protocols our older scripts speak: Graphite's
+//! `name value timestamp` lines and StatsD's `name:value|type` packets. Each
+//! listener accepts lines over both UDP and TCP on the same address. Lines that
+//! cannot be parsed are counted and logged, and the listener carries on.
+use std::{
+    net::SocketAddr,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use log::{info, warn};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, BufReader},
+    net::{TcpListener, UdpSocket},
+    sync::mpsc,
+};
+
+use crate::dal::{
+    ConflictPolicies,
+    Database,
+    Metric,
+    WriteMode,
+    Written,
+};
+
+pub mod graphite;
+pub mod statsd;
+
+/// the most metrics written to the database at once
+const MAX_BATCH: usize = 1000;
+
+/// the longest line accepted over TCP, the same as the largest UDP datagram
+const MAX_LINE: usize = 64 * 1024;
+
+/// how often a listener logs how many malformed lines it has seen
+const MALFORMED_REPORT_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// LineHandler is handed every line a listener receives
+#[tonic::async_trait]
+pub trait LineHandler: Send + Sync + 'static {
+    async fn handle(&self, line: &str);
+
+    /// the lines the handler could not parse
+    fn malformed(&self) -> &Malformed;
+}
+
+/// Malformed counts the lines of a protocol that could not be parsed
+pub struct Malformed {
+    protocol: &'static str,
+    count: AtomicU64,
+    reported: AtomicU64,
+}
+
+impl Malformed {
+    pub fn new(protocol: &'static str) -> Self {
+        Malformed{
+            protocol,
+            count: AtomicU64::new(0),
+            reported: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, line: &str, reason: &str) {
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Ignoring malformed {} line {:?} ({} so far): {}", self.protocol, line, count, reason);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// logs the lines that were malformed since the last report, along with the total, and
+    /// returns how many there were
+    pub fn report(&self) -> u64 {
+        let count = self.count();
+        let new = count - self.reported.swap(count, Ordering::Relaxed);
+        if new > 0 {
+            warn!("{} malformed {} lines in the last {:?}, {} in all", new, self.protocol, MALFORMED_REPORT_PERIOD, count);
+        }
+        new
+    }
+}
+
+/// reads the next line into `buf` without its line ending, returning false at the end of the
+/// stream; a line longer than MAX_LINE is an error, as the rest of it would have to be buffered
+async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R, buf: &mut Vec<u8>) -> std::io::Result<bool> {
+    buf.clear();
+    let len = reader.take(MAX_LINE as u64 + 1).read_until(b'\n', buf).await?;
+    if len == 0 {
+        return Ok(false);
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+        if buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+    } else if len > MAX_LINE {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData,
+            format!("line is longer than {} bytes", MAX_LINE)));
+    }
+    Ok(true)
+}
+
+/// receives lines from UDP datagrams and TCP connections on `addr`, passing each to the handler
+pub async fn listen<H: LineHandler>(addr: SocketAddr, handler: Arc<H>) -> std::io::Result<()> {
+    let udp = UdpSocket::bind(addr).await?;
+    let tcp = TcpListener::bind(addr).await?;
+    let reporter = handler.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(MALFORMED_REPORT_PERIOD);
+        // the first tick completes immediately, before anything could have been received
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            reporter.malformed().report();
+        }
+    });
+    let udp_handler = handler.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            match udp.recv_from(&mut buf).await {
+                Ok((len, _)) => {
+                    for line in String::from_utf8_lossy(&buf[..len]).lines() {
+                        udp_handler.handle(line).await;
+                    }
+                },
+                Err(e) => warn!("Unable to receive on {}: {}", addr, e),
+            }
+        }
+    });
+    loop {
+        let (stream, peer) = tcp.accept().await?;
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+            loop {
+                match next_line(&mut reader, &mut line).await {
+                    Ok(true) => handler.handle(&String::from_utf8_lossy(&line)).await,
+                    Ok(false) => break,
+                    Err(e) => {
+                        info!("Dropping connection from {}: {}", peer, e);
+                        break;
+                    },
+                }
+            }
+        });
+    }
+}
+
+/// writes the metrics sent down the channel in batches, settling conflicting points by
+/// `conflicts`, until every sender is gone
+pub async fn write_batches<D: Database + Clone + 'static>(db: D, conflicts: ConflictPolicies,
+    mut metrics: mpsc::Receiver<Metric<'static>>) {
+    let conflicts = Arc::new(conflicts);
+    while let Some(metric) = metrics.recv().await {
+        let mut batch = vec!(metric);
+        while batch.len() < MAX_BATCH {
+            match metrics.try_recv() {
+                Ok(metric) => batch.push(metric),
+                Err(_) => break,
+            }
+        }
+        let db = db.clone();
+        let conflicts = conflicts.clone();
+        // writing takes the database lock, so keep it off the async workers
+        match tokio::task::spawn_blocking(move || db.write_metrics_with(&batch, WriteMode::Partial, &conflicts)).await {
+            Ok(Ok(Written{failures, ..})) => {
+                if let Some((_, e)) = failures.first() {
+                    warn!("Unable to record {} plaintext metrics; first error: {}", failures.len(), e);
+                }
+            },
+            Ok(Err(e)) => warn!("Unable to record plaintext metrics: {}", e),
+            Err(e) => warn!("Plaintext writer failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_malformed_lines() {
+        let malformed = Malformed::new("graphite");
+        assert_eq!(malformed.report(), 0);
+        malformed.record("cpu_load", "missing value");
+        malformed.record("cpu_load high", "value is not a number");
+        assert_eq!(malformed.report(), 2);
+        assert_eq!(malformed.report(), 0);
+        malformed.record("cpu_load", "missing value");
+        assert_eq!(malformed.report(), 1);
+        assert_eq!(malformed.count(), 3);
+    }
+
+    #[tokio::test]
+    async fn tcp_lines_are_bounded() {
+        let long = "a".repeat(MAX_LINE);
+        let input = format!("one 1\r\ntwo 2\n{}\n{}b\nnever", long, long);
+        let mut reader = input.as_bytes();
+        let mut line = Vec::new();
+        assert!(next_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"one 1");
+        assert!(next_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"two 2");
+        assert!(next_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, long.as_bytes());
+        assert!(next_line(&mut reader, &mut line).await.is_err());
+
+        let mut reader = "last".as_bytes();
+        assert!(next_line(&mut reader, &mut line).await.unwrap());
+        assert_eq!(line, b"last");
+        assert!(!next_line(&mut reader, &mut line).await.unwrap());
+    }
+}
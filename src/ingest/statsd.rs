@@ -0,0 +1,348 @@
+//! StatsD's `name:value|type[|@rate]` lines. Samples are aggregated in memory
+//! and only written when flushed:
+//!
+//! * counters (`c`) are stored as the total for the interval, scaled up by their sample rate
+//! * timers (`ms`) are stored as `name.count`, `name.mean`, `name.min`, `name.max` and `name.p90`
+//! * gauges (`g`) are stored as their latest value; `+n` and `-n` adjust the previous value
+//! * sets (`s`) are stored as the number of distinct values seen
+//!
+//! At most MAX_NAMES names are aggregated between flushes, and samples for any more
+//! are dropped. A gauge that goes GAUGE_IDLE_FLUSHES flushes without an update is
+//! forgotten, so a delta for it starts again from zero.
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use chrono::prelude::*;
+use log::warn;
+use tokio::sync::mpsc;
+
+use crate::dal::{
+    Labels,
+    Metric,
+    MetricValue,
+};
+use super::{
+    LineHandler,
+    Malformed,
+};
+
+/// the most names of each type aggregated between flushes
+pub const MAX_NAMES: usize = 10_000;
+
+/// how many flushes a gauge is kept for without being updated
+pub const GAUGE_IDLE_FLUSHES: u32 = 360;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sample {
+    Counter(f64),
+    Timer{
+        value: f64,
+        rate: f64,
+    },
+    Gauge{
+        value: f64,
+        delta: bool,
+    },
+    Set(String),
+}
+
+/// parses a single line into the metric name and its sample
+pub fn parse(line: &str) -> Result<(&str, Sample), String> {
+    let (name, rest) = line.split_once(':').ok_or("missing ':'")?;
+    if name.is_empty() {
+        return Err("missing name".into());
+    }
+    let mut fields = rest.split('|');
+    let value = fields.next().unwrap_or_default();
+    let kind = fields.next().ok_or("missing type")?;
+    let mut rate = 1.0;
+    for field in fields {
+        // DogStatsD tags and the like are accepted but not kept
+        if let Some(r) = field.strip_prefix('@') {
+            rate = r.parse().map_err(|_| "sample rate is not a number")?;
+            if !(rate > 0.0 && rate <= 1.0) {
+                return Err("sample rate must be in (0, 1]".into());
+            }
+        }
+    }
+    let number = || -> Result<f64, String> {
+        match value.parse::<f64>() {
+            Ok(v) if v.is_finite() => Ok(v),
+            _ => Err("value is not a number".into()),
+        }
+    };
+    let sample = match kind {
+        "c" => Sample::Counter(number()? / rate),
+        "ms" | "h" => Sample::Timer{ value: number()?, rate },
+        "g" => Sample::Gauge{ value: number()?, delta: value.starts_with(['+', '-'].as_ref()) },
+        "s" => Sample::Set(value.to_string()),
+        _ => return Err(format!("unknown type '{}'", kind)),
+    };
+    Ok((name, sample))
+}
+
+#[derive(Default)]
+struct Timer {
+    count: f64,
+    values: Vec<f64>,
+}
+
+#[derive(Default)]
+struct Gauge {
+    value: f64,
+    /// the flushes since the gauge was last updated
+    idle: u32,
+}
+
+#[derive(Default)]
+struct Aggregates {
+    counters: HashMap<String, f64>,
+    timers: HashMap<String, Timer>,
+    /// gauges keep their value across flushes so deltas have something to adjust
+    gauges: HashMap<String, Gauge>,
+    updated_gauges: HashSet<String>,
+    sets: HashMap<String, HashSet<String>>,
+    /// samples dropped since the last flush for names beyond MAX_NAMES
+    dropped: u64,
+}
+
+/// finds the entry for `name`, unless it would be a name too many
+fn entry<'a, V: Default>(map: &'a mut HashMap<String, V>, name: &str) -> Option<&'a mut V> {
+    if map.len() >= MAX_NAMES && !map.contains_key(name) {
+        return None;
+    }
+    Some(map.entry(name.to_string()).or_default())
+}
+
+pub struct StatsD {
+    aggregates: Mutex<Aggregates>,
+    malformed: Malformed,
+}
+
+impl Default for StatsD {
+    fn default() -> Self {
+        StatsD{
+            aggregates: Mutex::default(),
+            malformed: Malformed::new("statsd"),
+        }
+    }
+}
+
+fn metric(name: String, when: DateTime<Utc>, value: f64) -> Metric<'static> {
+    Metric{
+        name: Cow::Owned(name),
+        labels: Labels::new(),
+        when: Cow::Owned(when),
+        value: MetricValue::Double(value),
+    }
+}
+
+impl StatsD {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, name: &str, sample: Sample) {
+        let mut aggregates = self.aggregates.lock().unwrap();
+        let aggregates = &mut *aggregates;
+        let recorded = match sample {
+            Sample::Counter(value) => entry(&mut aggregates.counters, name).map(|total| *total += value),
+            Sample::Timer{ value, rate } => entry(&mut aggregates.timers, name).map(|timer| {
+                timer.count += 1.0 / rate;
+                timer.values.push(value);
+            }),
+            Sample::Gauge{ value, delta } => {
+                // gauges outlive flushes, so it is the ones updated since the last that are bounded
+                if aggregates.updated_gauges.len() >= MAX_NAMES && !aggregates.updated_gauges.contains(name) {
+                    None
+                } else {
+                    let gauge = aggregates.gauges.entry(name.to_string()).or_default();
+                    if delta {
+                        gauge.value += value;
+                    } else {
+                        gauge.value = value;
+                    }
+                    aggregates.updated_gauges.insert(name.to_string());
+                    Some(())
+                }
+            },
+            Sample::Set(value) => entry(&mut aggregates.sets, name).map(|values| { values.insert(value); }),
+        };
+        if recorded.is_none() {
+            aggregates.dropped += 1;
+        }
+    }
+
+    /// takes everything aggregated since the last flush as metrics recorded at `now`
+    pub fn flush(&self, now: DateTime<Utc>) -> Vec<Metric<'static>> {
+        let mut aggregates = self.aggregates.lock().unwrap();
+        let mut metrics = Vec::new();
+        for (name, total) in aggregates.counters.drain() {
+            metrics.push(metric(name, now, total));
+        }
+        for (name, mut timer) in aggregates.timers.drain() {
+            timer.values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let values = &timer.values;
+            let p90 = values[((values.len() as f64 * 0.9).ceil() as usize).max(1) - 1];
+            metrics.push(metric(format!("{}.count", name), now, timer.count));
+            metrics.push(metric(format!("{}.mean", name), now, values.iter().sum::<f64>() / values.len() as f64));
+            metrics.push(metric(format!("{}.min", name), now, values[0]));
+            metrics.push(metric(format!("{}.max", name), now, values[values.len() - 1]));
+            metrics.push(metric(format!("{}.p90", name), now, p90));
+        }
+        for gauge in aggregates.gauges.values_mut() {
+            gauge.idle += 1;
+        }
+        let updated: Vec<_> = aggregates.updated_gauges.drain().collect();
+        for name in updated {
+            let gauge = aggregates.gauges.get_mut(&name).unwrap();
+            gauge.idle = 0;
+            let value = gauge.value;
+            metrics.push(metric(name, now, value));
+        }
+        aggregates.gauges.retain(|_, gauge| gauge.idle <= GAUGE_IDLE_FLUSHES);
+        for (name, values) in aggregates.sets.drain() {
+            metrics.push(metric(name, now, values.len() as f64));
+        }
+        if aggregates.dropped > 0 {
+            warn!("Dropped {} StatsD samples for names beyond the {} of each type a flush holds", aggregates.dropped, MAX_NAMES);
+            aggregates.dropped = 0;
+        }
+        metrics
+    }
+}
+
+#[tonic::async_trait]
+impl LineHandler for StatsD {
+    async fn handle(&self, line: &str) {
+        if line.trim().is_empty() {
+            return;
+        }
+        match parse(line) {
+            Ok((name, sample)) => self.record(name, sample),
+            Err(e) => self.malformed.record(line, &e),
+        }
+    }
+
+    fn malformed(&self) -> &Malformed {
+        &self.malformed
+    }
+}
+
+/// flushes the aggregates to the writer every `period`
+pub async fn flush(statsd: Arc<StatsD>, metrics: mpsc::Sender<Metric<'static>>, period: std::time::Duration) {
+    let mut interval = tokio::time::interval(period);
+    // the first tick completes immediately, and there is nothing to flush yet
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        for metric in statsd.flush(Utc::now()) {
+            if metrics.send(metric).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(parse("hits:1|c"), Ok(("hits", Sample::Counter(1.0))));
+        assert_eq!(parse("hits:1|c|@0.1"), Ok(("hits", Sample::Counter(10.0))));
+        assert_eq!(parse("hits:1|c|#region:eu"), Ok(("hits", Sample::Counter(1.0))));
+        assert_eq!(parse("rpc.time:320|ms|@0.5"), Ok(("rpc.time", Sample::Timer{ value: 320.0, rate: 0.5 })));
+        assert_eq!(parse("queue:42|g"), Ok(("queue", Sample::Gauge{ value: 42.0, delta: false })));
+        assert_eq!(parse("queue:-2|g"), Ok(("queue", Sample::Gauge{ value: -2.0, delta: true })));
+        assert_eq!(parse("users:aura|s"), Ok(("users", Sample::Set("aura".into()))));
+        assert!(parse("hits").is_err());
+        assert!(parse(":1|c").is_err());
+        assert!(parse("hits:1").is_err());
+        assert!(parse("hits:one|c").is_err());
+        assert!(parse("hits:1|x").is_err());
+        assert!(parse("hits:1|c|@2").is_err());
+    }
+
+    #[test]
+    fn flush_aggregates() {
+        let statsd = StatsD::new();
+        let now = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap();
+        for line in &["hits:1|c", "hits:2|c|@0.5", "rpc:10|ms", "rpc:30|ms|@0.5", "rpc:20|ms",
+                      "queue:40|g", "queue:+2|g", "users:a|s", "users:b|s", "users:a|s"] {
+            let (name, sample) = parse(line).unwrap();
+            statsd.record(name, sample);
+        }
+        let mut metrics = statsd.flush(now);
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        let values: Vec<_> = metrics.iter().map(|m| (m.name.as_ref(), m.value.clone())).collect();
+        assert_eq!(values, vec!(
+            ("hits", MetricValue::Double(5.0)),
+            ("queue", MetricValue::Double(42.0)),
+            ("rpc.count", MetricValue::Double(4.0)),
+            ("rpc.max", MetricValue::Double(30.0)),
+            ("rpc.mean", MetricValue::Double(20.0)),
+            ("rpc.min", MetricValue::Double(10.0)),
+            ("rpc.p90", MetricValue::Double(30.0)),
+            ("users", MetricValue::Double(2.0)),
+        ));
+        assert!(metrics.iter().all(|m| *m.when == now));
+
+        // gauges are only written again once updated, and deltas build on the last value
+        assert_eq!(statsd.flush(now), vec!());
+        let (name, sample) = parse("queue:-2|g").unwrap();
+        statsd.record(name, sample);
+        assert_eq!(statsd.flush(now), vec!(metric("queue".into(), now, 40.0)));
+    }
+
+    #[test]
+    fn names_are_bounded() {
+        let statsd = StatsD::new();
+        let now = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap();
+        for i in 0..=MAX_NAMES {
+            statsd.record(&format!("hits.{}", i), Sample::Counter(1.0));
+            statsd.record(&format!("queue.{}", i), Sample::Gauge{ value: 1.0, delta: false });
+        }
+        // names already being aggregated still are
+        statsd.record("hits.0", Sample::Counter(1.0));
+        let metrics = statsd.flush(now);
+        assert_eq!(metrics.len(), 2 * MAX_NAMES);
+        assert!(metrics.contains(&metric("hits.0".into(), now, 2.0)));
+        assert!(!metrics.iter().any(|m| m.name == format!("hits.{}", MAX_NAMES)));
+
+        // and the next flush has room again
+        statsd.record(&format!("hits.{}", MAX_NAMES), Sample::Counter(1.0));
+        assert_eq!(statsd.flush(now), vec!(metric(format!("hits.{}", MAX_NAMES), now, 1.0)));
+    }
+
+    #[test]
+    fn idle_gauges_are_forgotten() {
+        let statsd = StatsD::new();
+        let now = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 0).unwrap();
+        statsd.record("queue", Sample::Gauge{ value: 40.0, delta: false });
+        statsd.record("workers", Sample::Gauge{ value: 4.0, delta: false });
+        statsd.flush(now);
+        for _ in 0..GAUGE_IDLE_FLUSHES {
+            statsd.record("workers", Sample::Gauge{ value: 1.0, delta: true });
+            statsd.flush(now);
+        }
+        statsd.record("queue", Sample::Gauge{ value: 2.0, delta: true });
+        statsd.record("workers", Sample::Gauge{ value: 1.0, delta: true });
+        let mut metrics = statsd.flush(now);
+        metrics.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(metrics, vec!(
+            metric("queue".into(), now, 42.0),
+            metric("workers".into(), now, 4.0 + GAUGE_IDLE_FLUSHES as f64 + 1.0),
+        ));
+
+        for _ in 0..=GAUGE_IDLE_FLUSHES {
+            statsd.flush(now);
+        }
+        statsd.record("queue", Sample::Gauge{ value: 2.0, delta: true });
+        assert_eq!(statsd.flush(now), vec!(metric("queue".into(), now, 2.0)));
+    }
+}
@@ -1,2 +1,8 @@
 pub mod dal;
+pub mod hub;
+pub mod ingest;
+pub mod prometheus;
+pub mod ratelimit;
+pub mod retention;
+pub mod rollup;
 pub mod server;
\ No newline at end of file
@@ -1,12 +1,42 @@
+use hyper::{
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+};
 use tonic::{transport};
-use log::{info};
+use tower::{Layer, Service};
+use log::{error, info};
+
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use oc_metrics::{
+    ingest::{
+        self,
+        graphite::Graphite,
+        statsd::{self, StatsD},
+    },
+    prometheus,
+    ratelimit::{PeerAddr, Quotas, RateLimitLayer},
+    retention::{self, RetentionPolicy},
+    rollup,
     dal::{
+        ConflictPolicies,
         Database,
+        memory::{self, MemoryDatabase},
+        postgres::PostgresDatabase,
         sqlite::SqliteDatabase,
+        tsdb::{self, TsdbDatabase},
     },
     server::{
+        DEFAULT_BATCH_TTL,
+        DEFAULT_MAX_NAME_LENGTH,
+        DEFAULT_MAX_STRING_LENGTH,
+        DEFAULT_NAME_PATTERN,
+        DEFAULT_TRANSACTION_SIZE,
+        IngestRules,
         Server,
         proto::{
             FILE_DESCRIPTOR_SET,
@@ -15,6 +45,43 @@ use oc_metrics::{
 }   ,
 };
 
+/// how often retention policies are enforced
+const RETENTION_PERIOD: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// how often raw metrics are rolled up
+const ROLLUP_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// how old a bucket has to be before it is rolled up, unless ROLLUP_LATENESS says otherwise
+const ROLLUP_LATENESS: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// how often a tsdb database is compacted
+const COMPACTION_PERIOD: std::time::Duration = std::time::Duration::from_secs(5 * 60);
+
+/// how often an in-memory database logs what it has evicted
+const EVICTION_REPORT_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// how often StatsD aggregates are written, unless STATSD_FLUSH says otherwise
+const STATSD_FLUSH_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// how many plaintext metrics may wait on the database before listeners hold off
+const PLAINTEXT_BACKLOG: usize = 10_000;
+
+/// the settings read from the environment, other than the database to use
+struct Config {
+    addr: SocketAddr,
+    prometheus_addr: Option<SocketAddr>,
+    graphite_addr: Option<SocketAddr>,
+    statsd_addr: Option<SocketAddr>,
+    statsd_flush: std::time::Duration,
+    transaction_size: usize,
+    batch_ttl: std::time::Duration,
+    rollup_lateness: chrono::Duration,
+    policies: Vec<RetentionPolicy>,
+    conflicts: ConflictPolicies,
+    rules: IngestRules,
+    quotas: Quotas,
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // grab env variables
@@ -22,7 +89,105 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let dbpath = std::env::var("DBPATH").unwrap_or_else(|_| ":memory:".into());
     let addr = std::env::var("LISTEN").unwrap_or_else(|_| "[::1]:50051".into());
     let addr = addr.parse()?;
-    info!("Starting server on port {} with database {}", addr, dbpath);
+    let prometheus_addr = match std::env::var("PROMETHEUS_LISTEN") {
+        Ok(addr) => Some(addr.parse()?),
+        Err(_) => None,
+    };
+    let graphite_addr = match std::env::var("GRAPHITE_LISTEN") {
+        Ok(addr) => Some(addr.parse()?),
+        Err(_) => None,
+    };
+    let statsd_addr = match std::env::var("STATSD_LISTEN") {
+        Ok(addr) => Some(addr.parse()?),
+        Err(_) => None,
+    };
+    let statsd_flush = match std::env::var("STATSD_FLUSH") {
+        Ok(period) => retention::parse_duration(&period)?.to_std()?,
+        Err(_) => STATSD_FLUSH_PERIOD,
+    };
+    let transaction_size = match std::env::var("STREAM_TRANSACTION_SIZE") {
+        Ok(size) => size.parse()?,
+        Err(_) => DEFAULT_TRANSACTION_SIZE,
+    };
+    let batch_ttl = match std::env::var("BATCH_ID_TTL") {
+        Ok(ttl) => retention::parse_duration(&ttl)?.to_std()?,
+        Err(_) => DEFAULT_BATCH_TTL,
+    };
+    let rollup_lateness = match std::env::var("ROLLUP_LATENESS") {
+        Ok(lateness) => retention::parse_duration(&lateness)?,
+        Err(_) => chrono::Duration::from_std(ROLLUP_LATENESS)?,
+    };
+    let policies = retention::parse_policies(&std::env::var("RETENTION").unwrap_or_default())?;
+    let conflicts: ConflictPolicies = std::env::var("CONFLICT_POLICY").unwrap_or_default().parse()?;
+    let rules = IngestRules{
+        name_pattern: IngestRules::name_pattern(
+            &std::env::var("METRIC_NAME_PATTERN").unwrap_or_else(|_| DEFAULT_NAME_PATTERN.into()))?,
+        max_name_length: match std::env::var("MAX_NAME_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => DEFAULT_MAX_NAME_LENGTH,
+        },
+        max_string_length: match std::env::var("MAX_STRING_LENGTH") {
+            Ok(length) => length.parse()?,
+            Err(_) => DEFAULT_MAX_STRING_LENGTH,
+        },
+        max_age: match std::env::var("MAX_METRIC_AGE") {
+            Ok(age) => Some(retention::parse_duration(&age)?),
+            Err(_) => None,
+        },
+        max_ahead: match std::env::var("MAX_METRIC_AHEAD") {
+            Ok(ahead) => Some(retention::parse_duration(&ahead)?),
+            Err(_) => None,
+        },
+        nan: std::env::var("NAN_VALUES").unwrap_or_else(|_| "reject".into()).parse()?,
+    };
+    let quotas = Quotas{
+        points: std::env::var("RATE_LIMIT_POINTS").unwrap_or_default().parse()?,
+        series: std::env::var("RATE_LIMIT_SERIES").unwrap_or_default().parse()?,
+        request_bytes: std::env::var("RATE_LIMIT_BYTES").unwrap_or_default().parse()?,
+    };
+    let config = Config{
+        addr,
+        prometheus_addr,
+        graphite_addr,
+        statsd_addr,
+        statsd_flush,
+        transaction_size,
+        batch_ttl,
+        rollup_lateness,
+        policies,
+        conflicts,
+        rules,
+        quotas,
+    };
+
+    // a postgres URL picks the postgres backend, a tsdb:// one the tsdb backend in the
+    // directory that follows and a memory:// one the in-memory backend; anything else, :memory:
+    // included, is a path to a sqlite database
+    if dbpath.starts_with("postgres://") || dbpath.starts_with("postgresql://") {
+        // the URL may carry a password, so only the backend is logged
+        info!("Starting server on port {} with a postgres database", addr);
+        serve(PostgresDatabase::new(&dbpath)?, config).await
+    } else if let Some(dir) = dbpath.strip_prefix("tsdb://") {
+        info!("Starting server on port {} with a tsdb database in {}", addr, dir);
+        let db = TsdbDatabase::open(dir)?;
+        tokio::spawn(tsdb::run_compaction(db.clone(), COMPACTION_PERIOD));
+        serve(db, config).await
+    } else if let Some(options) = dbpath.strip_prefix("memory://") {
+        info!("Starting server on port {} with an in-memory database", addr);
+        let db = memory_database(options)?;
+        tokio::spawn(memory::report_evictions(db.clone(), EVICTION_REPORT_PERIOD));
+        serve(db, config).await
+    } else {
+        info!("Starting server on port {} with database {}", addr, dbpath);
+        serve(SqliteDatabase::new(&dbpath)?, config).await
+    }
+}
+
+/// sets up the database, starts the background tasks and listeners the config asks for,
+/// and serves the metrics service until it fails
+async fn serve<D: Database + Clone + 'static>(db: D, config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let Config{addr, prometheus_addr, graphite_addr, statsd_addr, statsd_flush, transaction_size, batch_ttl,
+        rollup_lateness, policies, conflicts, rules, quotas} = config;
 
     // build reflection service
     let reflection_service = tonic_reflection::server::Builder::configure()
@@ -30,16 +195,89 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build()
         .unwrap();
     
-    let db = SqliteDatabase::new(&dbpath)?;
     db.setup()?;
 
-    let logger_service = MetricsServiceServer::new(Server::new(db));
+    tokio::spawn(rollup::run(db.clone(), ROLLUP_PERIOD, rollup_lateness));
+    if !policies.is_empty() {
+        info!("Enforcing retention policies {:?}", policies);
+        tokio::spawn(retention::enforce(db.clone(), policies, RETENTION_PERIOD));
+    }
+
+    if let Some(prometheus_addr) = prometheus_addr {
+        info!("Accepting Prometheus remote_write on {}", prometheus_addr);
+        let db = db.clone();
+        let conflicts = conflicts.clone();
+        tokio::spawn(async move {
+            if let Err(e) = prometheus::serve(prometheus_addr, db, conflicts).await {
+                error!("Prometheus endpoint failed: {}", e);
+            }
+        });
+    }
+
+    if graphite_addr.is_some() || statsd_addr.is_some() {
+        let (metrics, backlog) = tokio::sync::mpsc::channel(PLAINTEXT_BACKLOG);
+        tokio::spawn(ingest::write_batches(db.clone(), conflicts.clone(), backlog));
+        if let Some(graphite_addr) = graphite_addr {
+            info!("Accepting Graphite plaintext on {}", graphite_addr);
+            let graphite = Arc::new(Graphite::new(metrics.clone()));
+            tokio::spawn(async move {
+                if let Err(e) = ingest::listen(graphite_addr, graphite).await {
+                    error!("Graphite listener failed: {}", e);
+                }
+            });
+        }
+        if let Some(statsd_addr) = statsd_addr {
+            info!("Accepting StatsD on {}, flushing every {:?}", statsd_addr, statsd_flush);
+            let statsd = Arc::new(StatsD::new());
+            tokio::spawn(statsd::flush(statsd.clone(), metrics, statsd_flush));
+            tokio::spawn(async move {
+                if let Err(e) = ingest::listen(statsd_addr, statsd).await {
+                    error!("StatsD listener failed: {}", e);
+                }
+            });
+        }
+    }
 
-    transport::Server::builder()
-        .add_service(reflection_service)
-        .add_service(logger_service)
-        .serve(addr)
+    let logger_service = RateLimitLayer::new(quotas).layer(MetricsServiceServer::new(Server::new(db)
+        .with_transaction_size(transaction_size)
+        .with_conflict_policies(conflicts)
+        .with_batch_ttl(batch_ttl)
+        .with_ingest_rules(rules)));
+
+    // tonic keeps the peer address where only a tonic::Request can read it, so each connection
+    // gets a router of its own that attaches it to requests for the rate limiter
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let peer = PeerAddr(conn.remote_addr());
+        let mut router = transport::Server::builder()
+            .add_service(reflection_service.clone())
+            .add_service(logger_service.clone())
+            .into_service();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |mut req| {
+                req.extensions_mut().insert(peer);
+                router.call(req)
+            }))
+        }
+    });
+    hyper::Server::bind(&addr)
+        .http2_only(true)
+        .serve(make_service)
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// creates the in-memory database described by what follows `memory://`, which may give
+/// its limits as `?max_series=1000&max_points=500`
+fn memory_database(options: &str) -> Result<MemoryDatabase, Box<dyn std::error::Error>> {
+    let mut max_series = memory::DEFAULT_MAX_SERIES;
+    let mut max_points = memory::DEFAULT_MAX_POINTS;
+    for option in options.trim_start_matches('?').split('&').filter(|option| !option.is_empty()) {
+        match option.split_once('=') {
+            Some(("max_series", n)) => max_series = n.parse()?,
+            Some(("max_points", n)) => max_points = n.parse()?,
+            _ => return Err(format!("unknown in-memory database option {}", option).into()),
+        }
+    }
+    Ok(MemoryDatabase::new(max_series, max_points))
+}
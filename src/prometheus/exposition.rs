@@ -0,0 +1,187 @@
+//! Renders the latest value of every double-valued series in the Prometheus
+//! text exposition format, for Prometheus to scrape from `/metrics`. A `prefix`
+//! query parameter limits the series rendered. Names are sanitized into valid
+//! Prometheus names (`hosts.cpu_load` becomes `hosts_cpu_load`), and each
+//! sample carries the time it was recorded at.
+use hyper::{
+    Body,
+    Request,
+    Response,
+    StatusCode,
+    header,
+};
+use log::{warn};
+
+use crate::dal::{
+    Database,
+    Metric,
+    MetricValue,
+};
+use super::respond;
+
+/// replaces every character Prometheus does not allow in a name with an underscore
+pub fn sanitize_name(name: &str, allow_colons: bool) -> String {
+    let mut sanitized = String::with_capacity(name.len() + 1);
+    for (i, c) in name.chars().enumerate() {
+        if i == 0 && c.is_ascii_digit() {
+            sanitized.push('_');
+        }
+        if c.is_ascii_alphanumeric() || c == '_' || (allow_colons && c == ':') {
+            sanitized.push(c);
+        } else {
+            sanitized.push('_');
+        }
+    }
+    if sanitized.is_empty() {
+        sanitized.push('_');
+    }
+    sanitized
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// renders the double-valued metrics in the text exposition format
+pub fn render(metrics: &[Metric]) -> String {
+    let mut samples: Vec<(String, &Metric, f64)> = metrics.iter()
+        .filter_map(|metric| match metric.value {
+            MetricValue::Double(value) => Some((sanitize_name(&metric.name, true), metric, value)),
+            MetricValue::String(_) => None,
+        })
+        .collect();
+    // every sample of a metric has to follow its TYPE line
+    samples.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut text = String::new();
+    let mut family: Option<&str> = None;
+    for (name, metric, value) in &samples {
+        if family != Some(name) {
+            text += &format!("# TYPE {} untyped\n", name);
+            family = Some(name);
+        }
+        text += name;
+        if !metric.labels.is_empty() {
+            let labels: Vec<String> = metric.labels.iter()
+                .map(|(k, v)| format!("{}=\"{}\"", sanitize_name(k, false), escape_label_value(v)))
+                .collect();
+            text += &format!("{{{}}}", labels.join(","));
+        }
+        let value = if value.is_nan() {
+            "NaN".to_string()
+        } else if value.is_infinite() {
+            if *value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+        } else {
+            value.to_string()
+        };
+        text += &format!(" {} {}\n", value, metric.when.timestamp_millis());
+    }
+    text
+}
+
+/// decodes a percent-encoded query string component
+fn percent_decode(s: &str) -> String {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                },
+                _ => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+pub async fn handle<D: Database + 'static>(db: D, req: Request<Body>) -> Response<Body> {
+    let prefix = req.uri().query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("prefix="))
+        .map(percent_decode)
+        .unwrap_or_default();
+    // reading takes the database lock, so keep it off the async workers
+    let rendered = tokio::task::spawn_blocking(move || {
+        db.latest_values(&prefix.as_str().into()).map(|metrics| render(&metrics))
+    }).await;
+    match rendered {
+        Ok(Ok(text)) => {
+            let mut response = respond(StatusCode::OK, text);
+            response.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static("text/plain; version=0.0.4"));
+            response
+        },
+        Ok(Err(e)) => {
+            warn!("Unable to read latest values for scraping: {}", e);
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "trouble accessing database")
+        },
+        Err(e) => {
+            warn!("Scrape task failed: {}", e);
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "trouble accessing database")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use chrono::prelude::*;
+
+    use super::*;
+    use crate::dal::Labels;
+
+    #[test]
+    fn render_metrics() {
+        let when = Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::milliseconds(453);
+        let mut labels = Labels::new();
+        labels.insert(Cow::Borrowed("host.name"), Cow::Borrowed("say \"aura\""));
+        let metrics = vec!(
+            Metric{
+                name: Cow::Borrowed("hosts.cpu_load"),
+                labels: Labels::new(),
+                when: Cow::Owned(when),
+                value: MetricValue::Double(0.5),
+            },
+            Metric{
+                name: Cow::Borrowed("hosts.state"),
+                labels: Labels::new(),
+                when: Cow::Owned(when),
+                value: MetricValue::String(Cow::Borrowed("ok")),
+            },
+            Metric{
+                name: Cow::Borrowed("hosts.cpu_load"),
+                labels,
+                when: Cow::Owned(when),
+                value: MetricValue::Double(f64::INFINITY),
+            },
+            Metric{
+                name: Cow::Borrowed("9lives"),
+                labels: Labels::new(),
+                when: Cow::Owned(when),
+                value: MetricValue::Double(9.0),
+            },
+        );
+        assert_eq!(render(&metrics), "\
+            # TYPE _9lives untyped\n\
+            _9lives 9 1516991409453\n\
+            # TYPE hosts_cpu_load untyped\n\
+            hosts_cpu_load 0.5 1516991409453\n\
+            hosts_cpu_load{host_name=\"say \\\"aura\\\"\"} +Inf 1516991409453\n\
+        ");
+    }
+
+    #[test]
+    fn decode_query_parameter() {
+        assert_eq!(percent_decode("hosts.aura%2Ecpu+load"), "hosts.aura.cpu load");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%e2%98%83"), "%zz\u{2603}");
+    }
+}
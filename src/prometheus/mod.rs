@@ -0,0 +1,56 @@
+//! An HTTP endpoint for Prometheus to talk to oc-metrics with, running alongside
+//! the gRPC service. Prometheus servers and agents can push samples here with
+//! remote_write, and scrape the latest value of every series from `/metrics`.
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use hyper::{
+    Body,
+    Method,
+    Request,
+    Response,
+    StatusCode,
+    service::{make_service_fn, service_fn},
+};
+
+use crate::dal::{
+    ConflictPolicies,
+    Database,
+};
+
+pub mod exposition;
+pub mod remote_write;
+
+/// serves the Prometheus endpoints on `addr` until the server fails, settling conflicting
+/// samples by `conflicts`
+pub async fn serve<D: Database + Clone + 'static>(addr: SocketAddr, db: D, conflicts: ConflictPolicies)
+    -> Result<(), hyper::Error> {
+    let conflicts = Arc::new(conflicts);
+    let make_service = make_service_fn(move |_| {
+        let db = db.clone();
+        let conflicts = conflicts.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| route(db.clone(), conflicts.clone(), req)))
+        }
+    });
+    hyper::Server::bind(&addr).serve(make_service).await
+}
+
+async fn route<D: Database + 'static>(db: D, conflicts: Arc<ConflictPolicies>, req: Request<Body>)
+    -> Result<Response<Body>, Infallible> {
+    Ok(match (req.method(), req.uri().path()) {
+        (&Method::POST, "/api/v1/write") => remote_write::handle(db, conflicts, req).await,
+        (&Method::GET, "/metrics") => exposition::handle(db, req).await,
+        _ => respond(StatusCode::NOT_FOUND, "not found"),
+    })
+}
+
+/// builds a plain text response
+fn respond(status: StatusCode, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+}
@@ -0,0 +1,281 @@
+//! Receives Prometheus remote_write requests: snappy-compressed protobuf
+//! WriteRequests, POSTed to `/api/v1/write`. The `__name__` label becomes the
+//! name of the metric, and the rest of the labels are kept as labels. A request
+//! with a series that has no name, or a sample at a time that cannot be
+//! represented, is turned away whole. NaN samples, which Prometheus sends for
+//! stale series and for summaries with nothing observed, are dropped.
+use std::{
+    borrow::Cow,
+    sync::Arc,
+};
+
+use chrono::prelude::*;
+use hyper::{
+    body::HttpBody,
+    Body,
+    Request,
+    Response,
+    StatusCode,
+};
+use log::{debug, warn};
+use prost::Message;
+
+use crate::dal::{
+    ConflictPolicies,
+    Database,
+    Labels,
+    Metric,
+    MetricValue,
+    WriteMode,
+    Written,
+};
+use super::respond;
+
+/// the value Prometheus uses to mark a series as stale; it says a series went away,
+/// not that it was measured, so it is not recorded or counted as dropped
+const STALE_NAN: u64 = 0x7ff0_0000_0000_0002;
+
+/// the largest compressed request accepted
+const MAX_COMPRESSED_BYTES: usize = 16 * 1024 * 1024;
+
+/// the largest request accepted once decompressed
+const MAX_DECOMPRESSED_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct WriteRequest {
+    #[prost(message, repeated, tag="1")]
+    pub timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct TimeSeries {
+    #[prost(message, repeated, tag="1")]
+    pub labels: Vec<Label>,
+    #[prost(message, repeated, tag="2")]
+    pub samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Label {
+    #[prost(string, tag="1")]
+    pub name: String,
+    #[prost(string, tag="2")]
+    pub value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Sample {
+    #[prost(double, tag="1")]
+    pub value: f64,
+    /// milliseconds since the unix epoch
+    #[prost(int64, tag="2")]
+    pub timestamp: i64,
+}
+
+/// converts every sample in the request into a metric, along with how many NaN samples
+/// were dropped, or explains why the request is invalid
+pub fn to_metrics(request: &WriteRequest) -> Result<(Vec<Metric<'_>>, usize), String> {
+    let mut metrics = vec!();
+    let mut dropped = 0;
+    for series in &request.timeseries {
+        let mut name = "";
+        let mut labels = Labels::new();
+        for label in &series.labels {
+            if label.name == "__name__" {
+                name = &label.value;
+            } else {
+                labels.insert(Cow::Borrowed(label.name.as_str()), Cow::Borrowed(label.value.as_str()));
+            }
+        }
+        if name.is_empty() {
+            return Err(format!("series with labels {:?} has no __name__", labels));
+        }
+        for sample in &series.samples {
+            if sample.value.to_bits() == STALE_NAN {
+                continue;
+            }
+            if sample.value.is_nan() {
+                dropped += 1;
+                continue;
+            }
+            let when = Utc.timestamp_millis_opt(sample.timestamp)
+                .single()
+                .ok_or_else(|| format!("sample of {} at {}ms is out of range", name, sample.timestamp))?;
+            metrics.push(Metric{
+                name: Cow::Borrowed(name),
+                labels: labels.clone(),
+                when: Cow::Owned(when),
+                value: MetricValue::Double(sample.value),
+            });
+        }
+    }
+    Ok((metrics, dropped))
+}
+
+/// reads a body of at most `limit` bytes, without buffering any more than that
+async fn read_body(mut body: Body, limit: usize) -> Result<Vec<u8>, Response<Body>> {
+    let mut read = vec!();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| respond(StatusCode::BAD_REQUEST, format!("unable to read request: {}", e)))?;
+        if read.len() + chunk.len() > limit {
+            return Err(respond(StatusCode::PAYLOAD_TOO_LARGE, format!("request is larger than {} bytes", limit)));
+        }
+        read.extend_from_slice(&chunk);
+    }
+    Ok(read)
+}
+
+pub async fn handle<D: Database + 'static>(db: D, conflicts: Arc<ConflictPolicies>, req: Request<Body>) -> Response<Body> {
+    let body = match read_body(req.into_body(), MAX_COMPRESSED_BYTES).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    // snappy puts the decompressed length up front, so check it before allocating for it
+    match snap::raw::decompress_len(&body) {
+        Ok(len) if len > MAX_DECOMPRESSED_BYTES => return respond(StatusCode::PAYLOAD_TOO_LARGE,
+            format!("request is larger than {} bytes once decompressed", MAX_DECOMPRESSED_BYTES)),
+        Ok(_) => (),
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("request is not snappy compressed: {}", e)),
+    }
+    let body = match snap::raw::Decoder::new().decompress_vec(&body) {
+        Ok(body) => body,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("request is not snappy compressed: {}", e)),
+    };
+    let request = match WriteRequest::decode(body.as_slice()) {
+        Ok(request) => request,
+        Err(e) => return respond(StatusCode::BAD_REQUEST, format!("request is not a WriteRequest: {}", e)),
+    };
+    // writing takes the database lock, so keep it off the async workers
+    let written = tokio::task::spawn_blocking(move || {
+        let (metrics, dropped) = to_metrics(&request)?;
+        if dropped > 0 {
+            debug!("Dropped {} NaN samples from remote_write", dropped);
+        }
+        Ok::<_, String>(db.write_metrics_with(&metrics, WriteMode::Partial, &conflicts))
+    }).await;
+    match written {
+        Ok(Ok(Ok(Written{failures, ..}))) => {
+            // Prometheus resends samples it is unsure about, so duplicates are expected
+            if let Some((_, e)) = failures.first() {
+                warn!("Unable to record {} samples from remote_write; first error: {}", failures.len(), e);
+            }
+            respond(StatusCode::NO_CONTENT, "")
+        },
+        Ok(Err(e)) => respond(StatusCode::BAD_REQUEST, e),
+        Ok(Ok(Err(e))) => {
+            warn!("Unable to record remote_write request: {}", e);
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "trouble accessing database")
+        },
+        Err(e) => {
+            warn!("remote_write task failed: {}", e);
+            respond(StatusCode::INTERNAL_SERVER_ERROR, "trouble accessing database")
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dal::{
+        Selector,
+        TimeRange,
+        memory::MemoryDatabase,
+        sqlite::SqliteDatabase,
+    };
+
+    #[test]
+    fn convert_write_request() {
+        let label = |name: &str, value: &str| Label{name: name.into(), value: value.into()};
+        let request = WriteRequest{
+            timeseries: vec!(TimeSeries{
+                labels: vec!(label("__name__", "node_load1"), label("instance", "aura:9100")),
+                samples: vec!(
+                    Sample{value: 0.5, timestamp: 1_516_991_409_453},
+                    Sample{value: f64::from_bits(STALE_NAN), timestamp: 1_516_991_424_453},
+                    Sample{value: f64::NAN, timestamp: 1_516_991_439_453},
+                ),
+            }),
+        };
+        let mut labels = Labels::new();
+        labels.insert(Cow::Borrowed("instance"), Cow::Borrowed("aura:9100"));
+        assert_eq!(to_metrics(&request), Ok((vec!(Metric{
+            name: Cow::Borrowed("node_load1"),
+            labels,
+            when: Cow::Owned(Utc.with_ymd_and_hms(2018, 1, 26, 18, 30, 9).unwrap() + chrono::Duration::milliseconds(453)),
+            value: MetricValue::Double(0.5),
+        }), 1)));
+
+        let unnamed = WriteRequest{
+            timeseries: vec!(TimeSeries{
+                labels: vec!(label("instance", "aura:9100")),
+                samples: vec!(Sample{value: 0.5, timestamp: 1_516_991_409_453}),
+            }),
+        };
+        assert!(to_metrics(&unnamed).is_err());
+        let out_of_range = WriteRequest{
+            timeseries: vec!(TimeSeries{
+                labels: vec!(label("__name__", "node_load1")),
+                samples: vec!(Sample{value: 0.5, timestamp: i64::MIN}),
+            }),
+        };
+        assert!(to_metrics(&out_of_range).is_err());
+    }
+
+    fn compress(request: &WriteRequest) -> Vec<u8> {
+        let mut encoded = vec!();
+        request.encode(&mut encoded).unwrap();
+        snap::raw::Encoder::new().compress_vec(&encoded).unwrap()
+    }
+
+    #[tokio::test]
+    async fn drop_nan_samples() {
+        let db = SqliteDatabase::new(":memory:").unwrap();
+        db.setup().unwrap();
+        let request = WriteRequest{
+            timeseries: vec!(TimeSeries{
+                labels: vec!(Label{name: "__name__".into(), value: "rpc_duration_seconds".into()}),
+                samples: vec!(
+                    Sample{value: f64::NAN, timestamp: 1_516_991_409_453},
+                    Sample{value: 0.25, timestamp: 1_516_991_424_453},
+                ),
+            }),
+        };
+        let post = Request::post("/api/v1/write").body(Body::from(compress(&request))).unwrap();
+        let response = handle(db.clone(), Arc::new(ConflictPolicies::default()), post).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // the series still reads back, holding only the sample that is a number
+        let selector = Selector::from("rpc_duration_seconds");
+        let page = db.read_metrics(&selector, &TimeRange::default(), 10, &[]).unwrap();
+        assert_eq!(page.metrics.iter().map(|m| m.value.clone()).collect::<Vec<_>>(), vec!(MetricValue::Double(0.25)));
+        assert_eq!(db.latest_values(&selector).unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_requests() {
+        let post = |body: Vec<u8>| Request::post("/api/v1/write").body(Body::from(body)).unwrap();
+        let conflicts = Arc::new(ConflictPolicies::default());
+        let response = handle(MemoryDatabase::default(), conflicts.clone(), post(vec!(0; MAX_COMPRESSED_BYTES + 1))).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // a few bytes of snappy claiming to decompress to far more than is allowed
+        let mut bomb = vec!();
+        let mut len = MAX_DECOMPRESSED_BYTES as u64 + 1;
+        while len >= 0x80 {
+            bomb.push(len as u8 | 0x80);
+            len >>= 7;
+        }
+        bomb.push(len as u8);
+        let response = handle(MemoryDatabase::default(), conflicts.clone(), post(bomb)).await;
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let request = WriteRequest{
+            timeseries: vec!(TimeSeries{
+                labels: vec!(Label{name: "job".into(), value: "node".into()}),
+                samples: vec!(Sample{value: 0.5, timestamp: 1_516_991_409_453}),
+            }),
+        };
+        let response = handle(MemoryDatabase::default(), conflicts, post(compress(&request))).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,625 @@
+//! Rate limiting holds each client of RecordMetrics and StreamMetrics to quotas on the points it
+//! records per second, the new series it creates per hour and the bytes it sends in one request.
+//! Each quota applies to a name prefix, and is configured with a string such as
+//! `=5000,hosts.=1000,debug.=unlimited`, where an empty prefix sets the quota for every
+//! other metric; a metric falls under the longest prefix it matches. Every message of a stream
+//! is charged as it arrives, like a request of its own, and one over a quota fails the stream.
+//!
+//! Clients are told apart by the principal an authenticating layer in front of this one
+//! attached to the request, or else by their IP address.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    future::Future,
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use hyper::{body::HttpBody, Body, Request, Response};
+use log::debug;
+use prost::Message;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{
+    body::BoxBody,
+    metadata::MetadataMap,
+    transport::NamedService,
+    Code,
+    Status,
+};
+use tower::{Layer, Service};
+
+use crate::{
+    dal::{series_key, Labels},
+    server::proto::{Metric, RecordMetricsRequest, StreamMetricsRequest},
+};
+
+/// the methods quotas apply to
+const RECORD_METRICS_PATH: &str = "/metrics_service.MetricsService/RecordMetrics";
+const STREAM_METRICS_PATH: &str = "/metrics_service.MetricsService/StreamMetrics";
+
+/// the largest message read from a client held to quotas, as only one is buffered at a time
+const MAX_MESSAGE_BYTES: usize = 16 * 1024 * 1024;
+
+/// how long a series written by a client counts as known to it
+const SERIES_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// how often clients that have gone quiet are forgotten
+const PRUNE_PERIOD: Duration = Duration::from_secs(60);
+
+/// the identity of a client, attached to requests by an authenticating layer in front of the
+/// rate limiter; without one, clients are told apart by their IP address
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Principal(pub String);
+
+/// the address of the client a request came from, attached for each connection by the server;
+/// tonic keeps its own copy where only a tonic::Request can read it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerAddr(pub SocketAddr);
+
+/// a limit for each name prefix, where None means unlimited
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixLimits {
+    pub default: Option<u64>,
+    pub prefixes: Vec<(String, Option<u64>)>,
+}
+
+impl PrefixLimits {
+    /// the prefix a metric's limit comes from, empty for the default, and the limit itself
+    pub fn limit_for(&self, name: &str) -> (&str, Option<u64>) {
+        self.prefixes.iter()
+            .filter(|(prefix, _)| name.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(prefix, limit)| (prefix.as_str(), *limit))
+            .unwrap_or(("", self.default))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.default.is_none() && self.prefixes.iter().all(|(_, limit)| limit.is_none())
+    }
+}
+
+impl FromStr for PrefixLimits {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut limits = PrefixLimits::default();
+        for entry in s.split(',').filter(|entry| !entry.trim().is_empty()) {
+            let mut parts = entry.splitn(2, '=');
+            let prefix = parts.next().unwrap_or_default().trim();
+            let limit = match parts.next().map(str::trim) {
+                Some("unlimited") => None,
+                Some(limit) => Some(limit.parse().map_err(|_| format!("limit '{}' should be a number or unlimited", limit))?),
+                None => return Err(format!("limit '{}' should look like prefix=limit", entry)),
+            };
+            if prefix.is_empty() {
+                limits.default = limit;
+            } else {
+                limits.prefixes.push((prefix.to_string(), limit));
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// the quotas each client of RecordMetrics is held to
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quotas {
+    /// points recorded per second
+    pub points: PrefixLimits,
+    /// series that are new to the client per hour
+    pub series: PrefixLimits,
+    /// bytes of metrics in one request
+    pub request_bytes: PrefixLimits,
+}
+
+impl Quotas {
+    fn is_empty(&self) -> bool {
+        self.points.is_empty() && self.series.is_empty() && self.request_bytes.is_empty()
+    }
+}
+
+/// a request the client has to hold back, and how long for if waiting would help
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    pub message: String,
+    pub retry_after: Option<Duration>,
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        let mut metadata = MetadataMap::new();
+        if let Some(retry_after) = rejection.retry_after {
+            // whole seconds, rounded up, as in an HTTP Retry-After header
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            metadata.insert("retry-after", seconds.into());
+        }
+        Status::with_metadata(Code::ResourceExhausted, rejection.message, metadata)
+    }
+}
+
+/// a token bucket holding up to `capacity` tokens, refilled at `rate` tokens a second
+#[derive(Debug, Clone)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(capacity: f64, now: Instant) -> Self {
+        Bucket{tokens: capacity, updated: now}
+    }
+
+    fn refill(&mut self, capacity: f64, rate: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(capacity);
+        self.updated = now;
+    }
+
+    /// how long until the bucket holds `tokens`
+    fn wait_for(&self, tokens: f64, rate: f64) -> Duration {
+        Duration::from_secs_f64(((tokens - self.tokens) / rate).max(0.0))
+    }
+}
+
+#[derive(Debug)]
+struct Client {
+    points: HashMap<String, Bucket>,
+    series: HashMap<String, Bucket>,
+    /// hashes of the series key of each series the client wrote, and when it last did
+    seen: HashMap<u64, Instant>,
+    last_request: Instant,
+}
+
+#[derive(Debug)]
+struct State {
+    clients: HashMap<String, Client>,
+    pruned: Instant,
+}
+
+/// the quotas, and the buckets of every client they are kept for
+#[derive(Debug)]
+pub struct RateLimiter {
+    quotas: Quotas,
+    state: Mutex<State>,
+}
+
+/// what a request asks of each prefix's quota: the quota, and how much it takes from it
+type Demands<'a> = HashMap<&'a str, (Option<u64>, u64)>;
+
+impl RateLimiter {
+    pub fn new(quotas: Quotas) -> Self {
+        RateLimiter{
+            quotas,
+            state: Mutex::new(State{clients: HashMap::new(), pruned: Instant::now()}),
+        }
+    }
+
+    /// takes what a request's metrics need from the client's buckets, or explains why it has to wait
+    pub fn admit(&self, client: &str, metrics: &[Metric], now: Instant) -> Result<(), Rejection> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now.saturating_duration_since(state.pruned) >= PRUNE_PERIOD {
+            state.clients.retain(|_, client| now.saturating_duration_since(client.last_request) < SERIES_WINDOW);
+            for client in state.clients.values_mut() {
+                client.seen.retain(|_, written| now.saturating_duration_since(*written) < SERIES_WINDOW);
+            }
+            state.pruned = now;
+        }
+        let client = state.clients.entry(client.to_string()).or_insert_with(|| Client{
+            points: HashMap::new(),
+            series: HashMap::new(),
+            seen: HashMap::new(),
+            last_request: now,
+        });
+        client.last_request = now;
+
+        let (mut points, mut bytes, mut series) = (Demands::new(), Demands::new(), Demands::new());
+        let mut written = HashSet::with_capacity(metrics.len());
+        for metric in metrics {
+            let (prefix, limit) = self.quotas.points.limit_for(&metric.identifier);
+            points.entry(prefix).or_insert((limit, 0)).1 += 1;
+            let (prefix, limit) = self.quotas.request_bytes.limit_for(&metric.identifier);
+            bytes.entry(prefix).or_insert((limit, 0)).1 += metric.encoded_len() as u64;
+            let labels: Labels = metric.labels.iter().map(|(k, v)| (k.as_str().into(), v.as_str().into())).collect();
+            let mut hasher = DefaultHasher::new();
+            series_key(&metric.identifier, &labels).hash(&mut hasher);
+            let hash = hasher.finish();
+            let known = client.seen.get(&hash).is_some_and(|at| now.saturating_duration_since(*at) < SERIES_WINDOW);
+            if written.insert(hash) && !known {
+                let (prefix, limit) = self.quotas.series.limit_for(&metric.identifier);
+                series.entry(prefix).or_insert((limit, 0)).1 += 1;
+            }
+        }
+
+        for (prefix, (limit, taken)) in &bytes {
+            if let Some(limit) = limit {
+                if taken > limit {
+                    return Err(Rejection{
+                        message: format!("metrics under '{}' take {} bytes, more than the {} allowed in one request",
+                            prefix, taken, limit),
+                        retry_after: None,
+                    });
+                }
+            }
+        }
+        // every bucket has to have room before any is taken from, so a rejected request costs nothing
+        let per_second = Duration::from_secs(1);
+        let wait = check(&points, &mut client.points, per_second, "points a second", now)?
+            .max(check(&series, &mut client.series, SERIES_WINDOW, "new series an hour", now)?);
+        if wait > Duration::from_secs(0) {
+            return Err(Rejection{
+                message: "too many metrics have been recorded recently".to_string(),
+                retry_after: Some(wait),
+            });
+        }
+        take(&points, &mut client.points);
+        take(&series, &mut client.series);
+        client.seen.extend(written.into_iter().map(|hash| (hash, now)));
+        Ok(())
+    }
+}
+
+/// refills the buckets a request draws on, whose quotas are for each `period`, and works out
+/// how long until all of them have room for it
+fn check(demands: &Demands, buckets: &mut HashMap<String, Bucket>, period: Duration, unit: &str, now: Instant)
+    -> Result<Duration, Rejection> {
+    let mut wait = Duration::from_secs(0);
+    for (prefix, (limit, taken)) in demands {
+        let limit = match limit {
+            Some(limit) => *limit,
+            None => continue,
+        };
+        if *taken > limit {
+            return Err(Rejection{
+                message: format!("{} metrics under '{}' are more than the {} {} allowed", taken, prefix, limit, unit),
+                retry_after: None,
+            });
+        }
+        let (capacity, rate) = (limit as f64, limit as f64 / period.as_secs_f64());
+        let bucket = buckets.entry(prefix.to_string()).or_insert_with(|| Bucket::full(capacity, now));
+        bucket.refill(capacity, rate, now);
+        wait = wait.max(bucket.wait_for(*taken as f64, rate));
+    }
+    Ok(wait)
+}
+
+fn take(demands: &Demands, buckets: &mut HashMap<String, Bucket>) {
+    for (prefix, (limit, taken)) in demands {
+        if limit.is_some() {
+            if let Some(bucket) = buckets.get_mut(*prefix) {
+                bucket.tokens -= *taken as f64;
+            }
+        }
+    }
+}
+
+/// applies a RateLimiter to the RecordMetrics and StreamMetrics requests of the service it wraps
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(quotas: Quotas) -> Self {
+        RateLimitLayer{limiter: Arc::new(RateLimiter::new(quotas))}
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit{inner, limiter: self.limiter.clone()}
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S: NamedService> NamedService for RateLimit<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let streaming = match req.uri().path() {
+            _ if self.limiter.quotas.is_empty() => return Box::pin(self.inner.call(req)),
+            RECORD_METRICS_PATH => false,
+            STREAM_METRICS_PATH => true,
+            _ => return Box::pin(self.inner.call(req)),
+        };
+        // the inner service poll_ready was called on has to be the one called, so a clone takes its place
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+        let client = client_identity(&req);
+        let (parts, body) = req.into_parts();
+        let mut messages = Messages{body, buf: vec!()};
+        if streaming {
+            // each message is charged as it arrives, and the service reads the error of the first
+            // one that is not admitted as the stream failing
+            let (sender, receiver) = mpsc::channel(1);
+            tokio::spawn(async move {
+                loop {
+                    let message = match messages.next().await {
+                        Ok(Some(message)) => charge::<StreamMetricsRequest>(&limiter, &client, &message).map(|_| message),
+                        Ok(None) => break,
+                        Err(status) => Err(status),
+                    };
+                    let failed = message.is_err();
+                    // the service hanging up on the stream leaves nothing more to do
+                    if sender.send(message).await.is_err() || failed {
+                        break;
+                    }
+                }
+            });
+            let body = Body::wrap_stream(ReceiverStream::new(receiver));
+            return Box::pin(inner.call(Request::from_parts(parts, body)));
+        }
+        Box::pin(async move {
+            let admitted = async {
+                let message = messages.next().await?.ok_or_else(|| Status::invalid_argument("missing request message"))?;
+                charge::<RecordMetricsRequest>(&limiter, &client, &message)?;
+                Ok::<_, Status>(message)
+            };
+            match admitted.await {
+                Ok(message) => inner.call(Request::from_parts(parts, Body::from(message))).await,
+                Err(status) => Ok(status.to_http()),
+            }
+        })
+    }
+}
+
+/// the messages whose metrics are charged to quotas
+trait Charged: Message + Default {
+    fn metrics(&self) -> &[Metric];
+}
+
+impl Charged for RecordMetricsRequest {
+    fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+}
+
+impl Charged for StreamMetricsRequest {
+    fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+}
+
+/// decodes a message read by Messages and charges its metrics to the client's quotas
+#[allow(clippy::result_large_err)]
+fn charge<M: Charged>(limiter: &RateLimiter, client: &str, message: &[u8]) -> Result<(), Status> {
+    let request = M::decode(&message[5..])
+        .map_err(|e| Status::invalid_argument(format!("unable to decode request: {}", e)))?;
+    limiter.admit(client, request.metrics(), Instant::now()).map_err(|rejection| {
+        debug!("Holding back {} metrics from {}: {}", request.metrics().len(), client, rejection.message);
+        Status::from(rejection)
+    })
+}
+
+/// who a request is from: the principal attached by an authenticating layer, or else the peer's
+/// IP address, from the PeerAddr the server attached
+fn client_identity(req: &Request<Body>) -> String {
+    if let Some(Principal(principal)) = req.extensions().get::<Principal>() {
+        return format!("principal {}", principal);
+    }
+    match req.extensions().get::<PeerAddr>() {
+        Some(PeerAddr(addr)) => format!("address {}", addr.ip()),
+        None => "unknown client".to_string(),
+    }
+}
+
+/// the gRPC messages of a request body, read one at a time so no more than one is ever buffered
+struct Messages {
+    body: Body,
+    buf: Vec<u8>,
+}
+
+impl Messages {
+    /// the next message, along with the five bytes that frame it
+    async fn next(&mut self) -> Result<Option<Vec<u8>>, Status> {
+        loop {
+            if let [compressed, a, b, c, d, ..] = self.buf[..] {
+                // quotas are charged by what the metrics are, so they have to be readable here
+                if compressed != 0 {
+                    return Err(Status::unimplemented("compressed messages are not supported"));
+                }
+                let length = u32::from_be_bytes([a, b, c, d]) as usize;
+                if length > MAX_MESSAGE_BYTES {
+                    return Err(Status::resource_exhausted(format!("messages may be at most {} bytes", MAX_MESSAGE_BYTES)));
+                }
+                if self.buf.len() >= 5 + length {
+                    let rest = self.buf.split_off(5 + length);
+                    return Ok(Some(std::mem::replace(&mut self.buf, rest)));
+                }
+            }
+            match self.body.data().await {
+                Some(Ok(chunk)) => self.buf.extend_from_slice(&chunk),
+                Some(Err(e)) => return Err(Status::invalid_argument(format!("unable to read request: {}", e))),
+                None if self.buf.is_empty() => return Ok(None),
+                None => return Err(Status::invalid_argument("request ends partway through a message")),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tower::ServiceExt;
+    use crate::{
+        dal::memory::MemoryDatabase,
+        server::{
+            Server,
+            proto::{Metric, metric::Value, metrics_service_server::MetricsServiceServer},
+        },
+    };
+
+    fn metrics(names: &[&str]) -> Vec<Metric> {
+        names.iter().map(|name| Metric{
+            identifier: name.to_string(),
+            value: Some(Value::DoubleValue(1.0)),
+            ..Default::default()
+        }).collect()
+    }
+
+    /// frames a message as a gRPC request body would
+    fn frame(message: &impl Message) -> Vec<u8> {
+        let mut body = vec!(0);
+        body.extend_from_slice(&(message.encoded_len() as u32).to_be_bytes());
+        message.encode(&mut body).unwrap();
+        body
+    }
+
+    fn grpc_status(response: &Response<BoxBody>) -> Option<&str> {
+        response.headers().get("grpc-status").map(|status| status.to_str().unwrap())
+    }
+
+    #[test]
+    fn parse_limits() {
+        let limits: PrefixLimits = "=100, hosts.=10,hosts.debug.=unlimited".parse().unwrap();
+        assert_eq!(limits.limit_for("myservice.cpu_time"), ("", Some(100)));
+        assert_eq!(limits.limit_for("hosts.cpu_load"), ("hosts.", Some(10)));
+        assert_eq!(limits.limit_for("hosts.debug.cpu_load"), ("hosts.debug.", None));
+        assert_eq!("".parse::<PrefixLimits>().unwrap(), PrefixLimits::default());
+        assert!("hosts.".parse::<PrefixLimits>().is_err());
+        assert!("hosts.=lots".parse::<PrefixLimits>().is_err());
+    }
+
+    #[test]
+    fn points_refill_over_time() {
+        let limiter = RateLimiter::new(Quotas{points: "=4,hosts.=unlimited".parse().unwrap(), ..Default::default()});
+        let start = Instant::now();
+        assert!(limiter.admit("a", &metrics(&["cpu", "mem", "disk"]), start).is_ok());
+        let rejection = limiter.admit("a", &metrics(&["cpu", "mem"]), start).unwrap_err();
+        assert_eq!(rejection.retry_after, Some(Duration::from_millis(250)));
+        // a rejected request takes nothing, and other clients and prefixes have their own buckets
+        assert!(limiter.admit("a", &metrics(&["cpu"]), start).is_ok());
+        assert!(limiter.admit("b", &metrics(&["cpu", "mem", "disk", "net"]), start).is_ok());
+        assert!(limiter.admit("a", &metrics(&["hosts.cpu"; 10]), start).is_ok());
+        assert!(limiter.admit("a", &metrics(&["cpu", "mem"]), start + Duration::from_millis(500)).is_ok());
+
+        let rejection = limiter.admit("c", &metrics(&["cpu"; 5]), start).unwrap_err();
+        assert_eq!(rejection.retry_after, None);
+    }
+
+    #[test]
+    fn only_new_series_count() {
+        let limiter = RateLimiter::new(Quotas{series: "=2".parse().unwrap(), ..Default::default()});
+        let start = Instant::now();
+        assert!(limiter.admit("a", &metrics(&["cpu", "mem", "cpu"]), start).is_ok());
+        assert!(limiter.admit("a", &metrics(&["cpu", "mem"]), start).is_ok());
+        let rejection = limiter.admit("a", &metrics(&["disk"]), start).unwrap_err();
+        assert_eq!(rejection.retry_after, Some(Duration::from_secs(30 * 60)));
+        assert!(limiter.admit("a", &metrics(&["disk"]), start + Duration::from_secs(30 * 60)).is_ok());
+    }
+
+    #[test]
+    fn request_bytes_are_capped() {
+        let limiter = RateLimiter::new(Quotas{request_bytes: "hosts.=40".parse().unwrap(), ..Default::default()});
+        let start = Instant::now();
+        assert!(limiter.admit("a", &metrics(&["hosts.cpu", "hosts.mem"]), start).is_ok());
+        let rejection = limiter.admit("a", &metrics(&["hosts.cpu", "hosts.mem", "hosts.disk"]), start).unwrap_err();
+        assert_eq!(rejection.retry_after, None);
+        assert!(limiter.admit("a", &metrics(&["cpu"; 10]), start).is_ok());
+    }
+
+    #[test]
+    fn identity_leaves_extensions_in_place() {
+        let mut req = Request::new(Body::empty());
+        req.extensions_mut().insert(7u32);
+        assert_eq!(client_identity(&req), "unknown client");
+        req.extensions_mut().insert(PeerAddr(([192, 0, 2, 1], 4000).into()));
+        assert_eq!(client_identity(&req), "address 192.0.2.1");
+        assert_eq!(req.extensions().get::<u32>(), Some(&7));
+    }
+
+    fn layer(quotas: Quotas) -> RateLimit<MetricsServiceServer<Server<MemoryDatabase>>> {
+        RateLimitLayer::new(quotas).layer(MetricsServiceServer::new(Server::<MemoryDatabase>::default()))
+    }
+
+    async fn call(service: &RateLimit<MetricsServiceServer<Server<MemoryDatabase>>>, path: &str, body: Body)
+        -> Response<BoxBody> {
+        let request = Request::post(path)
+            .header("content-type", "application/grpc")
+            .body(body)
+            .unwrap();
+        service.clone().oneshot(request).await.unwrap()
+    }
+
+    fn record(names: &[&str]) -> Body {
+        Body::from(frame(&RecordMetricsRequest{metrics: metrics(names), ..Default::default()}))
+    }
+
+    #[tokio::test]
+    async fn layer_holds_back_record_metrics() {
+        let service = layer(Quotas{points: "=2".parse().unwrap(), ..Default::default()});
+        let response = call(&service, RECORD_METRICS_PATH, record(&["cpu", "mem"])).await;
+        assert_eq!(grpc_status(&response), None);
+        let response = call(&service, RECORD_METRICS_PATH, record(&["cpu"])).await;
+        assert_eq!(grpc_status(&response), Some((Code::ResourceExhausted as i32).to_string().as_str()));
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn layer_charges_each_streamed_message() {
+        let service = layer(Quotas{points: "=3".parse().unwrap(), ..Default::default()});
+        let stream = |batches: &[&[&str]]| {
+            let mut body = vec!();
+            for names in batches {
+                body.extend(frame(&StreamMetricsRequest{metrics: metrics(names), ..Default::default()}));
+            }
+            Body::from(body)
+        };
+        let response = call(&service, STREAM_METRICS_PATH, stream(&[&["cpu"], &["mem"]])).await;
+        assert_eq!(grpc_status(&response), None);
+        let response = call(&service, STREAM_METRICS_PATH, stream(&[&["cpu"], &["mem"]])).await;
+        assert_eq!(grpc_status(&response), Some((Code::ResourceExhausted as i32).to_string().as_str()));
+        assert_eq!(response.headers()["retry-after"], "1");
+    }
+
+    #[tokio::test]
+    async fn layer_turns_away_unreadable_messages() {
+        let service = layer(Quotas{points: "=2".parse().unwrap(), ..Default::default()});
+        let code = |code: Code| Some((code as i32).to_string());
+        let mut compressed = frame(&RecordMetricsRequest{metrics: metrics(&["cpu"; 10]), ..Default::default()});
+        compressed[0] = 1;
+        let response = call(&service, RECORD_METRICS_PATH, Body::from(compressed.clone())).await;
+        assert_eq!(grpc_status(&response).map(String::from), code(Code::Unimplemented));
+        let response = call(&service, STREAM_METRICS_PATH, Body::from(compressed)).await;
+        assert_eq!(grpc_status(&response).map(String::from), code(Code::Unimplemented));
+
+        let garbage = Body::from(vec!(0, 0, 0, 0, 2, 0xff, 0xff));
+        let response = call(&service, RECORD_METRICS_PATH, garbage).await;
+        assert_eq!(grpc_status(&response).map(String::from), code(Code::InvalidArgument));
+        let truncated = Body::from(vec!(0, 0, 0, 0, 2, 0x0a));
+        let response = call(&service, RECORD_METRICS_PATH, truncated).await;
+        assert_eq!(grpc_status(&response).map(String::from), code(Code::InvalidArgument));
+
+        // an oversized message is turned away from its length alone, without waiting for the rest
+        let (mut sender, body) = Body::channel();
+        sender.send_data(vec!(0, 0x7f, 0xff, 0xff, 0xff).into()).await.unwrap();
+        let response = call(&service, RECORD_METRICS_PATH, body).await;
+        assert_eq!(grpc_status(&response).map(String::from), code(Code::ResourceExhausted));
+    }
+}
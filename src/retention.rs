@@ -0,0 +1,126 @@
+//! Retention policies expire old metrics, and their rollups, in the background.
+//! Each policy applies to a name prefix, and is configured with a string such as
+//! `debug.=2d,hosts.=90d`, where an empty prefix sets the policy for every other metric.
+//! A series matching several policies follows the one with the longest prefix, so
+//! `hosts.=90d,hosts.debug.=2d` keeps `hosts.debug.` series for two days and the rest of
+//! `hosts.` for ninety.
+use std::{
+    str::FromStr,
+};
+
+use chrono::prelude::*;
+use log::{info, warn};
+
+use crate::dal::Database;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    pub prefix: String,
+    pub keep: chrono::Duration,
+}
+
+impl FromStr for RetentionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, '=');
+        let prefix = parts.next().unwrap_or_default().trim();
+        let keep = parts.next()
+            .ok_or_else(|| format!("retention policy '{}' should look like prefix=duration", s))?;
+        Ok(RetentionPolicy{
+            prefix: prefix.to_string(),
+            keep: parse_duration(keep.trim())?,
+        })
+    }
+}
+
+/// parses a comma separated list of retention policies; a prefix given more than once
+/// takes the last period given for it
+pub fn parse_policies(s: &str) -> Result<Vec<RetentionPolicy>, String> {
+    let mut policies: Vec<RetentionPolicy> = vec!();
+    for policy in s.split(',').filter(|p| !p.trim().is_empty()) {
+        let policy: RetentionPolicy = policy.parse()?;
+        policies.retain(|p| p.prefix != policy.prefix);
+        policies.push(policy);
+    }
+    Ok(policies)
+}
+
+/// the prefixes of the policies that take over from `policy` for the series under them
+fn nested_prefixes(policies: &[RetentionPolicy], policy: &RetentionPolicy) -> Vec<String> {
+    policies.iter()
+        .filter(|p| p.prefix.len() > policy.prefix.len() && p.prefix.starts_with(&policy.prefix))
+        .map(|p| p.prefix.clone())
+        .collect()
+}
+
+/// parses durations of the form `90s`, `30m`, `12h`, `2d` or `1w`
+pub fn parse_duration(s: &str) -> Result<chrono::Duration, String> {
+    let unit_at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let count: i64 = s[..unit_at].parse()
+        .map_err(|_| format!("duration '{}' should start with a number", s))?;
+    let duration = match &s[unit_at..] {
+        "s" => chrono::Duration::try_seconds(count),
+        "m" => chrono::Duration::try_minutes(count),
+        "h" => chrono::Duration::try_hours(count),
+        "d" => chrono::Duration::try_days(count),
+        "w" => chrono::Duration::try_weeks(count),
+        unit => return Err(format!("duration '{}' has unknown unit '{}'", s, unit)),
+    };
+    duration.ok_or_else(|| format!("duration '{}' is too long", s))
+}
+
+/// enforces the retention policies every `period` forever; run it with tokio::spawn
+pub async fn enforce<D: Database + Clone + 'static>(db: D, policies: Vec<RetentionPolicy>, period: std::time::Duration) {
+    let nested: Vec<_> = policies.iter().map(|policy| nested_prefixes(&policies, policy)).collect();
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        for (policy, nested) in policies.iter().zip(&nested) {
+            let db = db.clone();
+            let (prefix, nested) = (policy.prefix.clone(), nested.clone());
+            let before = Utc::now() - policy.keep;
+            // deleting takes the database lock, so keep it off the async workers
+            match tokio::task::spawn_blocking(move || db.delete_metrics_except(&prefix, &nested, &before)).await {
+                Ok(Ok(0)) => (),
+                Ok(Ok(deleted)) => info!("Expired {} metrics matching '{}'", deleted, policy.prefix),
+                Ok(Err(e)) => warn!("Unable to expire metrics matching '{}': {}", policy.prefix, e),
+                Err(e) => warn!("Retention task for '{}' failed: {}", policy.prefix, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_retention_policies() {
+        assert_eq!(parse_policies("debug.=2d, hosts.=90d").unwrap(), vec!(
+            RetentionPolicy{prefix: "debug.".into(), keep: chrono::Duration::days(2)},
+            RetentionPolicy{prefix: "hosts.".into(), keep: chrono::Duration::days(90)},
+        ));
+        assert_eq!(parse_policies("").unwrap(), vec!());
+        assert!(parse_policies("debug.").is_err());
+        assert!(parse_policies("debug.=2y").is_err());
+        assert!(parse_policies("debug.=d").is_err());
+        assert!(parse_policies("debug.=99999999999999d").is_err());
+        assert!(parse_policies("debug.=9999999999999999s").is_err());
+        assert_eq!(parse_policies("debug.=2d,debug.=1w").unwrap(), vec!(
+            RetentionPolicy{prefix: "debug.".into(), keep: chrono::Duration::weeks(1)},
+        ));
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let policies = parse_policies("=30d,hosts.=90d,hosts.debug.=2d,debug.=1d").unwrap();
+        let nested = |prefix: &str| {
+            let policy = policies.iter().find(|p| p.prefix == prefix).unwrap();
+            nested_prefixes(&policies, policy)
+        };
+        assert_eq!(nested(""), vec!("hosts.", "hosts.debug.", "debug."));
+        assert_eq!(nested("hosts."), vec!("hosts.debug."));
+        assert_eq!(nested("hosts.debug."), Vec::<String>::new());
+    }
+}
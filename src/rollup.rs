@@ -0,0 +1,26 @@
+//! Rollups keep long-range history cheap to query. Raw metrics are periodically
+//! aggregated into 1-minute, 1-hour and 1-day buckets, which read_aggregated uses
+//! in place of raw metrics whenever the requested buckets line up with them.
+//! A bucket is only rolled up once it is older than the lateness window, so points
+//! that arrive a little late make it in the first time; points written to or deleted
+//! from a bucket after it has been rolled up leave it to be rolled up again on the
+//! next run.
+use chrono::prelude::*;
+use log::{warn};
+
+use crate::dal::Database;
+
+/// rolls up metrics older than `lateness` every `period` forever; run it with tokio::spawn
+pub async fn run<D: Database + Clone + 'static>(db: D, period: std::time::Duration, lateness: chrono::Duration) {
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let db = db.clone();
+        // rolling up takes the database lock, so keep it off the async workers
+        match tokio::task::spawn_blocking(move || db.roll_up(&(Utc::now() - lateness))).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("Unable to roll up metrics: {}", e),
+            Err(e) => warn!("Rollup task failed: {}", e),
+        }
+    }
+}
@@ -1,18 +1,46 @@
 use std::{
     borrow::Cow,
-    collections::HashMap,
-    time::{UNIX_EPOCH, Duration},
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
 };
 
 use log::{warn};
 use chrono::prelude::*;
+use prost::Message;
+use regex::Regex;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
 
-use tonic::{Request, Response, Status};
+use tonic::{Code, Request, Response, Status};
+use crate::hub::{
+    Hub,
+    Received,
+    Subscription,
+};
 use crate::dal::{
+    Aggregator,
+    Bounds,
+    ConflictPolicies,
+    ConflictPolicy,
     Database,
     DatabaseError,
+    LabelMatcher,
+    MatchOp,
     Metric,
+    NamePattern,
     MetricValue,
+    ReadResult,
+    Selector,
+    TimeRange,
+    WriteMode,
+    Written,
+    ROLLUP_RESOLUTIONS,
+    series_key,
 };
 
 pub mod proto {
@@ -24,12 +52,28 @@ pub mod proto {
 use proto::{
     RecordMetricsResponse,
     RecordMetricsRequest,
+    StreamMetricsResponse,
+    StreamMetricsRequest,
     LoadMetricsResponse,
     LoadMetricsRequest,
     ListMetricsResponse,
     ListMetricsRequest,
+    DeleteMetricsResponse,
+    DeleteMetricsRequest,
+    WatchMetricsResponse,
+    WatchMetricsRequest,
+    GetLatestResponse,
+    GetLatestRequest,
+    BrowseMetricsResponse,
+    BrowseMetricsRequest,
+    browse_metrics_response::Node as BrowseNode,
     metrics_service_server::MetricsService,
     list_metrics_response::ListMetric,
+    label_matcher::Op as ProtoMatchOp,
+    load_metrics_request::Aggregator as ProtoAggregator,
+    time_range::Bounds as ProtoBounds,
+    record_metrics_response::MetricError,
+    ConflictPolicy as ProtoConflictPolicy,
     metric::Value as ProtoValue,
     compressed_metric::{
         time_value::Value as CompressedValue,
@@ -38,115 +82,1243 @@ use proto::{
     
 };
 
-#[derive(Debug, Default)]
+/// how many streamed metrics are committed together, unless configured otherwise
+pub const DEFAULT_TRANSACTION_SIZE: usize = 1000;
+
+/// how long a RecordMetrics batch ID is remembered, unless configured otherwise
+pub const DEFAULT_BATCH_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// the grammar metric identifiers follow, unless configured otherwise
+pub const DEFAULT_NAME_PATTERN: &str = "[A-Za-z0-9_.:/-]+";
+
+/// the longest metric identifier, in bytes, unless configured otherwise
+pub const DEFAULT_MAX_NAME_LENGTH: usize = 256;
+
+/// the longest string value, in bytes, unless configured otherwise
+pub const DEFAULT_MAX_STRING_LENGTH: usize = 64 * 1024;
+
+/// how long clients are asked to wait before retrying a request the database could not serve
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// the ErrorInfo domain of errors raised by this service
+const ERROR_DOMAIN: &str = "metrics_service";
+
+/// the widest bucket LoadMetrics aggregates into, in seconds; bucket starts stay within the
+/// times chrono can represent
+const MAX_BUCKET_WIDTH: i64 = 100 * 366 * 24 * 60 * 60;
+
+/// how many responses may wait on a slow WatchMetrics client
+const WATCH_BUFFER: usize = 16;
+
+/// the most metrics sent in one WatchMetrics response
+const WATCH_BATCH: usize = 500;
+
+#[derive(Debug)]
 pub struct Server<D: Database> {
-    db: D
+    db: D,
+    hub: Hub,
+    transaction_size: usize,
+    conflicts: ConflictPolicies,
+    batch_ttl: Duration,
+    rules: IngestRules,
+}
+
+/// what happens to NaN and infinite doubles given to RecordMetrics and StreamMetrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NanPolicy {
+    /// the metric is rejected
+    #[default]
+    Reject,
+    /// the metric is recorded as it is
+    Allow,
+}
+
+impl FromStr for NanPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(NanPolicy::Reject),
+            "allow" => Ok(NanPolicy::Allow),
+            _ => Err(format!("unknown NaN policy {:?}", s)),
+        }
+    }
+}
+
+/// the rules metrics given to RecordMetrics and StreamMetrics must follow to be recorded
+#[derive(Debug, Clone)]
+pub struct IngestRules {
+    /// the pattern identifiers must match as a whole
+    pub name_pattern: Regex,
+    /// the longest identifier, in bytes
+    pub max_name_length: usize,
+    /// the longest string value, in bytes
+    pub max_string_length: usize,
+    /// how far before now a metric may be timestamped, if there is a limit
+    pub max_age: Option<chrono::Duration>,
+    /// how far after now a metric may be timestamped, if there is a limit
+    pub max_ahead: Option<chrono::Duration>,
+    pub nan: NanPolicy,
+}
+
+impl IngestRules {
+    /// compiles the pattern identifiers must match as a whole
+    pub fn name_pattern(pattern: &str) -> Result<Regex, regex::Error> {
+        Regex::new(&format!("^(?:{})$", pattern))
+    }
+
+    /// checks a metric against the rules, naming the field of the first one it breaks
+    fn check(&self, metric: &proto::Metric, now: &DateTime<Utc>) -> Result<(), FieldViolation> {
+        let violation = |field: &str, description: String| Err(FieldViolation{
+            field: field.to_string(),
+            description,
+        });
+        if metric.identifier.is_empty() {
+            return violation("identifier", "an identifier is required".into());
+        }
+        if metric.identifier.len() > self.max_name_length {
+            return violation("identifier", format!("identifier is longer than {} bytes", self.max_name_length));
+        }
+        if !self.name_pattern.is_match(&metric.identifier) {
+            return violation("identifier", format!("identifier {:?} does not match {}", metric.identifier, self.name_pattern));
+        }
+        match &metric.value {
+            None => return violation("value", "a value is required".into()),
+            Some(ProtoValue::DoubleValue(value)) if !value.is_finite() && self.nan == NanPolicy::Reject =>
+                return violation("value", format!("{} is not a finite number", value)),
+            Some(ProtoValue::StringValue(value)) if value.len() > self.max_string_length =>
+                return violation("value", format!("string value is longer than {} bytes", self.max_string_length)),
+            Some(_) => {},
+        }
+        if let Some(when) = &metric.when {
+            if when.nanos < 0 || when.nanos >= 1_000_000_000 {
+                return violation("when.nanos", "nanos must be between 0 and 999,999,999".into());
+            }
+            let when = match Utc.timestamp_opt(when.seconds, when.nanos as u32) {
+                chrono::LocalResult::Single(when) => when,
+                _ => return violation("when.seconds", "time is out of range".into()),
+            };
+            if let Some(max_age) = self.max_age {
+                if now.checked_sub_signed(max_age).is_some_and(|oldest| when < oldest) {
+                    return violation("when", format!("time {} is more than {} in the past", when, max_age));
+                }
+            }
+            if let Some(max_ahead) = self.max_ahead {
+                if now.checked_add_signed(max_ahead).is_some_and(|latest| when > latest) {
+                    return violation("when", format!("time {} is more than {} in the future", when, max_ahead));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for IngestRules {
+    fn default() -> Self {
+        IngestRules{
+            name_pattern: IngestRules::name_pattern(DEFAULT_NAME_PATTERN).unwrap(),
+            max_name_length: DEFAULT_MAX_NAME_LENGTH,
+            max_string_length: DEFAULT_MAX_STRING_LENGTH,
+            max_age: None,
+            max_ahead: None,
+            nan: NanPolicy::default(),
+        }
+    }
 }
 
 impl<D: Database> Server<D> {
     pub fn new(db: D) -> Self {
         Server{
             db,
+            hub: Hub::default(),
+            transaction_size: DEFAULT_TRANSACTION_SIZE,
+            conflicts: ConflictPolicies::default(),
+            batch_ttl: DEFAULT_BATCH_TTL,
+            rules: IngestRules::default(),
+        }
+    }
+
+    /// sets how many metrics StreamMetrics commits in each transaction
+    pub fn with_transaction_size(mut self, size: usize) -> Self {
+        self.transaction_size = size.max(1);
+        self
+    }
+
+    /// sets the conflict policies used when a request does not pick one
+    pub fn with_conflict_policies(mut self, conflicts: ConflictPolicies) -> Self {
+        self.conflicts = conflicts;
+        self
+    }
+
+    /// sets how long the ID of a recorded batch is remembered, so that a retry is answered
+    /// without writing the batch again
+    pub fn with_batch_ttl(mut self, ttl: Duration) -> Self {
+        self.batch_ttl = ttl;
+        self
+    }
+
+    /// sets the rules recorded and streamed metrics must follow
+    pub fn with_ingest_rules(mut self, rules: IngestRules) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// the conflict policies a request asks for, falling back to those configured
+    fn conflict_policies(&self, policy: i32) -> Result<Cow<'_, ConflictPolicies>, &'static str> {
+        let policy = match ProtoConflictPolicy::from_i32(policy) {
+            Some(ProtoConflictPolicy::Unspecified) => return Ok(Cow::Borrowed(&self.conflicts)),
+            Some(ProtoConflictPolicy::Reject) => ConflictPolicy::Reject,
+            Some(ProtoConflictPolicy::Overwrite) => ConflictPolicy::Overwrite,
+            Some(ProtoConflictPolicy::KeepFirst) => ConflictPolicy::KeepFirst,
+            Some(ProtoConflictPolicy::MultiSample) => ConflictPolicy::MultiSample,
+            None => return Err("unknown conflict policy"),
+        };
+        Ok(Cow::Owned(ConflictPolicies::uniform(policy)))
+    }
+}
+
+impl<D: Database + Clone + 'static> Server<D> {
+    /// records one transaction of streamed metrics, where `offset` is the stream index of the first
+    async fn commit_streamed(&self, metrics: &[proto::Metric], offset: usize, conflicts: &Arc<ConflictPolicies>,
+        ack: &mut StreamMetricsResponse) -> Result<(), Status> {
+        let current_time = Cow::Owned(Utc::now());
+        let mut batch = Vec::with_capacity(metrics.len());
+        let mut indexes = Vec::with_capacity(metrics.len());
+        let first_error = ack.errors.len();
+        for (i, metric) in metrics.iter().enumerate() {
+            let converted = self.rules.check(metric, &current_time)
+                .map_err(|v| format!("{}: {}", v.field, v.description))
+                .and_then(|_| to_metric(metric, &current_time).map_err(str::to_string));
+            match converted {
+                Ok(metric) => {
+                    batch.push(metric.to_owned_metric());
+                    indexes.push(offset + i);
+                },
+                Err(message) => ack.errors.push(MetricError{
+                    index: (offset + i) as u32,
+                    message,
+                    code: Code::InvalidArgument as i32,
+                }),
+            }
+        }
+        let db = self.db.clone();
+        let conflicts = conflicts.clone();
+        // writing takes the database lock, so keep it off the async workers
+        let (batch, written) = tokio::task::spawn_blocking(move || {
+            let written = db.write_metrics_with(&batch, WriteMode::Partial, &conflicts);
+            (batch, written)
+        }).await.map_err(|_| Status::internal("unable to record metrics"))?;
+        let Written{failures, discarded} = written?;
+        publish_recorded(&self.hub, &batch, &failures, &discarded);
+        ack.received += metrics.len() as u64;
+        ack.recorded += (batch.len() - failures.len()) as u64;
+        ack.transactions += 1;
+        ack.errors.extend(failures.into_iter().map(|(index, e)| metric_error(indexes[index], &e)));
+        ack.errors[first_error..].sort_by_key(|e| e.index);
+        Ok(())
+    }
+}
+
+impl<D: Database + Default> Default for Server<D> {
+    fn default() -> Self {
+        Server::new(D::default())
+    }
+}
+
+/// publishes the metrics of a batch that were recorded, skipping the failures and those the
+/// conflict policy left out
+fn publish_recorded(hub: &Hub, batch: &[Metric], failures: &[(usize, DatabaseError)], discarded: &[usize]) {
+    let skipped: HashSet<usize> = failures.iter().map(|(index, _)| *index).chain(discarded.iter().copied()).collect();
+    hub.publish(batch.iter().enumerate().filter(|(i, _)| !skipped.contains(i)).map(|(_, metric)| metric));
+}
+
+/// reports a metric that could not be recorded, with a code telling duplicates apart and
+/// the message a status for the error would carry
+fn metric_error(index: usize, e: &DatabaseError) -> MetricError {
+    MetricError{
+        index: index as u32,
+        message: status_message(e).into_owned(),
+        code: error_code(e) as i32,
+    }
+}
+
+fn error_code(e: &DatabaseError) -> Code {
+    match e {
+        DatabaseError::Duplicate(_) => Code::AlreadyExists,
+        DatabaseError::ConstraintViolation(_) => Code::FailedPrecondition,
+        DatabaseError::NotFound(_) => Code::NotFound,
+        DatabaseError::Busy(_) => Code::Unavailable,
+        DatabaseError::Corrupt(_) => Code::DataLoss,
+        DatabaseError::InvalidArgument(_) => Code::InvalidArgument,
+        DatabaseError::Timeout(_) => Code::DeadlineExceeded,
+        DatabaseError::Custom(_) | DatabaseError::MigrationError(_) => Code::Internal,
+    }
+}
+
+/// whether a database error was brought about by the request rather than the database
+fn caused_by_request(e: &DatabaseError) -> bool {
+    matches!(e, DatabaseError::Duplicate(_) | DatabaseError::ConstraintViolation(_)
+        | DatabaseError::NotFound(_) | DatabaseError::InvalidArgument(_))
+}
+
+/// what clients are told about a database error; failures on the database's side are only named
+fn status_message(e: &DatabaseError) -> Cow<'_, str> {
+    match e {
+        DatabaseError::Duplicate(message) | DatabaseError::ConstraintViolation(message)
+            | DatabaseError::NotFound(message) | DatabaseError::InvalidArgument(message) => Cow::Borrowed(message),
+        DatabaseError::Busy(_) => Cow::Borrowed("database is busy"),
+        DatabaseError::Timeout(_) => Cow::Borrowed("database took too long to answer"),
+        DatabaseError::Corrupt(_) => Cow::Borrowed("database is corrupt"),
+        DatabaseError::Custom(_) | DatabaseError::MigrationError(_) => Cow::Borrowed("trouble accessing database"),
+    }
+}
+
+/// builds the status for a database error, attaching google.rpc details: an ErrorInfo naming the
+/// kind of failure and whether it is retryable, and a RetryInfo when it is
+fn error_status(e: &DatabaseError, message: String) -> Status {
+    let code = error_code(e);
+    let mut metadata = HashMap::new();
+    metadata.insert("retryable".to_string(), e.is_retryable().to_string());
+    let mut details = vec![to_any("google.rpc.ErrorInfo", &ErrorInfo{
+        reason: e.to_stored().0.to_uppercase(),
+        domain: ERROR_DOMAIN.to_string(),
+        metadata,
+    })];
+    if e.is_retryable() {
+        details.push(to_any("google.rpc.RetryInfo", &RetryInfo{
+            retry_delay: Some(prost_types::Duration{
+                seconds: RETRY_DELAY.as_secs() as i64,
+                nanos: RETRY_DELAY.subsec_nanos() as i32,
+            }),
+        }));
+    }
+    with_details(code, message, details)
+}
+
+/// rejects a request whose metrics break the ingest rules, with a google.rpc.BadRequest
+/// detail naming every field at fault
+fn invalid_metrics(violations: Vec<FieldViolation>) -> Status {
+    let message = match violations.len() {
+        1 => format!("{}: {}; no metrics were recorded", violations[0].field, violations[0].description),
+        n => format!("{}: {}, and {} more; no metrics were recorded", violations[0].field, violations[0].description, n - 1),
+    };
+    let details = vec![to_any("google.rpc.BadRequest", &BadRequest{field_violations: violations})];
+    with_details(Code::InvalidArgument, message, details)
+}
+
+fn with_details(code: Code, message: String, details: Vec<prost_types::Any>) -> Status {
+    let status = RpcStatus{code: code as i32, message: message.clone(), details};
+    Status::with_details(code, message, encode(&status).into())
+}
+
+fn to_any<M: Message>(name: &str, message: &M) -> prost_types::Any {
+    prost_types::Any{
+        type_url: format!("type.googleapis.com/{}", name),
+        value: encode(message),
+    }
+}
+
+fn encode<M: Message>(message: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(message.encoded_len());
+    message.encode(&mut buf).expect("a Vec has room for any message");
+    buf
+}
+
+/// converts a duration from a client, or explains why it is out of range
+fn to_duration(duration: &prost_types::Duration) -> Result<chrono::Duration, &'static str> {
+    // chrono keeps durations in an i64 of milliseconds, and panics beyond that
+    if duration.seconds.unsigned_abs() >= (i64::MAX / 1000) as u64 || duration.nanos.unsigned_abs() >= 1_000_000_000 {
+        return Err("duration is out of range");
+    }
+    Ok(chrono::Duration::seconds(duration.seconds) + chrono::Duration::nanoseconds(duration.nanos as i64))
+}
+
+fn to_timestamp(when: &DateTime<Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp{
+        seconds: when.timestamp(),
+        nanos: when.timestamp_subsec_nanos() as i32,
+    }
+}
+
+/// converts a timestamp from a client, which may be before 1970, or explains why it cannot be
+fn from_timestamp(when: &prost_types::Timestamp) -> Result<DateTime<Utc>, &'static str> {
+    if !(0..1_000_000_000).contains(&when.nanos) {
+        return Err("nanos must be between 0 and 999,999,999");
+    }
+    match Utc.timestamp_opt(when.seconds, when.nanos as u32) {
+        chrono::LocalResult::Single(when) => Ok(when),
+        _ => Err("time is out of range"),
+    }
+}
+
+/// converts a recorded metric into the form clients send them in
+fn to_proto_metric(metric: &Metric) -> proto::Metric {
+    proto::Metric{
+        identifier: metric.name.to_string(),
+        labels: metric.labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        when: Some(to_timestamp(&metric.when)),
+        value: Some(match &metric.value {
+            MetricValue::Double(v) => ProtoValue::DoubleValue(*v),
+            MetricValue::String(v) => ProtoValue::StringValue(v.to_string()),
+        }),
+    }
+}
+
+/// sends every metric recorded between `start` and `stop` to a watcher, a page at a time
+async fn replay<D: Database + Clone + 'static>(db: D, selector: Selector, start: DateTime<Utc>, stop: DateTime<Utc>,
+        sender: &mpsc::Sender<Result<WatchMetricsResponse, Status>>) -> Result<(), Status> {
+    let mut resume = vec!();
+    loop {
+        let db = db.clone();
+        let selector = selector.clone();
+        let page = tokio::task::spawn_blocking(move || {
+            db.read_metrics(&selector, &TimeRange::new(Some(start), Some(stop), Bounds::HalfOpen), WATCH_BATCH, &resume)
+                .map(|page| (page.metrics.iter().map(to_proto_metric).collect::<Vec<_>>(), page.truncated))
+        }).await.map_err(|_| Status::internal("unable to replay metrics"))?;
+        let (metrics, truncated) = page?;
+        if !metrics.is_empty() {
+            let response = WatchMetricsResponse{
+                metrics,
+                dropped: 0,
+                live: false,
+            };
+            if sender.send(Ok(response)).await.is_err() {
+                return Ok(());
+            }
+        }
+        if truncated.is_empty() {
+            return Ok(());
+        }
+        resume = truncated;
+    }
+}
+
+/// sends live metrics to a watcher until either goes away, batching whatever has built up
+async fn forward(mut subscription: Subscription, sender: mpsc::Sender<Result<WatchMetricsResponse, Status>>) {
+    loop {
+        let received = tokio::select! {
+            received = subscription.recv() => received,
+            _ = sender.closed() => return,
+        };
+        let mut response = WatchMetricsResponse{
+            live: true,
+            ..Default::default()
+        };
+        let mut next = received;
+        while let Some(received) = next {
+            match received {
+                Received::Metric(metric) => response.metrics.push(to_proto_metric(&metric)),
+                Received::Dropped(dropped) => response.dropped += dropped,
+            }
+            if response.metrics.len() >= WATCH_BATCH {
+                break;
+            }
+            next = subscription.try_recv();
+        }
+        if response.metrics.is_empty() && response.dropped == 0 {
+            // the hub has gone
+            return;
+        }
+        // a slow client holds up only its own subscription, which drops metrics rather than
+        // blocking writers; the next response says how many
+        if sender.send(Ok(response)).await.is_err() {
+            return;
         }
     }
 }
 
+/// converts a metric from a request, stamping it with `now` if it has no time of its own
+fn to_metric<'a>(metric: &'a proto::Metric, now: &Cow<'a, DateTime<Utc>>) -> Result<Metric<'a>, &'static str> {
+    let value = match &metric.value {
+        Some(ProtoValue::DoubleValue(val)) => MetricValue::Double(*val),
+        Some(ProtoValue::StringValue(val)) => MetricValue::String(Cow::Borrowed(val)),
+        None => return Err("Did you ask for a metric with no value? How very foolish of you!"),
+    };
+    let when = match &metric.when {
+        Some(w) => Cow::Owned(from_timestamp(w)?),
+        None => now.clone(),
+    };
+    Ok(Metric{
+        name: Cow::Borrowed(&metric.identifier),
+        labels: metric.labels.iter().map(|(k, v)| (Cow::Borrowed(k.as_str()), Cow::Borrowed(v.as_str()))).collect(),
+        when,
+        value,
+    })
+}
+
+/// picks the finest rollup resolution that keeps each series within `limit` buckets
+/// over `span`, falling back to whole days
+fn budget_width(span: chrono::Duration, limit: usize) -> chrono::Duration {
+    let span = span.num_seconds().max(1);
+    let limit = limit as i64;
+    for resolution in ROLLUP_RESOLUTIONS.iter() {
+        if span <= resolution * limit {
+            return chrono::Duration::seconds(*resolution);
+        }
+    }
+    let day = ROLLUP_RESOLUTIONS[ROLLUP_RESOLUTIONS.len() - 1];
+    chrono::Duration::seconds(day * ((span + day * limit - 1) / (day * limit)))
+}
+
+/// builds the selector for a prefix and label matchers given in a request, or explains
+/// what is wrong with them
+fn to_selector(prefix: &str, matchers: &[proto::LabelMatcher]) -> Result<Selector, String> {
+    let mut selector = Selector::from(prefix);
+    for matcher in matchers {
+        let op = match ProtoMatchOp::from_i32(matcher.op) {
+            Some(ProtoMatchOp::Equal) => MatchOp::Equal,
+            Some(ProtoMatchOp::NotEqual) => MatchOp::NotEqual,
+            Some(ProtoMatchOp::Regex) => {
+                if let Err(e) = regex::Regex::new(&matcher.value) {
+                    return Err(format!("bad regular expression for label '{}': {}", matcher.key, e));
+                }
+                MatchOp::Regex
+            },
+            None => return Err(format!("unknown match operation for label '{}'", matcher.key)),
+        };
+        selector.matchers.push(LabelMatcher{
+            key: matcher.key.clone(),
+            op,
+            value: matcher.value.clone(),
+        });
+    }
+    Ok(selector)
+}
+
+/// narrows a selector down to the exact names, glob or name regular expression given in a
+/// request, or explains what is wrong with them
+fn select_names(selector: &mut Selector, names: &[String], glob: &str, name_regex: &str) -> Result<(), String> {
+    selector.names = names.to_vec();
+    selector.pattern = match (glob, name_regex) {
+        ("", "") => None,
+        (glob, "") => Some(NamePattern::Glob(glob.to_string())),
+        ("", name_regex) => Some(NamePattern::Regex(name_regex.to_string())),
+        _ => return Err("only one of a glob and a name regular expression may be given".into()),
+    };
+    if let Some(pattern) = &selector.pattern {
+        if let Err(e) = regex::Regex::new(&pattern.to_regex()) {
+            return Err(format!("bad name pattern: {}", e));
+        }
+    }
+    Ok(())
+}
+
+/// PageToken is the opaque continuation handed back to clients when one or more series
+/// in LoadMetrics had more points than max_time_values allowed
+#[derive(Clone, PartialEq, prost::Message)]
+struct PageToken {
+    #[prost(message, repeated, tag="1")]
+    series: Vec<SeriesCursor>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+struct SeriesCursor {
+    #[prost(string, tag="1")]
+    identifier: String,
+    #[prost(message, optional, tag="2")]
+    after: Option<prost_types::Timestamp>,
+}
+
+/// google.rpc.Status, which clients read error details from in the grpc-status-details-bin trailer
+#[derive(Clone, PartialEq, prost::Message)]
+struct RpcStatus {
+    #[prost(int32, tag="1")]
+    code: i32,
+    #[prost(string, tag="2")]
+    message: String,
+    #[prost(message, repeated, tag="3")]
+    details: Vec<prost_types::Any>,
+}
+
+/// google.rpc.ErrorInfo
+#[derive(Clone, PartialEq, prost::Message)]
+struct ErrorInfo {
+    #[prost(string, tag="1")]
+    reason: String,
+    #[prost(string, tag="2")]
+    domain: String,
+    #[prost(map="string, string", tag="3")]
+    metadata: HashMap<String, String>,
+}
+
+/// google.rpc.RetryInfo
+#[derive(Clone, PartialEq, prost::Message)]
+struct RetryInfo {
+    #[prost(message, optional, tag="1")]
+    retry_delay: Option<prost_types::Duration>,
+}
+
+/// google.rpc.BadRequest
+#[derive(Clone, PartialEq, prost::Message)]
+struct BadRequest {
+    #[prost(message, repeated, tag="1")]
+    field_violations: Vec<FieldViolation>,
+}
+
+/// google.rpc.BadRequest.FieldViolation
+#[derive(Clone, PartialEq, prost::Message)]
+struct FieldViolation {
+    #[prost(string, tag="1")]
+    field: String,
+    #[prost(string, tag="2")]
+    description: String,
+}
+
 impl From<DatabaseError> for Status {
     fn from(e: DatabaseError) -> Self {
-        warn!("Error accessing database: {}", e);
-        Status::internal("trouble accessing database")
+        if !caused_by_request(&e) {
+            warn!("Error accessing database: {}", e);
+        }
+        error_status(&e, status_message(&e).into_owned())
     }
 }
 
 #[tonic::async_trait]
-impl<D: Database + 'static> MetricsService for Server<D> {
+impl<D: Database + Clone + 'static> MetricsService for Server<D> {
+    type WatchMetricsStream = ReceiverStream<Result<WatchMetricsResponse, Status>>;
+
     async fn record_metrics(&self, request: Request<RecordMetricsRequest>)
         -> Result<Response<RecordMetricsResponse>, Status> {
         let current_time: Cow<'_, DateTime<Utc>> = Cow::Owned(Utc::now());
-        for metric in &request.get_ref().metrics {
-            let metric_value = match &metric.value {
-                Some(ProtoValue::DoubleValue(val)) => MetricValue::Double(*val),
-                Some(ProtoValue::StringValue(val)) => MetricValue::String(Cow::Borrowed(val)),
-                None => return Err(Status::unknown("Did you ask for a metric with no value? How very foolish of you!")),
-            };
-            let when = if let Some(w) = &metric.when {
-                let d = UNIX_EPOCH + Duration::from_secs(w.seconds as u64) + Duration::from_nanos(w.nanos as u64);
-                Cow::Owned(DateTime::from(d))
-            } else {
-                current_time.clone()
-            };
-            self.db.write_metric(&Metric{
-                name: Cow::Borrowed(&metric.identifier),
-                when: when,
-                value: metric_value,
-            })?;
+        let req = request.get_ref();
+        let mut metrics = Vec::with_capacity(req.metrics.len());
+        let violations: Vec<_> = req.metrics.iter().enumerate()
+            .filter_map(|(i, metric)| self.rules.check(metric, &current_time).err().map(|v| FieldViolation{
+                field: format!("metrics[{}].{}", i, v.field),
+                description: v.description,
+            }))
+            .collect();
+        if !violations.is_empty() {
+            return Err(invalid_metrics(violations));
+        }
+        for metric in &req.metrics {
+            metrics.push(to_metric(metric, &current_time).map_err(Status::invalid_argument)?);
         }
-        Ok(Response::new(RecordMetricsResponse{}))
+        let mode = if req.partial_success {
+            WriteMode::Partial
+        } else {
+            WriteMode::Atomic
+        };
+        let conflicts = self.conflict_policies(req.conflict_policy).map_err(Status::invalid_argument)?;
+        // a batch with an ID is only written once; retries get the first result back
+        let (failures, discarded, replayed) = if req.batch_id.is_empty() {
+            let Written{failures, discarded} = self.db.write_metrics_with(&metrics, mode, &conflicts)?;
+            (failures, discarded, false)
+        } else {
+            let ttl = chrono::Duration::from_std(self.batch_ttl).unwrap_or(chrono::Duration::MAX);
+            let written = self.db.write_batch(&req.batch_id, ttl, &metrics, mode, &conflicts)?;
+            (written.failures, written.discarded, written.replayed)
+        };
+        if mode == WriteMode::Atomic {
+            if let Some((index, e)) = failures.first() {
+                warn!("Rejecting batch of {} metrics: {}", metrics.len(), e);
+                let message = format!("metric {} could not be recorded; no metrics in the batch were recorded", index);
+                return Err(match e {
+                    DatabaseError::Custom(_) | DatabaseError::MigrationError(_) => Status::aborted(message),
+                    e => error_status(e, format!("{}: {}", message, status_message(e))),
+                });
+            }
+        }
+        if !replayed {
+            publish_recorded(&self.hub, &metrics, &failures, &discarded);
+        }
+        let errors = failures.iter().map(|(index, e)| metric_error(*index, e)).collect();
+        Ok(Response::new(RecordMetricsResponse{errors}))
+    }
+
+    /// records a stream of batches in transactions of `transaction_size` metrics; metrics that
+    /// cannot be recorded are reported back rather than failing the stream. The next message is
+    /// only read once the previous transaction commits, so a slow database holds clients back.
+    /// The conflict policy of the first message holds for the whole stream
+    async fn stream_metrics(&self, request: Request<tonic::Streaming<StreamMetricsRequest>>)
+        -> Result<Response<StreamMetricsResponse>, Status> {
+        let mut stream = request.into_inner();
+        let mut ack = StreamMetricsResponse::default();
+        let mut pending: Vec<proto::Metric> = Vec::new();
+        let mut offset = 0;
+        let mut policy = None;
+        // settled by the first message, before anything is committed
+        let mut conflicts = Arc::default();
+        while let Some(batch) = stream.message().await? {
+            match policy {
+                None => {
+                    conflicts = Arc::new(self.conflict_policies(batch.conflict_policy).map_err(Status::invalid_argument)?.into_owned());
+                    policy = Some(batch.conflict_policy);
+                },
+                Some(policy) if batch.conflict_policy != ProtoConflictPolicy::Unspecified as i32 && batch.conflict_policy != policy =>
+                    return Err(Status::invalid_argument("conflict policy cannot change partway through a stream")),
+                Some(_) => (),
+            }
+            pending.extend(batch.metrics);
+            while pending.len() >= self.transaction_size {
+                let rest = pending.split_off(self.transaction_size);
+                self.commit_streamed(&pending, offset, &conflicts, &mut ack).await?;
+                offset += pending.len();
+                pending = rest;
+            }
+        }
+        if !pending.is_empty() {
+            self.commit_streamed(&pending, offset, &conflicts, &mut ack).await?;
+        }
+        Ok(Response::new(ack))
     }
 
     async fn load_metrics(&self, request: Request<LoadMetricsRequest>)
         -> Result<Response<LoadMetricsResponse>, Status> {
         let req = request.get_ref();
-        let mut start = None;
-        let mut stop = None;
-        if let Some(range) = &req.time_range {
-            if let Some(start_proto) = &range.start {
-                let d = UNIX_EPOCH + Duration::from_secs(start_proto.seconds as u64) + Duration::from_nanos(start_proto.nanos as u64);
-                start = Some(DateTime::from(d));
+        let mut range = TimeRange::default();
+        if let Some(time_range) = &req.time_range {
+            if let Some(start) = &time_range.start {
+                range.start = Some(from_timestamp(start).map_err(Status::invalid_argument)?);
             }
-            if let Some(stop_proto) = &range.stop {
-                let d = UNIX_EPOCH + Duration::from_secs(stop_proto.seconds as u64) + Duration::from_nanos(stop_proto.nanos as u64);
-                stop = Some(DateTime::from(d));
+            if let Some(stop) = &time_range.stop {
+                range.stop = Some(from_timestamp(stop).map_err(Status::invalid_argument)?);
             }
+            range.bounds = match ProtoBounds::from_i32(time_range.bounds) {
+                Some(ProtoBounds::HalfOpen) => Bounds::HalfOpen,
+                Some(ProtoBounds::Inclusive) => Bounds::Inclusive,
+                Some(ProtoBounds::Exclusive) => Bounds::Exclusive,
+                None => return Err(Status::invalid_argument("unknown time range bounds")),
+            };
+        }
+        // relative offsets are evaluated against our clock, so clients need not agree with it
+        let now = Utc::now();
+        if let Some(offset) = &req.relative_start {
+            if range.start.is_some() {
+                return Err(Status::invalid_argument("only one of a start time and a relative start may be given"));
+            }
+            let offset = to_duration(offset).map_err(Status::invalid_argument)?;
+            range.start = Some(now.checked_add_signed(offset)
+                .ok_or_else(|| Status::invalid_argument("relative start is out of range"))?);
         }
+        if let Some(offset) = &req.relative_stop {
+            if range.stop.is_some() {
+                return Err(Status::invalid_argument("only one of a stop time and a relative stop may be given"));
+            }
+            let offset = to_duration(offset).map_err(Status::invalid_argument)?;
+            range.stop = Some(now.checked_add_signed(offset)
+                .ok_or_else(|| Status::invalid_argument("relative stop is out of range"))?);
+        }
+        let mut selector = to_selector(&req.prefix, &req.matchers).map_err(Status::invalid_argument)?;
+        select_names(&mut selector, &req.names, &req.glob, &req.name_regex).map_err(Status::invalid_argument)?;
         let limit = if req.max_time_values != 0 {
             req.max_time_values as usize
         } else {
             1000
         };
-        let mut mapping: HashMap<Cow<'_, str>, Vec<TimeValue>> = HashMap::default();
-        for metric in self.db.read_metrics(&req.prefix, start.as_ref(), stop.as_ref(), limit)? {
-            if !mapping.contains_key(&metric.name) {
-                mapping.insert(metric.name.clone(), vec!());
+        let mut resume = vec!();
+        if !req.page_token.is_empty() {
+            let token = PageToken::decode(req.page_token.as_slice())
+                .map_err(|_| Status::invalid_argument("malformed page token"))?;
+            for cursor in token.series {
+                let after = from_timestamp(&cursor.after.unwrap_or_default())
+                    .map_err(|_| Status::invalid_argument("malformed page token"))?;
+                resume.push((cursor.identifier, after));
             }
+        }
+        let aggregator = match ProtoAggregator::from_i32(req.aggregator) {
+            Some(ProtoAggregator::Avg) => Some(Aggregator::Avg),
+            Some(ProtoAggregator::Min) => Some(Aggregator::Min),
+            Some(ProtoAggregator::Max) => Some(Aggregator::Max),
+            Some(ProtoAggregator::Sum) => Some(Aggregator::Sum),
+            Some(ProtoAggregator::Count) => Some(Aggregator::Count),
+            Some(ProtoAggregator::Last) => Some(Aggregator::Last),
+            Some(ProtoAggregator::Percentile) if (0.0..=100.0).contains(&req.percentile) =>
+                Some(Aggregator::Percentile(req.percentile)),
+            Some(ProtoAggregator::Percentile) => return Err(Status::invalid_argument("percentile must be between 0 and 100")),
+            Some(ProtoAggregator::None) => None,
+            None => return Err(Status::invalid_argument("unknown aggregator")),
+        };
+        if req.tail > 0 && aggregator.is_some() {
+            return Err(Status::invalid_argument("tail requests cannot be aggregated"));
+        }
+        let page = match (aggregator, &req.bucket_width) {
+            (Some(aggregator), width) => {
+                let width = match width {
+                    Some(width) => to_duration(width).map_err(Status::invalid_argument)?,
+                    None => match range.start {
+                        Some(start) => budget_width(range.stop.unwrap_or(now) - start, limit),
+                        None => return Err(Status::invalid_argument("a start time or bucket width is required to aggregate")),
+                    },
+                };
+                if width < chrono::Duration::seconds(1) {
+                    return Err(Status::invalid_argument("bucket width must be at least one second"));
+                }
+                if width > chrono::Duration::seconds(MAX_BUCKET_WIDTH) {
+                    return Err(Status::invalid_argument("bucket width must be at most a hundred years"));
+                }
+                ReadResult{
+                    metrics: self.db.read_aggregated(&selector, &range, width, aggregator)?,
+                    truncated: vec!(),
+                }
+            },
+            (None, Some(_)) => return Err(Status::invalid_argument("an aggregator is required along with a bucket width")),
+            (None, None) if req.tail > 0 => {
+                if !resume.is_empty() {
+                    return Err(Status::invalid_argument("tail requests cannot be paged"));
+                }
+                ReadResult{
+                    metrics: self.db.read_tail(&selector, &range, req.tail as usize)?,
+                    truncated: vec!(),
+                }
+            },
+            (None, None) => self.db.read_metrics(&selector, &range, limit, &resume)?,
+        };
+        let mut mapping: HashMap<String, proto::CompressedMetric> = HashMap::default();
+        for metric in page.metrics {
+            let key = series_key(&metric.name, &metric.labels);
+            let compressed = mapping.entry(key).or_insert_with(|| proto::CompressedMetric{
+                identifier: metric.name.to_string(),
+                labels: metric.labels.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+                time_values: vec!(),
+                truncated: false,
+            });
             let value = match metric.value {
                 MetricValue::String(v) => CompressedValue::StringValue(v.into_owned()),
                 MetricValue::Double(v) => CompressedValue::DoubleValue(v),
             };
-            let when = metric.when.into_owned();
-            mapping.get_mut(&metric.name).unwrap().push(TimeValue{
+            compressed.time_values.push(TimeValue{
                 value: Some(value),
-                when: Some(prost_types::Timestamp{
-                    seconds: when.timestamp(),
-                    nanos: when.timestamp_subsec_nanos() as i32,
-                }),
+                when: Some(to_timestamp(&metric.when)),
             });
         }
-        let mut metrics = Vec::with_capacity(mapping.len());
-        for (key, value) in mapping {
-            metrics.push(proto::CompressedMetric{
-                identifier: key.to_string(),
-                time_values: value,
-            })
+        for (key, _) in &page.truncated {
+            if let Some(compressed) = mapping.get_mut(key) {
+                compressed.truncated = true;
+            }
+        }
+        let metrics = mapping.into_values().collect();
+        let mut next_page_token = vec!();
+        if !page.truncated.is_empty() {
+            let token = PageToken{
+                series: page.truncated.into_iter().map(|(identifier, after)| SeriesCursor{
+                    identifier,
+                    after: Some(to_timestamp(&after)),
+                }).collect(),
+            };
+            token.encode(&mut next_page_token)
+                .map_err(|_| Status::internal("unable to build page token"))?;
         }
-        Ok(Response::new(LoadMetricsResponse{metrics}))
+        Ok(Response::new(LoadMetricsResponse{metrics, next_page_token}))
     }
 
 
     async fn list_metrics(&self, request: Request<ListMetricsRequest>)
         -> Result<Response<ListMetricsResponse>, Status> {
-        let prefix = &request.get_ref().prefix;
-        let metrics = self.db.list_metrics(&prefix)?;
+        let req = request.get_ref();
+        let mut selector = to_selector(&req.prefix, &req.matchers).map_err(Status::invalid_argument)?;
+        select_names(&mut selector, &req.names, &req.glob, &req.name_regex).map_err(Status::invalid_argument)?;
+        let metrics = self.db.list_metrics(&selector)?;
         let mut metrics_list = vec!();
-        for (identifier, when) in metrics {
+        for (identifier, labels, when) in metrics {
             metrics_list.push(ListMetric{
                 identifier,
-                last_timestamp: Some(prost_types::Timestamp{
-                    seconds: when.timestamp(),
-                    nanos: when.timestamp_subsec_nanos() as i32,
-                }),
+                labels: labels.into_iter().map(|(k, v)| (k.into_owned(), v.into_owned())).collect(),
+                last_timestamp: Some(to_timestamp(&when)),
             })
         }
         Ok(Response::new(ListMetricsResponse{metrics_list}))
     }
-}
\ No newline at end of file
+
+    async fn delete_metrics(&self, request: Request<DeleteMetricsRequest>)
+        -> Result<Response<DeleteMetricsResponse>, Status> {
+        let req = request.get_ref();
+        let before = match &req.before {
+            Some(before) => from_timestamp(before).map_err(Status::invalid_argument)?,
+            None => return Err(Status::invalid_argument("a time to delete metrics before is required")),
+        };
+        let deleted = self.db.delete_metrics(&req.prefix, &before)?;
+        Ok(Response::new(DeleteMetricsResponse{deleted: deleted as u64}))
+    }
+
+    /// streams metrics as they are recorded, after replaying those recorded within `replay`.
+    /// Metrics recorded while the replay is read may be sent twice
+    async fn watch_metrics(&self, request: Request<WatchMetricsRequest>)
+        -> Result<Response<Self::WatchMetricsStream>, Status> {
+        let req = request.into_inner();
+        let selector = to_selector(&req.prefix, &req.matchers).map_err(Status::invalid_argument)?;
+        let subscription = self.hub.subscribe(&selector)
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        let now = Utc::now();
+        let replay_from = match req.replay {
+            Some(replay) if replay.seconds < 0 || replay.nanos < 0 =>
+                return Err(Status::invalid_argument("replay must not be negative")),
+            Some(replay) => {
+                let replay = to_duration(&replay).map_err(Status::invalid_argument)?;
+                Some(now.checked_sub_signed(replay).ok_or_else(|| Status::invalid_argument("replay is out of range"))?)
+            },
+            None => None,
+        };
+        let (sender, receiver) = mpsc::channel(WATCH_BUFFER);
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            if let Some(start) = replay_from {
+                if let Err(e) = replay(db, selector, start, now, &sender).await {
+                    let _ = sender.send(Err(e)).await;
+                    return;
+                }
+            }
+            forward(subscription, sender).await;
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_latest(&self, request: Request<GetLatestRequest>)
+        -> Result<Response<GetLatestResponse>, Status> {
+        let req = request.into_inner();
+        let mut selector = to_selector(&req.prefix, &req.matchers).map_err(Status::invalid_argument)?;
+        selector.names = req.names;
+        let metrics = self.db.latest_values(&selector)?.iter().map(to_proto_metric).collect();
+        Ok(Response::new(GetLatestResponse{metrics}))
+    }
+
+    async fn browse_metrics(&self, request: Request<BrowseMetricsRequest>)
+        -> Result<Response<BrowseMetricsResponse>, Status> {
+        let req = request.get_ref();
+        let nodes = self.db.browse_metrics(&req.prefix)?.into_iter().map(|node| BrowseNode{
+            path: format!("{}{}", req.prefix, node.component),
+            component: node.component,
+            leaf: node.leaf,
+            branch: node.branch,
+            series: node.series as u64,
+        }).collect();
+        Ok(Response::new(BrowseMetricsResponse{nodes}))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dal::memory::MemoryDatabase;
+
+    fn metric(name: &str, seconds: i64, value: f64) -> proto::Metric {
+        proto::Metric{
+            identifier: name.to_string(),
+            labels: HashMap::new(),
+            when: Some(prost_types::Timestamp{seconds, nanos: 0}),
+            value: Some(ProtoValue::DoubleValue(value)),
+        }
+    }
+
+    async fn record(server: &Server<MemoryDatabase>, metrics: Vec<proto::Metric>, partial_success: bool)
+        -> Result<RecordMetricsResponse, Status> {
+        let request = RecordMetricsRequest{metrics, partial_success, ..Default::default()};
+        server.record_metrics(Request::new(request)).await.map(Response::into_inner)
+    }
+
+    async fn load(server: &Server<MemoryDatabase>, request: LoadMetricsRequest) -> Result<LoadMetricsResponse, Status> {
+        server.load_metrics(Request::new(request)).await.map(Response::into_inner)
+    }
+
+    fn seconds(metric: &proto::CompressedMetric) -> Vec<i64> {
+        metric.time_values.iter().map(|tv| tv.when.as_ref().unwrap().seconds).collect()
+    }
+
+    #[tokio::test]
+    async fn records_and_loads_metrics() {
+        let server = Server::<MemoryDatabase>::default();
+        record(&server, vec!(
+            metric("myservice.cpu_time", 10, 1.0),
+            metric("myservice.cpu_time", 20, 2.0),
+            metric("myservice.mem_used", 10, 3.0),
+            metric("otherservice.cpu_time", 10, 4.0),
+        ), false).await.unwrap();
+        let mut metrics = load(&server, LoadMetricsRequest{
+            prefix: "myservice.".into(),
+            ..Default::default()
+        }).await.unwrap().metrics;
+        metrics.sort_by(|a, b| a.identifier.cmp(&b.identifier));
+        assert_eq!(metrics.iter().map(|m| m.identifier.as_str()).collect::<Vec<_>>(), vec!("myservice.cpu_time", "myservice.mem_used"));
+        assert_eq!(seconds(&metrics[0]), vec!(10, 20));
+        assert!(!metrics[0].truncated);
+    }
+
+    #[tokio::test]
+    async fn atomic_batches_are_all_or_nothing() {
+        let server = Server::<MemoryDatabase>::default();
+        let batch = vec!(metric("myservice.cpu_time", 10, 1.0), metric("myservice.cpu_time", 10, 2.0));
+        let status = record(&server, batch.clone(), false).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        assert!(load(&server, LoadMetricsRequest::default()).await.unwrap().metrics.is_empty());
+
+        let errors = record(&server, batch, true).await.unwrap().errors;
+        assert_eq!(errors.iter().map(|e| e.index).collect::<Vec<_>>(), vec!(1));
+        assert_eq!(load(&server, LoadMetricsRequest::default()).await.unwrap().metrics.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn settles_conflicts_by_policy() {
+        let server = Server::new(MemoryDatabase::default())
+            .with_conflict_policies("myservice.mem=overwrite".parse().unwrap());
+        let first = vec!(metric("myservice.cpu_time", 10, 1.0), metric("myservice.mem_used", 10, 1.0));
+        record(&server, first, false).await.unwrap();
+
+        // duplicates come back as such, however the batch is written
+        let status = record(&server, vec!(metric("myservice.cpu_time", 10, 2.0)), false).await.unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+        let errors = record(&server, vec!(metric("myservice.cpu_time", 10, 2.0)), true).await.unwrap().errors;
+        assert_eq!(errors.iter().map(|e| e.code).collect::<Vec<_>>(), vec!(Code::AlreadyExists as i32));
+        assert!(errors[0].message.starts_with("myservice.cpu_time already has a point at "), "{}", errors[0].message);
+
+        // the configured policy applies unless the request picks another
+        record(&server, vec!(metric("myservice.mem_used", 10, 2.0)), false).await.unwrap();
+        let request = RecordMetricsRequest{
+            metrics: vec!(metric("myservice.cpu_time", 10, 2.0)),
+            conflict_policy: ProtoConflictPolicy::MultiSample as i32,
+            ..Default::default()
+        };
+        server.record_metrics(Request::new(request)).await.unwrap();
+        let mut metrics = load(&server, LoadMetricsRequest::default()).await.unwrap().metrics;
+        metrics.sort_by(|a, b| a.identifier.cmp(&b.identifier));
+        let values: Vec<Vec<_>> = metrics.iter()
+            .map(|m| m.time_values.iter().map(|tv| tv.value.clone().unwrap()).collect())
+            .collect();
+        assert_eq!(values, vec!(
+            vec!(CompressedValue::DoubleValue(1.0), CompressedValue::DoubleValue(2.0)),
+            vec!(CompressedValue::DoubleValue(2.0)),
+        ));
+    }
+
+    #[tokio::test]
+    async fn watchers_see_only_points_kept() {
+        let server = Server::new(MemoryDatabase::default())
+            .with_conflict_policies("myservice.=keep-first".parse().unwrap());
+        let request = WatchMetricsRequest{prefix: "myservice.".into(), ..Default::default()};
+        let mut watch = server.watch_metrics(Request::new(request)).await.unwrap().into_inner().into_inner();
+        record(&server, vec!(metric("myservice.cpu_time", 10, 1.0)), false).await.unwrap();
+        // the point left out in favour of the first is never sent
+        record(&server, vec!(metric("myservice.cpu_time", 10, 2.0), metric("myservice.cpu_time", 20, 3.0)), false)
+            .await
+            .unwrap();
+        let mut values = vec!();
+        while values.len() < 2 {
+            let response = watch.recv().await.unwrap().unwrap();
+            values.extend(response.metrics.into_iter().map(|m| m.value.unwrap()));
+        }
+        assert_eq!(values, vec!(ProtoValue::DoubleValue(1.0), ProtoValue::DoubleValue(3.0)));
+    }
+
+    #[tokio::test]
+    async fn retried_batches_are_written_once() {
+        let server = Server::<MemoryDatabase>::default();
+        let request = |metrics| RecordMetricsRequest{
+            metrics,
+            partial_success: true,
+            batch_id: "agent-1/42".into(),
+            ..Default::default()
+        };
+        let batch = vec!(metric("myservice.cpu_time", 10, 1.0), metric("myservice.cpu_time", 10, 2.0));
+        let first = server.record_metrics(Request::new(request(batch.clone()))).await.unwrap().into_inner();
+        assert_eq!(first.errors.len(), 1);
+        // the retry neither fails on the points already recorded nor records anything new
+        let retry = vec!(metric("myservice.cpu_time", 10, 1.0), metric("myservice.cpu_time", 20, 2.0));
+        let retried = server.record_metrics(Request::new(request(retry))).await.unwrap().into_inner();
+        assert_eq!(retried, first);
+        let metrics = load(&server, LoadMetricsRequest::default()).await.unwrap().metrics;
+        assert_eq!(seconds(&metrics[0]), vec!(10));
+    }
+
+    fn error_details(status: &Status) -> (ErrorInfo, Option<RetryInfo>) {
+        let details = RpcStatus::decode(status.details()).unwrap();
+        assert_eq!(details.code, status.code() as i32);
+        let info = details.details.iter().find(|any| any.type_url == "type.googleapis.com/google.rpc.ErrorInfo").unwrap();
+        let retry = details.details.iter().find(|any| any.type_url == "type.googleapis.com/google.rpc.RetryInfo");
+        (ErrorInfo::decode(&info.value[..]).unwrap(), retry.map(|any| RetryInfo::decode(&any.value[..]).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn tells_retryable_failures_apart() {
+        let server = Server::<MemoryDatabase>::default();
+        let batch = vec!(metric("myservice.cpu_time", 10, 1.0), metric("myservice.cpu_time", 10, 2.0));
+        let status = record(&server, batch, false).await.unwrap_err();
+        let (info, retry) = error_details(&status);
+        assert_eq!(info.reason, "DUPLICATE");
+        assert_eq!(info.domain, ERROR_DOMAIN);
+        assert_eq!(info.metadata["retryable"], "false");
+        assert!(retry.is_none());
+
+        let status = Status::from(DatabaseError::Busy("database is locked".into()));
+        assert_eq!(status.code(), Code::Unavailable);
+        let (info, retry) = error_details(&status);
+        assert_eq!(info.reason, "BUSY");
+        assert_eq!(info.metadata["retryable"], "true");
+        assert_eq!(retry.unwrap().retry_delay.unwrap().seconds, 1);
+
+        let status = Status::from(DatabaseError::Custom("disk on fire".into()));
+        assert_eq!(status.code(), Code::Internal);
+        assert_eq!(status.message(), "trouble accessing database");
+        assert_eq!(error_details(&status).0.metadata["retryable"], "false");
+        // failures reported per metric say no more than a status would
+        let error = metric_error(3, &DatabaseError::Custom("disk on fire".into()));
+        assert_eq!((error.index, error.message.as_str()), (3, "trouble accessing database"));
+    }
+
+    #[tokio::test]
+    async fn validates_ingested_metrics() {
+        let server = Server::<MemoryDatabase>::default().with_ingest_rules(IngestRules{
+            max_name_length: 20,
+            max_string_length: 4,
+            max_ahead: Some(chrono::Duration::hours(1)),
+            ..Default::default()
+        });
+        let far_future = Utc::now().timestamp() + 2 * 60 * 60;
+        let invalid = vec!(
+            metric("", 10, 1.0),
+            metric("myservice.cpu time", 10, 1.0),
+            metric("myservice.a_very_long_name", 10, 1.0),
+            metric("myservice.cpu_time", 10, f64::NAN),
+            metric("myservice.cpu_time", 10, f64::INFINITY),
+            proto::Metric{value: Some(ProtoValue::StringValue("hello".into())), ..metric("myservice.state", 10, 0.0)},
+            proto::Metric{value: None, ..metric("myservice.cpu_time", 10, 0.0)},
+            metric("myservice.cpu_time", i64::MAX, 1.0),
+            proto::Metric{when: Some(prost_types::Timestamp{seconds: 10, nanos: -1}), ..metric("myservice.cpu_time", 0, 1.0)},
+            metric("myservice.cpu_time", far_future, 1.0),
+        );
+        let mut batch = vec!(metric("myservice.cpu_time", 10, 1.0));
+        batch.extend(invalid.iter().cloned());
+        let status = record(&server, batch, true).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let details = RpcStatus::decode(status.details()).unwrap();
+        let violations = BadRequest::decode(&details.details[0].value[..]).unwrap().field_violations;
+        assert_eq!(violations.iter().map(|v| v.field.as_str()).collect::<Vec<_>>(), vec!(
+            "metrics[1].identifier", "metrics[2].identifier", "metrics[3].identifier", "metrics[4].value",
+            "metrics[5].value", "metrics[6].value", "metrics[7].value", "metrics[8].when.seconds",
+            "metrics[9].when.nanos", "metrics[10].when",
+        ));
+        assert!(load(&server, LoadMetricsRequest::default()).await.unwrap().metrics.is_empty());
+
+        // streamed metrics that break the rules are acknowledged as errors, and the rest recorded
+        let mut ack = StreamMetricsResponse::default();
+        let streamed = vec!(metric("myservice.cpu_time", 10, 1.0), invalid[3].clone());
+        server.commit_streamed(&streamed, 0, &Arc::new(ConflictPolicies::default()), &mut ack).await.unwrap();
+        assert_eq!((ack.recorded, ack.errors.len()), (1, 1));
+        assert_eq!(ack.errors[0].code, Code::InvalidArgument as i32);
+        assert!(ack.errors[0].message.starts_with("value: "));
+
+        let server = Server::<MemoryDatabase>::default().with_ingest_rules(IngestRules{
+            nan: NanPolicy::Allow,
+            ..Default::default()
+        });
+        assert!(record(&server, vec!(metric("myservice.cpu_time", 10, f64::NAN)), false).await.is_ok());
+
+        // how old a metric may be is left to max_age, so points from before 1970 are recorded
+        let server = Server::<MemoryDatabase>::default();
+        assert!(record(&server, vec!(metric("myservice.cpu_time", -10, 1.0)), false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pages_through_long_series() {
+        let server = Server::<MemoryDatabase>::default();
+        record(&server, (-3..3).map(|i| metric("myservice.cpu_time", i, i as f64)).collect(), false).await.unwrap();
+        // the series and its pages reach back before 1970
+        let mut request = LoadMetricsRequest{
+            max_time_values: 2,
+            time_range: Some(proto::TimeRange{
+                start: Some(prost_types::Timestamp{seconds: -2, nanos: 0}),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut pages = vec!();
+        loop {
+            let response = load(&server, request.clone()).await.unwrap();
+            pages.push(seconds(&response.metrics[0]));
+            if response.next_page_token.is_empty() {
+                break;
+            }
+            assert!(response.metrics[0].truncated);
+            request.page_token = response.next_page_token;
+        }
+        assert_eq!(pages, vec!(vec!(-2, -1), vec!(0, 1), vec!(2)));
+    }
+
+    #[tokio::test]
+    async fn deletes_metrics_before_a_time() {
+        let server = Server::<MemoryDatabase>::default();
+        record(&server, (-2..2).map(|i| metric("myservice.cpu_time", i, i as f64)).collect(), false).await.unwrap();
+        let delete = |seconds, nanos| server.delete_metrics(Request::new(DeleteMetricsRequest{
+            prefix: "myservice.".into(),
+            before: Some(prost_types::Timestamp{seconds, nanos}),
+        }));
+        assert_eq!(delete(-1, 0).await.unwrap().into_inner().deleted, 1);
+        assert_eq!(delete(-1, -1).await.unwrap_err().code(), Code::InvalidArgument);
+        assert_eq!(delete(i64::MIN, 0).await.unwrap_err().code(), Code::InvalidArgument);
+        let response = load(&server, LoadMetricsRequest::default()).await.unwrap();
+        assert_eq!(seconds(&response.metrics[0]), vec!(-1, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_loads() {
+        let server = Server::<MemoryDatabase>::default();
+        let requests = vec!(
+            LoadMetricsRequest{
+                matchers: vec!(proto::LabelMatcher{
+                    key: "region".into(),
+                    op: ProtoMatchOp::Regex as i32,
+                    value: "eu-(".into(),
+                }),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                tail: 1,
+                aggregator: ProtoAggregator::Avg as i32,
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                glob: "myservice.*".into(),
+                name_regex: "myservice\\..*".into(),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                page_token: vec!(0xff),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                time_range: Some(proto::TimeRange{
+                    stop: Some(prost_types::Timestamp{seconds: -10, nanos: -1}),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                time_range: Some(proto::TimeRange{
+                    start: Some(prost_types::Timestamp{seconds: i64::MIN, nanos: 0}),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                relative_start: Some(prost_types::Duration{seconds: i64::MIN, nanos: 0}),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                relative_stop: Some(prost_types::Duration{seconds: i64::MAX / 1000 - 1, nanos: 0}),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                aggregator: ProtoAggregator::Avg as i32,
+                bucket_width: Some(prost_types::Duration{seconds: i64::MAX, nanos: 0}),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                aggregator: ProtoAggregator::Avg as i32,
+                bucket_width: Some(prost_types::Duration{seconds: 1_000_000_000_000, nanos: 0}),
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                aggregator: 99,
+                ..Default::default()
+            },
+            LoadMetricsRequest{
+                page_token: encode(&PageToken{series: vec!(SeriesCursor{
+                    identifier: "myservice.cpu_time".into(),
+                    after: Some(prost_types::Timestamp{seconds: 0, nanos: 2_000_000_000}),
+                })}),
+                ..Default::default()
+            },
+        );
+        for request in requests {
+            assert_eq!(load(&server, request).await.unwrap_err().code(), Code::InvalidArgument);
+        }
+    }
+}